@@ -0,0 +1,703 @@
+//! AArch64 backend, producing GNU assembler syntax for `aarch64-linux-gnu`.
+//!
+//! The tape pointer lives in `x19`. Cells are addressed with an immediate
+//! offset from it when the offset fits the load/store encodings and through
+//! `x17` otherwise; `x16` holds immediates too large for `add`/`sub`. Scans
+//! test 16 cells at a time with NEON, which every AArch64 CPU has.
+
+use crate::compiler::{
+    escape_ascii, scan_mask, CompileOptions, ExitCode, Simd, INIT_POINTER_LOC, INIT_TAPE_SIZE,
+    OUTPUT_BUFFER_SIZE, TAPE_PADDING,
+};
+use crate::debuginfo::LoopPart;
+use crate::parser::{divisor_mask, output_runs, run_bytes, Command, Direction, OutputType};
+
+const PTR_REG: &str = "x19";
+/// Bytes compared per iteration of a NEON scan
+const VECTOR_WIDTH: usize = 16;
+
+struct Aarch64Writer<'a> {
+    out: String,
+    options: &'a CompileOptions,
+    /// Number of constant strings emitted so far, used to name them
+    strings: usize,
+}
+
+impl Aarch64Writer<'_> {
+    /// Loads a 64-bit immediate with `movz` and as many `movk` as needed
+    fn load_imm(&mut self, reg: &str, value: u64) {
+        self.out
+            .push_str(&format!("    movz {}, #{:#x}\n", reg, value & 0xFFFF));
+        for shift in [16, 32, 48] {
+            let part = (value >> shift) & 0xFFFF;
+            if part != 0 {
+                self.out
+                    .push_str(&format!("    movk {}, #{:#x}, lsl #{}\n", reg, part, shift));
+            }
+        }
+    }
+
+    /// `dest = src + value`, going through `x16` if `value` doesn't fit in 12 bits
+    fn add_imm(&mut self, dest: &str, src: &str, value: isize) {
+        let op = if value < 0 { "sub" } else { "add" };
+        let magnitude = value.unsigned_abs();
+        if magnitude < 4096 {
+            self.out
+                .push_str(&format!("    {}  {}, {}, #{}\n", op, dest, src, magnitude));
+        } else {
+            self.load_imm("x16", magnitude as u64);
+            self.out
+                .push_str(&format!("    {}  {}, {}, x16\n", op, dest, src));
+        }
+    }
+
+    /// Address operand of the cell at `offset`. `ldrb`/`strb` take offsets in
+    /// 0..4096 and `ldurb`/`sturb`, which the assembler picks for negative
+    /// offsets, take -256..0. Anything else is computed into `x17`.
+    fn cell(&mut self, offset: isize) -> String {
+        if offset == 0 {
+            format!("[{}]", PTR_REG)
+        } else if (-256..4096).contains(&offset) {
+            format!("[{}, #{}]", PTR_REG, offset)
+        } else {
+            self.add_imm("x17", PTR_REG, offset);
+            String::from("[x17]")
+        }
+    }
+
+    /// Loads the address of `label` into `reg`
+    fn load_address(&mut self, reg: &str, label: &str) {
+        self.out.push_str(&format!("    adrp {}, {}\n", reg, label));
+        self.out
+            .push_str(&format!("    add  {}, {}, :lo12:{}\n", reg, reg, label));
+    }
+
+    fn write_header(&mut self) {
+        if self.options.freestanding {
+            // The tape lives in `.bss`, which the kernel zeroes
+            self.out.push_str(&format!(
+                r#"
+.section .bss
+
+.lcomm tape, {}
+
+.section .text
+
+.globl _start
+
+_start:
+"#,
+                INIT_TAPE_SIZE + 2 * TAPE_PADDING
+            ));
+            self.load_address(PTR_REG, "tape");
+        } else {
+            self.out.push_str(
+                r#"
+.section .text
+
+.globl main
+.type main, %function
+
+main:
+    stp  x29, x30, [sp, #-48]!
+    mov  x29, sp
+    stp  x19, x20, [sp, #16]
+    str  x21, [sp, #32]
+
+"#,
+            );
+            self.load_imm("x0", (INIT_TAPE_SIZE + 2 * TAPE_PADDING) as u64);
+            self.out.push_str("    bl   malloc\n");
+            self.out
+                .push_str("    mov  x20, x0 // Keep the tape address for free\n");
+            self.out.push_str(&format!("    mov  {}, x0\n", PTR_REG));
+        }
+        // Point past the padding to the middle of the tape
+        self.add_imm(PTR_REG, PTR_REG, (TAPE_PADDING + INIT_POINTER_LOC) as isize);
+        self.out.push_str("\n    // Begin program code\n");
+    }
+
+    fn write_footer(&mut self) {
+        let exit_status = match self.options.exit_code {
+            ExitCode::Zero => String::from("mov  w0, #0"),
+            ExitCode::Cell => format!("ldrb w0, [{}]", PTR_REG),
+        };
+
+        if self.options.freestanding {
+            self.out.push_str(&format!(
+                r#"    // At bottom of _start
+    bl   flush_output
+
+    {}
+    mov  x8, #94 // exit_group
+    svc  #0
+"#,
+                exit_status
+            ));
+            return;
+        }
+
+        self.out.push_str(&format!(
+            r#"    // At bottom of main
+    bl   flush_output
+
+    {}
+    mov  w21, w0 // Keep the exit status across the call to free
+    mov  x0, x20
+    bl   free
+    mov  w0, w21
+
+    ldr  x21, [sp, #32]
+    ldp  x19, x20, [sp, #16]
+    ldp  x29, x30, [sp], #48
+    ret
+"#,
+            exit_status
+        ));
+    }
+
+    /// Routines for buffered output. `output_byte` appends the byte in `w0`,
+    /// `output_bytes` appends `x2` bytes starting at `x1` and `flush_output`
+    /// writes out the buffer. They only clobber caller saved registers.
+    fn write_output_runtime(&mut self) {
+        let write = if self.options.freestanding {
+            "mov  x8, #64 // write\n    svc  #0"
+        } else {
+            "bl   write"
+        };
+        self.out.push_str(&format!(
+            r#"
+.section .bss
+
+.lcomm output_buffer, {size}
+.lcomm output_len, 8
+
+.section {text}
+
+output_byte:
+    adrp x9, output_len
+    add  x9, x9, :lo12:output_len
+    ldr  x10, [x9]
+    adrp x11, output_buffer
+    add  x11, x11, :lo12:output_buffer
+    strb w0, [x11, x10]
+    add  x10, x10, #1
+    str  x10, [x9]
+    cmp  x10, #{size}
+    b.eq flush_output
+    ret
+
+output_bytes:
+    stp  x29, x30, [sp, #-32]!
+    mov  x29, sp
+    stp  x21, x22, [sp, #16]
+    mov  x21, x1
+    mov  x22, x2
+output_bytes_loop:
+    cbz  x22, output_bytes_end
+    ldrb w0, [x21], #1
+    bl   output_byte
+    sub  x22, x22, #1
+    b    output_bytes_loop
+output_bytes_end:
+    ldp  x21, x22, [sp, #16]
+    ldp  x29, x30, [sp], #32
+    ret
+
+flush_output:
+    stp  x29, x30, [sp, #-32]!
+    mov  x29, sp
+    stp  x21, x22, [sp, #16]
+    adrp x21, output_buffer
+    add  x21, x21, :lo12:output_buffer
+    adrp x9, output_len
+    add  x9, x9, :lo12:output_len
+    ldr  x22, [x9]
+flush_output_loop:
+    cmp  x22, #0
+    b.le flush_output_end
+    mov  x0, #1
+    mov  x1, x21
+    mov  x2, x22
+    {write}
+    cmp  x0, #0
+    b.le flush_output_end // Output is lost on write errors
+    add  x21, x21, x0
+    sub  x22, x22, x0
+    b    flush_output_loop
+flush_output_end:
+    adrp x9, output_len
+    add  x9, x9, :lo12:output_len
+    str  xzr, [x9]
+    ldp  x21, x22, [sp, #16]
+    ldp  x29, x30, [sp], #32
+    ret
+"#,
+            text = self.options.runtime_section(),
+            size = OUTPUT_BUFFER_SIZE,
+            write = write
+        ));
+    }
+
+    fn write_data_op(&mut self, offset: isize, op: &str, amount: u8, comment: &str) {
+        self.out.push_str(&format!("    // {}\n", comment));
+        let cell = self.cell(offset);
+        self.out.push_str(&format!("    ldrb w9, {}\n", cell));
+        self.out
+            .push_str(&format!("    {}  w9, w9, #{}\n", op, amount));
+        self.out.push_str(&format!("    strb w9, {}\n", cell));
+        self.out.push('\n');
+    }
+
+    fn write_offset_data_op(
+        &mut self,
+        dest_offset: isize,
+        src_offset: isize,
+        multiplier: usize,
+        inverted: bool,
+        op: &str,
+    ) {
+        let src = self.cell(src_offset);
+        self.out.push_str(&format!("    ldrb w9, {}\n", src));
+        if inverted {
+            self.out.push_str("    neg  w9, w9\n");
+        }
+        self.out
+            .push_str(&format!("    mov  w10, #{}\n", multiplier % 256));
+        self.out.push_str("    mul  w9, w9, w10\n");
+        let dest = self.cell(dest_offset);
+        self.out.push_str(&format!("    ldrb w10, {}\n", dest));
+        self.out.push_str(&format!("    {}  w10, w10, w9\n", op));
+        self.out.push_str(&format!("    strb w10, {}\n", dest));
+    }
+
+    fn write_scan(&mut self, id: usize, direction: &Direction, skip_amount: usize, offset: isize) {
+        let step = match direction {
+            Direction::Left => "<",
+            Direction::Right => ">",
+        };
+        if offset == 0 {
+            self.out
+                .push_str(&format!("    // [{}]\n", step.repeat(skip_amount)));
+        } else {
+            self.out.push_str(&format!(
+                "    // [{}] testing cell {}\n",
+                step.repeat(skip_amount),
+                offset
+            ));
+        }
+
+        let label = format!("vector{}", id);
+        if self.options.simd == Simd::None || skip_amount > VECTOR_WIDTH {
+            self.write_scalar_scan(&label, direction, skip_amount, offset);
+        } else {
+            self.write_vector_scan(&label, direction, skip_amount, offset);
+        }
+        self.out.push('\n');
+    }
+
+    fn write_scalar_scan(
+        &mut self,
+        label: &str,
+        direction: &Direction,
+        skip_amount: usize,
+        offset: isize,
+    ) {
+        let step = match direction {
+            Direction::Left => -(skip_amount as isize),
+            Direction::Right => skip_amount as isize,
+        };
+        self.out.push_str(&format!("{}_loop_start:\n", label));
+        let cell = self.cell(offset);
+        self.out.push_str(&format!("    ldrb w9, {}\n", cell));
+        self.out
+            .push_str(&format!("    cbz  w9, {}_found_zero\n", label));
+        self.add_imm(PTR_REG, PTR_REG, step);
+        self.out
+            .push_str(&format!("    b    {}_loop_start\n", label));
+        self.out.push_str(&format!("{}_found_zero:\n", label));
+    }
+
+    /// NEON has no byte mask like `pmovmskb`, so the compare result is
+    /// narrowed with `shrn` into a 64-bit value with four bits per cell
+    fn write_vector_scan(
+        &mut self,
+        label: &str,
+        direction: &Direction,
+        skip_amount: usize,
+        offset: isize,
+    ) {
+        // Each iteration tests the cells on the stride within one vector, so
+        // the window advances by the largest multiple of the stride that fits
+        let chunk = VECTOR_WIDTH - VECTOR_WIDTH % skip_amount;
+        if skip_amount > 1 {
+            let mask = scan_mask(direction, skip_amount, VECTOR_WIDTH);
+            let mask_bytes: Vec<String> = mask.iter().map(|b| format!("0x{:02X}", b)).collect();
+            self.out.push_str("    .pushsection .rodata\n");
+            self.out.push_str(&format!("{}_mask:\n", label));
+            self.out
+                .push_str(&format!("    .byte {}\n", mask_bytes.join(", ")));
+            self.out.push_str("    .popsection\n");
+            self.load_address("x10", &format!("{}_mask", label));
+            self.out.push_str("    ld1  {v3.16b}, [x10]\n");
+        }
+        // x11 is the address of the tested cell
+        self.add_imm("x11", PTR_REG, offset);
+        self.out.push_str(&format!("{}_loop_start:\n", label));
+        match direction {
+            Direction::Right => self.out.push_str("    ld1  {v0.16b}, [x11]\n"),
+            Direction::Left => {
+                // The window ends at the tested cell
+                self.out
+                    .push_str(&format!("    sub  x12, x11, #{}\n", VECTOR_WIDTH - 1));
+                self.out.push_str("    ld1  {v0.16b}, [x12]\n");
+            }
+        }
+        if skip_amount > 1 {
+            self.out.push_str("    orr  v0.16b, v0.16b, v3.16b\n");
+        }
+        self.out.push_str("    cmeq v0.16b, v0.16b, #0\n");
+        self.out.push_str("    shrn v0.8b, v0.8h, #4\n");
+        self.out.push_str("    fmov x9, d0\n");
+        self.out
+            .push_str(&format!("    cbnz x9, {}_found_zero\n", label));
+        match direction {
+            Direction::Right => self
+                .out
+                .push_str(&format!("    add  x11, x11, #{}\n", chunk)),
+            Direction::Left => self
+                .out
+                .push_str(&format!("    sub  x11, x11, #{}\n", chunk)),
+        }
+        self.out
+            .push_str(&format!("    b    {}_loop_start\n", label));
+        self.out.push_str(&format!("{}_found_zero:\n", label));
+        // The zero nearest to the start of the scan
+        match direction {
+            Direction::Right => {
+                self.out.push_str("    rbit x9, x9\n");
+                self.out.push_str("    clz  x9, x9\n");
+                self.out.push_str("    add  x11, x11, x9, lsr #2\n");
+            }
+            Direction::Left => {
+                self.out.push_str("    clz  x9, x9\n");
+                self.out.push_str("    sub  x11, x11, x9, lsr #2\n");
+            }
+        }
+        self.add_imm(PTR_REG, "x11", -offset);
+    }
+
+    /// Ties the following code to a part of loop `id` with `-g`, and shows
+    /// its source with `--annotate`
+    fn loop_marker(&mut self, id: usize, part: LoopPart) {
+        self.out.push_str(&self.options.loop_marker(id, part, "//"));
+    }
+
+    fn write_commands(&mut self, commands: &[Command]) {
+        for run in output_runs(commands) {
+            let command = &run[0];
+            self.out
+                .push_str(&self.options.command_marker(command, "//"));
+            match command {
+                Command::IncPointer { amount, .. } => {
+                    self.out.push_str("    // >\n");
+                    self.add_imm(PTR_REG, PTR_REG, *amount as isize);
+                    self.out.push('\n');
+                }
+                Command::DecPointer { amount, .. } => {
+                    self.out.push_str("    // <\n");
+                    self.add_imm(PTR_REG, PTR_REG, -(*amount as isize));
+                    self.out.push('\n');
+                }
+                Command::IncData { offset, amount, .. } => {
+                    self.write_data_op(*offset, "add", *amount, "+");
+                }
+                Command::DecData { offset, amount, .. } => {
+                    self.write_data_op(*offset, "sub", *amount, "-");
+                }
+                Command::SetData { offset, value, .. } => {
+                    self.out.push_str(&format!("    // ={}\n", value));
+                    let cell = self.cell(*offset);
+                    if *value == 0 {
+                        self.out.push_str(&format!("    strb wzr, {}\n", cell));
+                    } else {
+                        self.out.push_str(&format!("    mov  w9, #{}\n", value));
+                        self.out.push_str(&format!("    strb w9, {}\n", cell));
+                    }
+                }
+                Command::Scan {
+                    id,
+                    direction,
+                    skip_amount,
+                    offset,
+                    ..
+                } => {
+                    self.write_scan(*id, direction, *skip_amount, *offset);
+                    self.loop_marker(*id, LoopPart::After);
+                }
+                Command::AddOffsetData {
+                    src_offset,
+                    dest_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    self.write_offset_data_op(
+                        *dest_offset,
+                        *src_offset,
+                        *multiplier,
+                        *inverted,
+                        "add",
+                    );
+                }
+                Command::SubOffsetData {
+                    src_offset,
+                    dest_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    self.write_offset_data_op(
+                        *dest_offset,
+                        *src_offset,
+                        *multiplier,
+                        *inverted,
+                        "sub",
+                    );
+                }
+                Command::Output {
+                    out_type: OutputType::Const(val),
+                    ..
+                } => {
+                    let bytes = run_bytes(run);
+                    if bytes.len() == 1 {
+                        self.out.push_str("    // .\n");
+                        self.out.push_str(&format!("    mov  w0, #{}\n", val));
+                        self.out.push_str("    bl   output_byte\n");
+                    } else {
+                        let label = format!("string{}", self.strings);
+                        self.strings += 1;
+                        self.out.push_str(&format!("    // . x{}\n", bytes.len()));
+                        self.out.push_str("    .pushsection .rodata\n");
+                        self.out.push_str(&format!("{}:\n", label));
+                        self.out
+                            .push_str(&format!("    .ascii \"{}\"\n", escape_ascii(&bytes)));
+                        self.out.push_str("    .popsection\n");
+                        self.load_address("x1", &label);
+                        self.load_imm("x2", bytes.len() as u64);
+                        self.out.push_str("    bl   output_bytes\n");
+                    }
+                    self.out.push('\n');
+                }
+                Command::Output {
+                    out_type: OutputType::Cell { offset },
+                    ..
+                } => {
+                    self.out.push_str("    // .\n");
+                    let cell = self.cell(*offset);
+                    self.out.push_str(&format!("    ldrb w0, {}\n", cell));
+                    self.out.push_str("    bl   output_byte\n");
+                    self.out.push('\n');
+                }
+                Command::Input { offset, .. } if self.options.freestanding => {
+                    self.out.push_str("    // ,\n");
+                    self.out.push_str("    bl   flush_output\n");
+                    self.out.push_str("    mov  x0, #0\n");
+                    self.add_imm("x1", PTR_REG, *offset);
+                    self.out.push_str("    mov  x2, #1\n");
+                    self.out.push_str("    mov  x8, #63 // read\n");
+                    self.out.push_str("    svc  #0\n");
+                    // Store -1 on end of input or error, like getchar
+                    self.out.push_str("    cmp  x0, #0\n");
+                    self.out.push_str("    b.gt 1f\n");
+                    self.out.push_str("    mov  w9, #255\n");
+                    let cell = self.cell(*offset);
+                    self.out.push_str(&format!("    strb w9, {}\n", cell));
+                    self.out.push_str("1:\n");
+                    self.out.push('\n');
+                }
+                Command::Input { offset, .. } => {
+                    self.out.push_str("    // ,\n");
+                    self.out.push_str("    bl   flush_output\n");
+                    self.out.push_str("    bl   getchar\n");
+                    let cell = self.cell(*offset);
+                    self.out.push_str(&format!("    strb w0, {}\n", cell));
+                    self.out.push('\n');
+                }
+                Command::InfiniteLoop { id, divisor, .. } => {
+                    let msg = format!("infinite loop detected at loop {}", id);
+                    self.out.push_str("    // [!]\n");
+                    self.out.push_str(&format!("    ldrb w9, [{}]\n", PTR_REG));
+                    self.out
+                        .push_str(&format!("    cbz  w9, infinite{}_end\n", id));
+                    if let Some(mask) = divisor_mask(*divisor) {
+                        // Zero isn't a valid logical immediate, and every cell is
+                        // a multiple of one
+                        if mask != 0 {
+                            self.out.push_str(&format!("    tst  w9, #{}\n", mask));
+                            self.out
+                                .push_str(&format!("    b.ne infinite{}_trap\n", id));
+                        }
+                        self.out.push_str(&format!("    strb wzr, [{}]\n", PTR_REG));
+                        self.out.push_str(&format!("    b    infinite{}_end\n", id));
+                    }
+                    self.out.push_str(&format!("infinite{}_trap:\n", id));
+                    self.out.push_str("    bl   flush_output\n");
+                    self.out.push_str("    mov  x0, #2\n");
+                    self.load_address("x1", &format!("infinite{}_msg", id));
+                    // Message length including the newline
+                    self.out
+                        .push_str(&format!("    mov  x2, #{}\n", msg.len() + 1));
+                    if self.options.freestanding {
+                        self.out.push_str("    mov  x8, #64 // write\n");
+                        self.out.push_str("    svc  #0\n");
+                        self.out.push_str("    mov  x0, #1\n");
+                        self.out.push_str("    mov  x8, #94 // exit_group\n");
+                        self.out.push_str("    svc  #0\n");
+                    } else {
+                        self.out.push_str("    bl   write\n");
+                        self.out.push_str("    mov  w0, #1\n");
+                        self.out.push_str("    bl   exit\n");
+                    }
+                    self.out.push_str("    .pushsection .rodata\n");
+                    self.out.push_str(&format!("infinite{}_msg:\n", id));
+                    self.out.push_str(&format!("    .ascii \"{}\\n\"\n", msg));
+                    self.out.push_str("    .popsection\n");
+                    self.out.push_str(&format!("infinite{}_end:\n", id));
+                    self.loop_marker(*id, LoopPart::After);
+                    self.out.push('\n');
+                }
+                Command::If { body, id, .. } => {
+                    self.out.push_str("    // ?[\n");
+                    self.out.push_str(&format!("    ldrb w9, [{}]\n", PTR_REG));
+                    self.out.push_str(&format!("    cbz  w9, if{}_end\n", id));
+                    self.out.push('\n');
+                    self.loop_marker(*id, LoopPart::Body);
+
+                    self.write_commands(body);
+
+                    self.out.push_str("    // ]\n");
+                    self.out.push_str(&format!("if{}_end:\n", id));
+                    self.loop_marker(*id, LoopPart::After);
+                }
+                Command::Loop {
+                    body,
+                    id,
+                    end_count,
+                    ..
+                } => {
+                    self.out.push_str("    // [\n");
+                    self.out.push_str(&format!("loop{}:\n", id));
+                    self.out.push_str(&format!("    ldrb w9, [{}]\n", PTR_REG));
+                    self.out.push_str(&format!("    cbz  w9, loop{}_end\n", id));
+                    self.out.push('\n');
+                    self.loop_marker(*id, LoopPart::Body);
+
+                    self.write_commands(body);
+
+                    self.out.push_str("    // ]\n");
+                    self.out
+                        .push_str(&self.options.loop_end_marker(*id, *end_count, "//"));
+                    self.out.push_str(&format!("    b    loop{}\n", id));
+                    self.out.push('\n');
+                    self.out.push_str(&format!("loop{}_end:\n", id));
+                    self.loop_marker(*id, LoopPart::After);
+                }
+            }
+        }
+    }
+}
+
+/// Generates the assembly for the whole program
+pub fn generate(commands: &[Command], options: &CompileOptions) -> String {
+    let mut writer = Aarch64Writer {
+        out: String::new(),
+        options,
+        strings: 0,
+    };
+    let source_map = options.debug_map();
+    if let Some(source_map) = source_map {
+        writer.out.push_str(&source_map.file_directive());
+    }
+    writer.write_header();
+    writer.out.push_str(&options.start_marker("//"));
+    writer.write_commands(commands);
+    writer.write_footer();
+    if let Some(source_map) = source_map {
+        writer.out.push_str(&source_map.end_label());
+    }
+    writer.write_output_runtime();
+    if let Some(source_map) = source_map {
+        // `x19` is register 19 in the AArch64 DWARF numbering
+        writer
+            .out
+            .push_str(&source_map.debug_sections(options.entry(), 19));
+    }
+    writer.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::{cross_assemble, run_under_qemu, target_options, LOWERINGS, TRAP};
+    use crate::compiler::Target;
+    use crate::interp::interp_io;
+    use crate::ir::parse_ir;
+
+    #[test]
+    fn infinite_loops_by_one_clear_the_cell() {
+        let commands = parse_ir("inf_loop #1 1\ninf_loop #2 4\n").unwrap();
+        let asm = generate(&commands, &target_options(Target::Aarch64, Simd::Neon));
+        assert!(!asm.contains("tst  w9, #0"));
+        assert!(asm.contains("tst  w9, #3\n    b.ne infinite2_trap\n"));
+        assert!(asm.contains("cbz  w9, infinite1_end\n    strb wzr, [x19]\n"));
+    }
+
+    #[test]
+    fn constant_output_is_written_at_once() {
+        let commands = parse_ir("out_const 72\nout_const 105\nout_const 10\n").unwrap();
+        let asm = generate(&commands, &target_options(Target::Aarch64, Simd::Neon));
+        assert!(asm.contains("    // . x3\n"));
+        assert!(asm.contains("    .ascii \"Hi\\012\"\n"));
+        assert!(asm.contains("    movz x2, #0x3\n    bl   output_bytes\n"));
+    }
+
+    #[test]
+    fn lowerings_assemble() {
+        for simd in [Simd::Neon, Simd::None] {
+            for freestanding in [false, true] {
+                let options = CompileOptions {
+                    freestanding,
+                    ..target_options(Target::Aarch64, simd)
+                };
+                for ir in [LOWERINGS, TRAP] {
+                    let asm = generate(&parse_ir(ir).unwrap(), &options);
+                    let Some(result) = cross_assemble(&asm, Target::Aarch64) else {
+                        return;
+                    };
+                    result.unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lowerings_run_under_qemu() {
+        let commands = parse_ir(LOWERINGS).unwrap();
+        let mut expected = vec![];
+        interp_io(&mut commands.clone(), &mut &b"xyz"[..], &mut expected);
+        for simd in [Simd::Neon, Simd::None] {
+            let options = target_options(Target::Aarch64, simd);
+            let Some(output) = run_under_qemu(&commands, &options, b"xyz") else {
+                return;
+            };
+            assert!(output.status.success());
+            assert_eq!(output.stdout, expected, "{:?}", simd);
+
+            let output = run_under_qemu(&parse_ir(TRAP).unwrap(), &options, b"").unwrap();
+            assert_eq!(output.status.code(), Some(1));
+            assert_eq!(output.stdout, b"Hi");
+            assert_eq!(output.stderr, b"infinite loop detected at loop 3\n");
+        }
+    }
+}
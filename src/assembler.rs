@@ -0,0 +1,1286 @@
+//! Assembler for the x86-64 code the backend emits.
+//!
+//! This understands the AT&T syntax subset that `compiler::compile` writes:
+//! the `.section`, `.pushsection`, `.popsection`, `.globl`, `.lcomm`, `.byte`
+//! and `.ascii` directives, named and numeric labels, and the general purpose,
+//! SSE2 and AVX2 instructions used by the generated code and its runtime. The
+//! result is an `Object` that `elf` writes out as a relocatable object or a
+//! static executable, so no external assembler or linker is needed.
+//!
+//! Branches and calls always use 32-bit displacements. Instructions therefore
+//! have the same size wherever their targets end up, and everything is
+//! assembled in a single pass, with references patched at the end.
+
+use std::collections::{HashMap, HashSet};
+
+/// Sections in the order they are laid out
+pub const TEXT: usize = 0;
+pub const RODATA: usize = 1;
+pub const DATA: usize = 2;
+pub const BSS: usize = 3;
+pub const SECTION_NAMES: [&str; 4] = [".text", ".rodata", ".data", ".bss"];
+
+/// Alignment of `.lcomm` symbols, enough for any vector load
+const LCOMM_ALIGN: usize = 16;
+
+pub struct Section {
+    pub data: Vec<u8>,
+    /// Size of `.bss`, which has no data
+    pub size: usize,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Section {
+    pub fn len(&self) -> usize {
+        self.data.len().max(self.size)
+    }
+}
+
+/// What a relocation refers to
+pub enum RelocationTarget {
+    /// The start of a section. Local labels are referred to this way, with
+    /// their offset in the addend.
+    Section(usize),
+    /// A global symbol, which may be defined elsewhere
+    Symbol(String),
+}
+
+/// A 32-bit PC-relative reference that the assembler couldn't resolve itself,
+/// because its target is in another section or isn't defined at all
+pub struct Relocation {
+    pub offset: usize,
+    pub target: RelocationTarget,
+    pub addend: i64,
+    /// A call or jump to a function, which may go through the PLT
+    pub branch: bool,
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub section: usize,
+    pub offset: usize,
+    pub global: bool,
+}
+
+pub struct Object {
+    pub sections: Vec<Section>,
+    /// Symbols defined by the assembly, in order of definition. Numeric and
+    /// `.L` labels are left out.
+    pub symbols: Vec<Symbol>,
+    /// Symbols referenced but not defined, in order of first use
+    pub undefined: Vec<String>,
+}
+
+impl Object {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RegKind {
+    Byte,
+    Dword,
+    Qword,
+    Xmm,
+    Ymm,
+}
+
+#[derive(Clone, Copy)]
+struct Reg {
+    num: u8,
+    kind: RegKind,
+}
+
+impl Reg {
+    /// `%spl`, `%bpl`, `%sil` and `%dil` can only be encoded with a REX prefix
+    fn needs_rex(self) -> bool {
+        self.kind == RegKind::Byte && (4..8).contains(&self.num)
+    }
+}
+
+/// The opcode extension `/n`, which takes the place of a register in ModRM
+fn ext(n: u8) -> Reg {
+    Reg {
+        num: n,
+        kind: RegKind::Qword,
+    }
+}
+
+fn parse_reg(name: &str) -> Option<Reg> {
+    const QWORD: [&str; 8] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi"];
+    const DWORD: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+    const BYTE: [&str; 8] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil"];
+    let reg = |num: usize, kind| {
+        Some(Reg {
+            num: num as u8,
+            kind,
+        })
+    };
+    for (names, kind) in [
+        (QWORD, RegKind::Qword),
+        (DWORD, RegKind::Dword),
+        (BYTE, RegKind::Byte),
+    ] {
+        if let Some(num) = names.iter().position(|&n| n == name) {
+            return reg(num, kind);
+        }
+    }
+    if let Some(num) = name.strip_prefix("xmm") {
+        return num
+            .parse()
+            .ok()
+            .filter(|&n| n < 16)
+            .and_then(|n| reg(n, RegKind::Xmm));
+    }
+    if let Some(num) = name.strip_prefix("ymm") {
+        return num
+            .parse()
+            .ok()
+            .filter(|&n| n < 16)
+            .and_then(|n| reg(n, RegKind::Ymm));
+    }
+    // %r8 to %r15 with an optional size suffix
+    let rest = name.strip_prefix('r')?;
+    let digits = rest.trim_end_matches(['d', 'b']);
+    let num: usize = digits.parse().ok().filter(|n| (8..16).contains(n))?;
+    match &rest[digits.len()..] {
+        "" => reg(num, RegKind::Qword),
+        "d" => reg(num, RegKind::Dword),
+        "b" => reg(num, RegKind::Byte),
+        _ => None,
+    }
+}
+
+/// A memory operand, `disp(base, index, scale)` or `symbol+disp(%rip)`
+struct Mem {
+    /// `None` for `%rip`
+    base: Option<Reg>,
+    index: Option<(Reg, u8)>,
+    symbol: Option<String>,
+    disp: i64,
+}
+
+enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    Mem(Mem),
+    /// Target of a branch or call
+    Label(String, i64),
+}
+
+fn parse_int(s: &str) -> Result<i64, String> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let value = match digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| format!("invalid number `{}`", s))?;
+    Ok(if negative { -value } else { value })
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+/// Splits `s` at commas outside parentheses and strings
+fn split_operands(s: &str) -> Vec<&str> {
+    let mut operands = vec![];
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if !s[start..].trim().is_empty() {
+        operands.push(s[start..].trim());
+    }
+    operands
+}
+
+/// Removes a `#` comment, ignoring any inside strings
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == '#' {
+            return &line[..i];
+        }
+    }
+    line
+}
+
+fn parse_string(s: &str) -> Result<Vec<u8>, String> {
+    let inner = s
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| format!("invalid string {}", s))?;
+    let mut bytes = vec![];
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some(digit @ '0'..='7') => {
+                // Up to three octal digits
+                let mut value = digit.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                bytes.push(value as u8);
+            }
+            Some(c @ ('\\' | '"')) => bytes.push(c as u8),
+            _ => return Err(format!("invalid escape in string {}", s)),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Condition codes, in encoding order. Aliases share a number.
+fn condition_code(name: &str) -> Option<u8> {
+    Some(match name {
+        "o" => 0,
+        "no" => 1,
+        "b" | "c" | "nae" => 2,
+        "ae" | "nb" | "nc" => 3,
+        "e" | "z" => 4,
+        "ne" | "nz" => 5,
+        "be" | "na" => 6,
+        "a" | "nbe" => 7,
+        "s" => 8,
+        "ns" => 9,
+        "p" | "pe" => 10,
+        "np" | "po" => 11,
+        "l" | "nge" => 12,
+        "ge" | "nl" => 13,
+        "le" | "ng" => 14,
+        "g" | "nle" => 15,
+        _ => return None,
+    })
+}
+
+/// A reference to a symbol from inside an instruction
+struct Fixup {
+    /// Position of the 32-bit field within the instruction
+    position: usize,
+    symbol: String,
+    addend: i64,
+    branch: bool,
+}
+
+/// Machine code for one instruction
+#[derive(Default)]
+struct Encoded {
+    bytes: Vec<u8>,
+    fixup: Option<Fixup>,
+}
+
+/// Register or memory operand of a ModRM byte
+enum Rm<'a> {
+    Reg(Reg),
+    Mem(&'a Mem),
+}
+
+impl Rm<'_> {
+    /// REX.X and REX.B bits needed to encode the operand
+    fn rex_xb(&self) -> u8 {
+        match self {
+            Rm::Reg(reg) => (reg.num >> 3) & 1,
+            Rm::Mem(mem) => {
+                let x = mem.index.map_or(0, |(index, _)| (index.num >> 3) & 1);
+                let b = mem.base.map_or(0, |base| (base.num >> 3) & 1);
+                (x << 1) | b
+            }
+        }
+    }
+
+    fn needs_rex(&self) -> bool {
+        matches!(self, Rm::Reg(reg) if reg.needs_rex())
+    }
+}
+
+struct Encoder {
+    out: Encoded,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder {
+            out: Encoded::default(),
+        }
+    }
+
+    /// Appends the ModRM byte and whatever follows it for `rm`, with `reg` in
+    /// the reg field. `imm_len` is the size of the immediate that will follow,
+    /// which RIP-relative displacements have to account for.
+    fn modrm(&mut self, reg: u8, rm: &Rm, imm_len: usize) -> Result<(), String> {
+        let reg = (reg & 7) << 3;
+        let mem = match rm {
+            Rm::Reg(r) => {
+                self.out.bytes.push(0xC0 | reg | (r.num & 7));
+                return Ok(());
+            }
+            Rm::Mem(mem) => mem,
+        };
+        let Some(base) = mem.base else {
+            if mem.index.is_some() {
+                return Err("%rip can't be used with an index".to_string());
+            }
+            let Some(symbol) = &mem.symbol else {
+                return Err("%rip-relative operand without a symbol".to_string());
+            };
+            self.out.bytes.push(reg | 0b101);
+            self.out.fixup = Some(Fixup {
+                position: self.out.bytes.len(),
+                symbol: symbol.clone(),
+                // The CPU adds the displacement to the address of the next
+                // instruction, which is further along by the immediate
+                addend: mem.disp - 4 - imm_len as i64,
+                branch: false,
+            });
+            self.out.bytes.extend([0; 4]);
+            return Ok(());
+        };
+        if mem.symbol.is_some() {
+            return Err("symbols can only be used with %rip".to_string());
+        }
+        let disp = mem.disp;
+        if i32::try_from(disp).is_err() {
+            return Err(format!("displacement {} out of range", disp));
+        }
+        // %rbp and %r13 as a base always need a displacement
+        let mode = if disp == 0 && base.num & 7 != 5 {
+            0b00
+        } else if i8::try_from(disp).is_ok() {
+            0b01
+        } else {
+            0b10
+        };
+        // %rsp and %r12 as a base always need a SIB byte
+        if mem.index.is_some() || base.num & 7 == 4 {
+            self.out.bytes.push((mode << 6) | reg | 0b100);
+            let (index, scale) = match mem.index {
+                Some((index, scale)) => ((index.num & 7) << 3, scale.trailing_zeros() as u8),
+                None => (0b100 << 3, 0),
+            };
+            self.out.bytes.push((scale << 6) | index | (base.num & 7));
+        } else {
+            self.out.bytes.push((mode << 6) | reg | (base.num & 7));
+        }
+        match mode {
+            0b01 => self.out.bytes.push(disp as u8),
+            0b10 => self.out.bytes.extend((disp as i32).to_le_bytes()),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Legacy encoding: prefixes, REX, opcode, ModRM and an immediate
+    fn legacy(
+        mut self,
+        prefix: Option<u8>,
+        rex_w: bool,
+        opcode: &[u8],
+        reg: Reg,
+        rm: &Rm,
+        imm: &[u8],
+    ) -> Result<Encoded, String> {
+        if let Some(prefix) = prefix {
+            self.out.bytes.push(prefix);
+        }
+        let rex = ((rex_w as u8) << 3) | (((reg.num >> 3) & 1) << 2) | rm.rex_xb();
+        if rex != 0 || reg.needs_rex() || rm.needs_rex() {
+            self.out.bytes.push(0x40 | rex);
+        }
+        self.out.bytes.extend(opcode);
+        self.modrm(reg.num, rm, imm.len())?;
+        self.out.bytes.extend(imm);
+        Ok(self.out)
+    }
+
+    /// VEX encoding for 256-bit instructions in the 0F map. `pp` selects the
+    /// implied prefix: 1 for 66, 2 for F3.
+    fn vex(mut self, pp: u8, opcode: u8, reg: u8, vvvv: u8, rm: &Rm) -> Result<Encoded, String> {
+        let r = (!reg >> 3) & 1;
+        let xb = rm.rex_xb();
+        let vvvv = (!vvvv & 0xF) << 3;
+        let l = 1 << 2;
+        if xb == 0 {
+            self.out.bytes.extend([0xC5, (r << 7) | vvvv | l | pp]);
+        } else {
+            let x = (!xb >> 1) & 1;
+            let b = !xb & 1;
+            self.out.bytes.extend([
+                0xC4,
+                (r << 7) | (x << 6) | (b << 5) | 0b00001,
+                vvvv | l | pp,
+            ]);
+        }
+        self.out.bytes.push(opcode);
+        self.modrm(reg, rm, 0)?;
+        Ok(self.out)
+    }
+}
+
+/// Operand size of an integer instruction
+#[derive(Clone, Copy, PartialEq)]
+enum Size {
+    Byte,
+    Dword,
+    Qword,
+}
+
+impl Size {
+    fn from_suffix(suffix: char) -> Option<Size> {
+        match suffix {
+            'b' => Some(Size::Byte),
+            'l' => Some(Size::Dword),
+            'q' => Some(Size::Qword),
+            _ => None,
+        }
+    }
+
+    fn of(reg: Reg) -> Option<Size> {
+        match reg.kind {
+            RegKind::Byte => Some(Size::Byte),
+            RegKind::Dword => Some(Size::Dword),
+            RegKind::Qword => Some(Size::Qword),
+            RegKind::Xmm | RegKind::Ymm => None,
+        }
+    }
+
+    fn imm_bytes(self, imm: i64) -> Result<Vec<u8>, String> {
+        match self {
+            // Byte immediates may be given signed or unsigned
+            Size::Byte if (-128..256).contains(&imm) => Ok(vec![imm as u8]),
+            Size::Dword if (i32::MIN as i64..=u32::MAX as i64).contains(&imm) => {
+                Ok((imm as u32).to_le_bytes().to_vec())
+            }
+            Size::Qword if i32::try_from(imm).is_ok() => Ok((imm as i32).to_le_bytes().to_vec()),
+            _ => Err(format!("immediate {} out of range", imm)),
+        }
+    }
+}
+
+/// Encodes an instruction with `size` as its operand size. `opcode8` is the
+/// byte form; the 32 and 64-bit forms are the opcode after it.
+fn sized(size: Size, opcode8: u8, reg: Reg, rm: &Rm, imm: &[u8]) -> Result<Encoded, String> {
+    let opcode = if size == Size::Byte {
+        opcode8
+    } else {
+        opcode8 + 1
+    };
+    Encoder::new().legacy(None, size == Size::Qword, &[opcode], reg, rm, imm)
+}
+
+fn gpr(operand: &Operand) -> Option<Reg> {
+    match operand {
+        Operand::Reg(reg) if Size::of(*reg).is_some() => Some(*reg),
+        _ => None,
+    }
+}
+
+fn rm_operand(operand: &Operand) -> Option<Rm<'_>> {
+    match operand {
+        Operand::Reg(reg) => Some(Rm::Reg(*reg)),
+        Operand::Mem(mem) => Some(Rm::Mem(mem)),
+        _ => None,
+    }
+}
+
+/// Integer instructions, with the operand size from the mnemonic's suffix or
+/// failing that from a register operand
+fn encode_integer(base: &str, size: Option<Size>, ops: &[Operand]) -> Result<Encoded, String> {
+    let size = size
+        .or_else(|| ops.iter().find_map(|op| gpr(op).and_then(Size::of)))
+        .ok_or("operand size unknown")?;
+    let mismatch = || "operands don't match the instruction".to_string();
+    for op in ops {
+        if let Some(reg) = gpr(op) {
+            if Size::of(reg) != Some(size) && !matches!(base, "movzb" | "lea") {
+                return Err("register size doesn't match the instruction".to_string());
+            }
+        }
+    }
+
+    const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+    if let Some(n) = ALU.iter().position(|&op| op == base) {
+        let n = n as u8;
+        return match ops {
+            [Operand::Imm(imm), dst] => {
+                let rm = rm_operand(dst).ok_or_else(mismatch)?;
+                if size != Size::Byte && i8::try_from(*imm).is_ok() {
+                    // Sign extended byte immediate
+                    Encoder::new().legacy(
+                        None,
+                        size == Size::Qword,
+                        &[0x83],
+                        ext(n),
+                        &rm,
+                        &[*imm as u8],
+                    )
+                } else {
+                    sized(size, 0x80, ext(n), &rm, &size.imm_bytes(*imm)?)
+                }
+            }
+            [Operand::Reg(src), dst] => {
+                let rm = rm_operand(dst).ok_or_else(mismatch)?;
+                sized(size, n * 8, *src, &rm, &[])
+            }
+            [Operand::Mem(mem), Operand::Reg(dst)] => {
+                sized(size, n * 8 + 2, *dst, &Rm::Mem(mem), &[])
+            }
+            _ => Err(mismatch()),
+        };
+    }
+
+    match (base, ops) {
+        ("mov", [Operand::Imm(imm), Operand::Reg(dst)]) => {
+            let rex_b = (dst.num >> 3) & 1;
+            let mut bytes = vec![];
+            match size {
+                Size::Qword if i32::try_from(*imm).is_err() => {
+                    bytes.extend([0x48 | rex_b, 0xB8 + (dst.num & 7)]);
+                    bytes.extend(imm.to_le_bytes());
+                }
+                Size::Qword => {
+                    return sized(size, 0xC6, ext(0), &Rm::Reg(*dst), &size.imm_bytes(*imm)?)
+                }
+                _ => {
+                    if rex_b != 0 || dst.needs_rex() {
+                        bytes.push(0x40 | rex_b);
+                    }
+                    let opcode = if size == Size::Byte { 0xB0 } else { 0xB8 };
+                    bytes.push(opcode + (dst.num & 7));
+                    bytes.extend(size.imm_bytes(*imm)?);
+                }
+            }
+            Ok(Encoded { bytes, fixup: None })
+        }
+        ("mov", [Operand::Imm(imm), Operand::Mem(mem)]) => {
+            sized(size, 0xC6, ext(0), &Rm::Mem(mem), &size.imm_bytes(*imm)?)
+        }
+        ("mov", [Operand::Reg(src), dst]) => {
+            let rm = rm_operand(dst).ok_or_else(mismatch)?;
+            sized(size, 0x88, *src, &rm, &[])
+        }
+        ("mov", [Operand::Mem(mem), Operand::Reg(dst)]) => {
+            sized(size, 0x8A, *dst, &Rm::Mem(mem), &[])
+        }
+        ("test", [Operand::Imm(imm), dst]) => {
+            let rm = rm_operand(dst).ok_or_else(mismatch)?;
+            sized(size, 0xF6, ext(0), &rm, &size.imm_bytes(*imm)?)
+        }
+        ("test", [Operand::Reg(src), dst]) => {
+            let rm = rm_operand(dst).ok_or_else(mismatch)?;
+            sized(size, 0x84, *src, &rm, &[])
+        }
+        ("inc" | "dec", [dst]) => {
+            let rm = rm_operand(dst).ok_or_else(mismatch)?;
+            sized(size, 0xFE, ext((base == "dec") as u8), &rm, &[])
+        }
+        ("not" | "neg" | "mul", [dst]) => {
+            let n = match base {
+                "not" => 2,
+                "neg" => 3,
+                _ => 4,
+            };
+            let rm = rm_operand(dst).ok_or_else(mismatch)?;
+            sized(size, 0xF6, ext(n), &rm, &[])
+        }
+        ("shl" | "shr" | "sar", [Operand::Imm(imm), dst]) => {
+            let n = match base {
+                "shl" => 4,
+                "shr" => 5,
+                _ => 7,
+            };
+            let rm = rm_operand(dst).ok_or_else(mismatch)?;
+            if *imm == 1 {
+                sized(size, 0xD0, ext(n), &rm, &[])
+            } else {
+                sized(size, 0xC0, ext(n), &rm, &[*imm as u8])
+            }
+        }
+        ("push" | "pop", [Operand::Reg(reg)]) if size == Size::Qword => {
+            let mut bytes = vec![];
+            if reg.num >= 8 {
+                bytes.push(0x41);
+            }
+            let opcode = if base == "push" { 0x50 } else { 0x58 };
+            bytes.push(opcode + (reg.num & 7));
+            Ok(Encoded { bytes, fixup: None })
+        }
+        ("lea", [Operand::Mem(mem), Operand::Reg(dst)]) if size != Size::Byte => {
+            Encoder::new().legacy(None, size == Size::Qword, &[0x8D], *dst, &Rm::Mem(mem), &[])
+        }
+        ("imul", [Operand::Imm(imm), src, Operand::Reg(dst)]) if size != Size::Byte => {
+            let rm = rm_operand(src).ok_or_else(mismatch)?;
+            if let Ok(imm) = i8::try_from(*imm) {
+                Encoder::new().legacy(None, size == Size::Qword, &[0x6B], *dst, &rm, &[imm as u8])
+            } else {
+                Encoder::new().legacy(
+                    None,
+                    size == Size::Qword,
+                    &[0x69],
+                    *dst,
+                    &rm,
+                    &size.imm_bytes(*imm)?,
+                )
+            }
+        }
+        ("bsf" | "bsr", [src, Operand::Reg(dst)]) if size != Size::Byte => {
+            let rm = rm_operand(src).ok_or_else(mismatch)?;
+            let opcode = if base == "bsf" { 0xBC } else { 0xBD };
+            Encoder::new().legacy(None, size == Size::Qword, &[0x0F, opcode], *dst, &rm, &[])
+        }
+        ("movzb", [src, Operand::Reg(dst)]) if size != Size::Byte => {
+            let rm = rm_operand(src).ok_or_else(mismatch)?;
+            if matches!(rm, Rm::Reg(reg) if reg.kind != RegKind::Byte) {
+                return Err(mismatch());
+            }
+            Encoder::new().legacy(None, size == Size::Qword, &[0x0F, 0xB6], *dst, &rm, &[])
+        }
+        _ => Err(mismatch()),
+    }
+}
+
+/// SSE2 and AVX2 instructions
+fn encode_vector(mnemonic: &str, ops: &[Operand]) -> Option<Result<Encoded, String>> {
+    let mismatch = || Err("operands don't match the instruction".to_string());
+    let is = |op: &Operand, kind| matches!(op, Operand::Reg(reg) if reg.kind == kind);
+    // Instructions of the form `op src, dst` computing `dst = dst op src`
+    let sse_binary = |opcode: u8| -> Result<Encoded, String> {
+        match ops {
+            [src, Operand::Reg(dst)] if dst.kind == RegKind::Xmm && !is(src, RegKind::Ymm) => {
+                let rm = rm_operand(src).ok_or("operands don't match the instruction")?;
+                Encoder::new().legacy(Some(0x66), false, &[0x0F, opcode], *dst, &rm, &[])
+            }
+            _ => mismatch(),
+        }
+    };
+    // Instructions of the form `op src2, src1, dst`
+    let avx_binary = |opcode: u8| -> Result<Encoded, String> {
+        match ops {
+            [src2, Operand::Reg(src1), Operand::Reg(dst)]
+                if src1.kind == RegKind::Ymm && dst.kind == RegKind::Ymm =>
+            {
+                let rm = rm_operand(src2).ok_or("operands don't match the instruction")?;
+                Encoder::new().vex(1, opcode, dst.num, src1.num, &rm)
+            }
+            _ => mismatch(),
+        }
+    };
+    Some(match mnemonic {
+        "movdqu" => match ops {
+            [src, Operand::Reg(dst)] if dst.kind == RegKind::Xmm => {
+                let rm = rm_operand(src)?;
+                Encoder::new().legacy(Some(0xF3), false, &[0x0F, 0x6F], *dst, &rm, &[])
+            }
+            [Operand::Reg(src), Operand::Mem(mem)] if src.kind == RegKind::Xmm => {
+                Encoder::new().legacy(Some(0xF3), false, &[0x0F, 0x7F], *src, &Rm::Mem(mem), &[])
+            }
+            _ => mismatch(),
+        },
+        "pxor" => sse_binary(0xEF),
+        "por" => sse_binary(0xEB),
+        "pand" => sse_binary(0xDB),
+        "pcmpeqb" => sse_binary(0x74),
+        "pmovmskb" => match ops {
+            [Operand::Reg(src), Operand::Reg(dst)]
+                if src.kind == RegKind::Xmm && dst.kind == RegKind::Dword =>
+            {
+                Encoder::new().legacy(Some(0x66), false, &[0x0F, 0xD7], *dst, &Rm::Reg(*src), &[])
+            }
+            _ => mismatch(),
+        },
+        "vmovdqu" => match ops {
+            [src, Operand::Reg(dst)] if dst.kind == RegKind::Ymm => {
+                let rm = rm_operand(src)?;
+                Encoder::new().vex(2, 0x6F, dst.num, 0, &rm)
+            }
+            [Operand::Reg(src), Operand::Mem(mem)] if src.kind == RegKind::Ymm => {
+                Encoder::new().vex(2, 0x7F, src.num, 0, &Rm::Mem(mem))
+            }
+            _ => mismatch(),
+        },
+        "vpxor" => avx_binary(0xEF),
+        "vpor" => avx_binary(0xEB),
+        "vpand" => avx_binary(0xDB),
+        "vpcmpeqb" => avx_binary(0x74),
+        "vpmovmskb" => match ops {
+            [Operand::Reg(src), Operand::Reg(dst)]
+                if src.kind == RegKind::Ymm && dst.kind == RegKind::Dword =>
+            {
+                Encoder::new().vex(1, 0xD7, dst.num, 0, &Rm::Reg(*src))
+            }
+            _ => mismatch(),
+        },
+        "vzeroupper" if ops.is_empty() => Ok(Encoded {
+            bytes: vec![0xC5, 0xF8, 0x77],
+            fixup: None,
+        }),
+        _ => return None,
+    })
+}
+
+fn encode(mnemonic: &str, ops: &[Operand]) -> Result<Encoded, String> {
+    let fixed = |bytes: &[u8]| {
+        if ops.is_empty() {
+            Ok(Encoded {
+                bytes: bytes.to_vec(),
+                fixup: None,
+            })
+        } else {
+            Err(format!("`{}` takes no operands", mnemonic))
+        }
+    };
+    let branch = |opcode: &[u8]| match ops {
+        [Operand::Label(symbol, addend)] => {
+            let mut bytes = opcode.to_vec();
+            let position = bytes.len();
+            bytes.extend([0; 4]);
+            Ok(Encoded {
+                bytes,
+                fixup: Some(Fixup {
+                    position,
+                    symbol: symbol.clone(),
+                    addend: addend - 4,
+                    branch: true,
+                }),
+            })
+        }
+        _ => Err(format!("`{}` needs a label", mnemonic)),
+    };
+
+    match mnemonic {
+        "ret" => return fixed(&[0xC3]),
+        "syscall" => return fixed(&[0x0F, 0x05]),
+        "cpuid" => return fixed(&[0x0F, 0xA2]),
+        "xgetbv" => return fixed(&[0x0F, 0x01, 0xD0]),
+        "nop" => return fixed(&[0x90]),
+        "call" => return branch(&[0xE8]),
+        "jmp" => return branch(&[0xE9]),
+        _ => (),
+    }
+    if let Some(cc) = mnemonic.strip_prefix('j').and_then(condition_code) {
+        return branch(&[0x0F, 0x80 + cc]);
+    }
+    if let Some(result) = encode_vector(mnemonic, ops) {
+        return result;
+    }
+    // movzbl and movzbq carry two sizes
+    if let Some(suffix) = mnemonic.strip_prefix("movzb") {
+        let size = match suffix {
+            "l" => Size::Dword,
+            "q" => Size::Qword,
+            _ => return Err(format!("unsupported instruction `{}`", mnemonic)),
+        };
+        return encode_integer("movzb", Some(size), ops);
+    }
+
+    const INTEGER: [&str; 20] = [
+        "add", "or", "adc", "sbb", "and", "sub", "xor", "cmp", "mov", "test", "inc", "dec", "not",
+        "neg", "mul", "shl", "shr", "sar", "push", "pop",
+    ];
+    const INTEGER_WIDE: [&str; 4] = ["lea", "imul", "bsf", "bsr"];
+    let known = |base: &str| INTEGER.contains(&base) || INTEGER_WIDE.contains(&base);
+    if known(mnemonic) {
+        return encode_integer(mnemonic, None, ops);
+    }
+    let suffix = mnemonic.chars().last().and_then(Size::from_suffix);
+    match suffix {
+        Some(size) if known(&mnemonic[..mnemonic.len() - 1]) => {
+            encode_integer(&mnemonic[..mnemonic.len() - 1], Some(size), ops)
+        }
+        _ => Err(format!("unsupported instruction `{}`", mnemonic)),
+    }
+}
+
+/// A symbol reference like `name`, `name+8` or a numeric label reference
+fn parse_symbol_expr<'a>(
+    expr: &'a str,
+    numeric: &dyn Fn(&str) -> Option<String>,
+) -> Result<(String, i64), String> {
+    let end = expr
+        .char_indices()
+        .find(|&(_, c)| !is_symbol_char(c))
+        .map_or(expr.len(), |(i, _)| i);
+    let (name, rest) = expr.split_at(end);
+    if name.is_empty() {
+        return Err(format!("invalid operand `{}`", expr));
+    }
+    let name = numeric(name).unwrap_or_else(|| name.to_string());
+    let addend = match rest.chars().next() {
+        None => 0,
+        Some('+') => parse_int(rest[1..].trim())?,
+        Some('-') => -parse_int(rest[1..].trim())?,
+        _ => return Err(format!("invalid operand `{}`", expr)),
+    };
+    Ok((name, addend))
+}
+
+fn parse_operand(
+    operand: &str,
+    numeric: &dyn Fn(&str) -> Option<String>,
+) -> Result<Operand, String> {
+    let reg = |name: &str| {
+        name.strip_prefix('%')
+            .and_then(parse_reg)
+            .ok_or_else(|| format!("invalid register `{}`", name))
+    };
+    if operand.starts_with('%') {
+        return Ok(Operand::Reg(reg(operand)?));
+    }
+    if let Some(imm) = operand.strip_prefix('$') {
+        return Ok(Operand::Imm(parse_int(imm)?));
+    }
+    let Some(open) = operand.find('(') else {
+        let (symbol, addend) = parse_symbol_expr(operand, numeric)?;
+        return Ok(Operand::Label(symbol, addend));
+    };
+    let inner = operand[open + 1..]
+        .strip_suffix(')')
+        .ok_or_else(|| format!("invalid memory operand `{}`", operand))?;
+    let disp = operand[..open].trim();
+    let parts: Vec<&str> = inner.split(',').map(str::trim).collect();
+    let (symbol, disp) = if disp.is_empty() {
+        (None, 0)
+    } else if let Ok(disp) = parse_int(disp) {
+        (None, disp)
+    } else {
+        let (symbol, addend) = parse_symbol_expr(disp, numeric)?;
+        (Some(symbol), addend)
+    };
+    let base = match parts[0] {
+        "%rip" => None,
+        "" => return Err(format!("memory operand without a base `{}`", operand)),
+        name => Some(reg(name)?),
+    };
+    let index = match parts.get(1) {
+        Some(index) => {
+            let scale = match parts.get(2) {
+                Some(scale) => parse_int(scale)?,
+                None => 1,
+            };
+            if ![1, 2, 4, 8].contains(&scale) {
+                return Err(format!("invalid scale `{}`", scale));
+            }
+            Some((reg(index)?, scale as u8))
+        }
+        None => None,
+    };
+    let address_reg = |reg: Option<Reg>| reg.is_none_or(|reg| reg.kind == RegKind::Qword);
+    if !address_reg(base) || !address_reg(index.map(|(reg, _)| reg)) {
+        return Err(format!("addresses need 64-bit registers `{}`", operand));
+    }
+    if index.is_some_and(|(reg, _)| reg.num == 4) {
+        return Err("%rsp can't be an index".to_string());
+    }
+    Ok(Operand::Mem(Mem {
+        base,
+        index,
+        symbol,
+        disp,
+    }))
+}
+
+fn section_index(name: &str) -> Result<usize, String> {
+    SECTION_NAMES
+        .iter()
+        .position(|&section| section == name)
+        .ok_or_else(|| format!("unsupported section `{}`", name))
+}
+
+struct Assembler {
+    sections: Vec<Section>,
+    section: usize,
+    section_stack: Vec<usize>,
+    /// Every label and `.lcomm` symbol with its section and offset
+    labels: HashMap<String, (usize, usize)>,
+    /// Labels to include in the symbol table, in order
+    symbols: Vec<String>,
+    globals: HashSet<String>,
+    /// Number of times each numeric label has been defined so far
+    numeric_labels: HashMap<String, usize>,
+    /// Section, offset within it and the reference, for every fixup
+    fixups: Vec<(usize, usize, Fixup)>,
+}
+
+impl Assembler {
+    /// Name for the `n`th definition of numeric label `label`. The name can't
+    /// clash with labels from the source, which can't contain `@`.
+    fn numeric_label_name(label: &str, n: usize) -> String {
+        format!(".L{}@{}", label, n)
+    }
+
+    /// Resolves a reference like `1f` or `2b` to the name of the label meant
+    fn numeric_reference(&self, reference: &str) -> Option<String> {
+        let label = reference.strip_suffix(['f', 'b'])?;
+        if label.is_empty() || !label.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let defined = self.numeric_labels.get(label).copied().unwrap_or(0);
+        let n = if reference.ends_with('f') {
+            defined + 1
+        } else {
+            defined
+        };
+        Some(Self::numeric_label_name(label, n))
+    }
+
+    fn define(&mut self, name: String, section: usize, offset: usize) -> Result<(), String> {
+        if self
+            .labels
+            .insert(name.clone(), (section, offset))
+            .is_some()
+        {
+            return Err(format!("`{}` is already defined", name));
+        }
+        if !name.starts_with(".L") {
+            self.symbols.push(name);
+        }
+        Ok(())
+    }
+
+    fn label(&mut self, name: &str) -> Result<(), String> {
+        let offset = self.sections[self.section].len();
+        if name.bytes().all(|b| b.is_ascii_digit()) {
+            let n = self.numeric_labels.entry(name.to_string()).or_insert(0);
+            *n += 1;
+            let name = Self::numeric_label_name(name, *n);
+            return self.define(name, self.section, offset);
+        }
+        self.define(name.to_string(), self.section, offset)
+    }
+
+    fn emit_data(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.section == BSS {
+            return Err("data in .bss".to_string());
+        }
+        self.sections[self.section].data.extend(bytes);
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: &str, args: &str) -> Result<(), String> {
+        match directive {
+            ".section" | ".pushsection" => {
+                // Flags and type after the name are ignored
+                let name = args.split([',', ' ', '\t']).next().unwrap_or("");
+                let section = section_index(name)?;
+                if directive == ".pushsection" {
+                    self.section_stack.push(self.section);
+                }
+                self.section = section;
+            }
+            ".text" | ".data" | ".bss" => self.section = section_index(directive)?,
+            ".popsection" => {
+                self.section = self
+                    .section_stack
+                    .pop()
+                    .ok_or(".popsection without .pushsection")?;
+            }
+            ".globl" | ".global" => {
+                for name in args.split(',') {
+                    self.globals.insert(name.trim().to_string());
+                }
+            }
+            ".lcomm" => {
+                let [name, size] = split_operands(args)[..] else {
+                    return Err("expected `.lcomm name, size`".to_string());
+                };
+                let size = usize::try_from(parse_int(size)?).map_err(|_| "negative size")?;
+                let bss = &mut self.sections[BSS];
+                let offset = bss.size.next_multiple_of(LCOMM_ALIGN);
+                bss.size = offset + size;
+                self.define(name.to_string(), BSS, offset)?;
+            }
+            ".byte" => {
+                for value in split_operands(args) {
+                    let value = parse_int(value)?;
+                    if !(-128..256).contains(&value) {
+                        return Err(format!("byte {} out of range", value));
+                    }
+                    self.emit_data(&[value as u8])?;
+                }
+            }
+            ".ascii" => {
+                for string in split_operands(args) {
+                    self.emit_data(&parse_string(string)?)?;
+                }
+            }
+            _ => return Err(format!("unsupported directive `{}`", directive)),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &str) -> Result<(), String> {
+        if self.section == BSS {
+            return Err("instructions in .bss".to_string());
+        }
+        let numeric = |reference: &str| self.numeric_reference(reference);
+        let ops = split_operands(operands)
+            .into_iter()
+            .map(|operand| parse_operand(operand, &numeric))
+            .collect::<Result<Vec<_>, _>>()?;
+        let encoded = encode(mnemonic, &ops)?;
+        let data = &self.sections[self.section].data;
+        if let Some(fixup) = encoded.fixup {
+            self.fixups.push((self.section, data.len(), fixup));
+        }
+        self.sections[self.section].data.extend(encoded.bytes);
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let mut line = strip_comment(line).trim();
+        // Any number of labels may come before a statement
+        while let Some(colon) = line.find(':') {
+            let name = &line[..colon];
+            if name.is_empty() || !name.chars().all(is_symbol_char) {
+                break;
+            }
+            self.label(name)?;
+            line = line[colon + 1..].trim();
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+        let (first, rest) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(first, rest)| (first, rest.trim()));
+        if first.starts_with('.') {
+            self.directive(first, rest)
+        } else {
+            self.instruction(first, rest)
+        }
+    }
+
+    /// Patches references within a section and turns the rest into relocations
+    fn finish(mut self) -> Result<Object, String> {
+        let mut undefined = vec![];
+        for (section, offset, fixup) in std::mem::take(&mut self.fixups) {
+            let position = offset + fixup.position;
+            let (target, addend) = match self.labels.get(&fixup.symbol) {
+                Some(&(target_section, target)) if target_section == section => {
+                    let value = target as i64 + fixup.addend - position as i64;
+                    let value = i32::try_from(value)
+                        .map_err(|_| format!("`{}` is out of range", fixup.symbol))?;
+                    self.sections[section].data[position..position + 4]
+                        .copy_from_slice(&value.to_le_bytes());
+                    continue;
+                }
+                Some(_) if self.globals.contains(&fixup.symbol) => {
+                    (RelocationTarget::Symbol(fixup.symbol), fixup.addend)
+                }
+                Some(&(target_section, target)) => (
+                    RelocationTarget::Section(target_section),
+                    fixup.addend + target as i64,
+                ),
+                None if fixup.symbol.starts_with(".L") => {
+                    return Err(format!("undefined label `{}`", fixup.symbol));
+                }
+                None => {
+                    if !undefined.contains(&fixup.symbol) {
+                        undefined.push(fixup.symbol.clone());
+                    }
+                    (RelocationTarget::Symbol(fixup.symbol), fixup.addend)
+                }
+            };
+            self.sections[section].relocations.push(Relocation {
+                offset: position,
+                target,
+                addend,
+                branch: fixup.branch,
+            });
+        }
+
+        let symbols = self
+            .symbols
+            .iter()
+            .map(|name| {
+                let (section, offset) = self.labels[name];
+                Symbol {
+                    name: name.clone(),
+                    section,
+                    offset,
+                    global: self.globals.contains(name),
+                }
+            })
+            .collect();
+        Ok(Object {
+            sections: self.sections,
+            symbols,
+            undefined,
+        })
+    }
+}
+
+/// Assembles the output of the x86-64 backend
+pub fn assemble(asm: &str) -> Result<Object, String> {
+    let mut assembler = Assembler {
+        sections: (0..SECTION_NAMES.len())
+            .map(|_| Section {
+                data: vec![],
+                size: 0,
+                relocations: vec![],
+            })
+            .collect(),
+        section: TEXT,
+        section_stack: vec![],
+        labels: HashMap::new(),
+        symbols: vec![],
+        globals: HashSet::new(),
+        numeric_labels: HashMap::new(),
+        fixups: vec![],
+    };
+    for (i, line) in asm.lines().enumerate() {
+        assembler
+            .line(line)
+            .map_err(|e| format!("Error: Assembly line {}: {}: `{}`", i + 1, e, line.trim()))?;
+    }
+    assembler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{
+        compile, Artifact, AsmSyntax, CompileOptions, Destination, ExitCode, Simd, Target,
+    };
+    use crate::optimizer::tests::{optimized_bf, HELLO};
+    use crate::optimizer::InfiniteLoopMode;
+
+    /// Instructions and relocations in `objdump -dr` output for `path`, without
+    /// addresses, encodings or branch targets, since `as` picks shorter jumps
+    fn disassembly(path: &std::path::Path) -> Vec<String> {
+        let output = std::process::Command::new("objdump")
+            .args(["-dr", "--no-show-raw-insn"])
+            .arg(path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter_map(|line| {
+                // Instructions and relocations are indented, after their offset
+                let (address, text) = line.split_once(':')?;
+                let address = address.trim_start();
+                if !line.starts_with(char::is_whitespace)
+                    || !address.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    return None;
+                }
+                let text = text.split('#').next().unwrap();
+                let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                let mnemonic = text.split(' ').next().unwrap_or("");
+                if mnemonic.starts_with('j') || mnemonic == "call" {
+                    Some(mnemonic.to_string())
+                } else {
+                    Some(text)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_gnu_as() {
+        let programs = [
+            HELLO,
+            ",[>>+>+<<<-]>>[<<+>>-]+>[<->[-]]<[>>>[<]>>>[-->]<<[++]]<<.,[.,]",
+        ];
+        let dir = std::env::temp_dir().join(format!("bfr-as-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let asm_path = dir.join("a.s");
+        let as_object = dir.join("as.o");
+        let object = dir.join("integrated.o");
+
+        for src in programs {
+            let commands = optimized_bf(src, 3, InfiniteLoopMode::Trap);
+            for simd in [Simd::Avx2, Simd::Sse2, Simd::None, Simd::Auto] {
+                for freestanding in [false, true] {
+                    let options = CompileOptions {
+                        target: Target::X86_64,
+                        simd,
+                        exit_code: ExitCode::Cell,
+                        freestanding,
+                        integrated_as: false,
+                        asm_syntax: AsmSyntax::Att,
+                        source_map: None,
+                        debug_info: false,
+                        annotate: false,
+                        counts: false,
+                    };
+                    let dest = Destination::File(asm_path.to_string_lossy().into_owned());
+                    compile(&commands, Artifact::Assembly, &dest, &options).unwrap();
+                    let asm = std::fs::read_to_string(&asm_path).unwrap();
+
+                    let status = std::process::Command::new("as")
+                        .arg("-o")
+                        .arg(&as_object)
+                        .arg(&asm_path)
+                        .status()
+                        .unwrap();
+                    assert!(status.success());
+                    let assembled = assemble(&asm).unwrap();
+                    std::fs::write(&object, crate::elf::write_object(&assembled)).unwrap();
+
+                    let expected = disassembly(&as_object);
+                    assert!(expected.iter().any(|line| line.starts_with("R_X86_64")));
+                    assert_eq!(
+                        disassembly(&object),
+                        expected,
+                        "{} with {:?}, freestanding: {}",
+                        src,
+                        simd,
+                        freestanding
+                    );
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
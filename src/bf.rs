@@ -0,0 +1,254 @@
+//! Lowers the command tree back to plain eight-command Brainfuck.
+//!
+//! Pointer movement is emitted lazily, so offsets only cost the moves needed
+//! to reach the cell being touched. The pointer is brought back in line with
+//! the logical pointer before every `[` and `]`.
+//!
+//! `AddOffsetData`/`SubOffsetData` can only be expressed as a multiplication
+//! loop, which consumes the source cell. They are therefore lowered as a group
+//! together with the `SetData` that clears the source cell afterwards, which is
+//! the shape `replace_simple_loops` produces. Constant output needs a scratch
+//! cell and cannot be lowered at all.
+
+use crate::parser::{Command, Direction, OutputType};
+
+const LINE_WIDTH: usize = 80;
+
+struct BfWriter {
+    out: String,
+    /// Logical pointer, relative to the start of the current loop body
+    logical: isize,
+    /// Pointer position the emitted code has moved to, relative to the same point
+    physical: isize,
+}
+
+impl BfWriter {
+    fn move_to(&mut self, target: isize) {
+        let diff = target - self.physical;
+        if diff > 0 {
+            self.out.push_str(&">".repeat(diff as usize));
+        } else {
+            self.out.push_str(&"<".repeat((-diff) as usize));
+        }
+        self.physical = target;
+    }
+
+    /// Adds `amount` to the cell under the pointer, using the shorter of `+` and `-`
+    fn add(&mut self, amount: u8) {
+        if amount <= 128 {
+            self.out.push_str(&"+".repeat(amount as usize));
+        } else {
+            self.out.push_str(&"-".repeat(256 - amount as usize));
+        }
+    }
+
+    fn write_commands(&mut self, commands: &[Command]) -> Result<(), String> {
+        let mut i = 0;
+        while i < commands.len() {
+            match &commands[i] {
+                Command::IncPointer { amount, .. } => self.logical += *amount as isize,
+                Command::DecPointer { amount, .. } => self.logical -= *amount as isize,
+                Command::IncData { offset, amount, .. } => {
+                    self.move_to(self.logical + offset);
+                    self.add(*amount);
+                }
+                Command::DecData { offset, amount, .. } => {
+                    self.move_to(self.logical + offset);
+                    self.add(0u8.wrapping_sub(*amount));
+                }
+                Command::SetData { offset, value, .. } => {
+                    self.move_to(self.logical + offset);
+                    self.out.push_str("[-]");
+                    self.add(*value);
+                }
+                Command::Scan {
+                    direction,
+                    skip_amount,
+                    offset,
+                    ..
+                } => {
+                    // The pointer stays `offset` cells behind the cell being tested
+                    self.move_to(self.logical + offset);
+                    let step = match direction {
+                        Direction::Left => "<",
+                        Direction::Right => ">",
+                    };
+                    self.out.push('[');
+                    self.out.push_str(&step.repeat(*skip_amount));
+                    self.out.push(']');
+                }
+                Command::AddOffsetData { src_offset, .. }
+                | Command::SubOffsetData { src_offset, .. } => {
+                    i = self.write_multiply_loop(commands, i, *src_offset)?;
+                    continue;
+                }
+                Command::Output { out_type, .. } => match out_type {
+                    OutputType::Const(val) => {
+                        return Err(format!(
+                            "Error: Cannot lower constant output ({}) to Brainfuck. Try again without --partial-eval.",
+                            val
+                        ));
+                    }
+                    OutputType::Cell { offset } => {
+                        self.move_to(self.logical + offset);
+                        self.out.push('.');
+                    }
+                },
+                Command::Input { offset, .. } => {
+                    self.move_to(self.logical + offset);
+                    self.out.push(',');
+                }
+                Command::InfiniteLoop { divisor, .. } => {
+                    // Stepping by the divisor terminates on exactly the same values
+                    self.move_to(self.logical);
+                    self.out.push('[');
+                    self.out.push_str(&"-".repeat(*divisor as usize));
+                    self.out.push(']');
+                }
+                // The body of an `If` clears the cell, so a loop runs it at most once too
+                Command::If { body, .. } | Command::Loop { body, .. } => {
+                    self.move_to(self.logical);
+                    self.out.push('[');
+                    let (outer_logical, outer_physical) = (self.logical, self.physical);
+                    self.logical = 0;
+                    self.physical = 0;
+                    self.write_commands(body)?;
+                    self.move_to(self.logical);
+                    self.out.push(']');
+                    self.logical = outer_logical;
+                    self.physical = outer_physical;
+                }
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Lowers the run of offset data commands starting at `start` that read
+    /// `src_offset`, plus the clear of the source cell that must follow them.
+    /// Returns the index of the first command after the group.
+    fn write_multiply_loop(
+        &mut self,
+        commands: &[Command],
+        start: usize,
+        src_offset: isize,
+    ) -> Result<usize, String> {
+        // Each entry is the amount added to `dest_offset` per decrement of the source
+        let mut deltas: Vec<(isize, u8)> = vec![];
+        let mut end = start;
+        while end < commands.len() {
+            let (dest_offset, multiplier, inverted, is_sub) = match &commands[end] {
+                Command::AddOffsetData {
+                    dest_offset,
+                    src_offset: other_src,
+                    multiplier,
+                    inverted,
+                    ..
+                } if *other_src == src_offset => (*dest_offset, *multiplier, *inverted, false),
+                Command::SubOffsetData {
+                    dest_offset,
+                    src_offset: other_src,
+                    multiplier,
+                    inverted,
+                    ..
+                } if *other_src == src_offset => (*dest_offset, *multiplier, *inverted, true),
+                _ => break,
+            };
+            if dest_offset == src_offset {
+                return Err("Error: Cannot lower an offset data command that reads and writes the same cell to Brainfuck.".to_string());
+            }
+            let delta = (multiplier % 256) as u8;
+            // An inverted add is a subtraction of the source value and vice versa
+            let delta = if inverted != is_sub {
+                0u8.wrapping_sub(delta)
+            } else {
+                delta
+            };
+            deltas.push((dest_offset, delta));
+            end += 1;
+        }
+
+        match commands.get(end) {
+            Some(Command::SetData {
+                offset, value: 0, ..
+            }) if *offset == src_offset => (),
+            _ => {
+                return Err(
+                    "Error: Cannot lower an offset data command to Brainfuck unless its source cell is cleared right after it."
+                        .to_string(),
+                );
+            }
+        }
+
+        let src = self.logical + src_offset;
+        self.move_to(src);
+        self.out.push_str("[-");
+        for (dest_offset, delta) in deltas {
+            self.move_to(self.logical + dest_offset);
+            self.add(delta);
+        }
+        self.move_to(src);
+        self.out.push(']');
+
+        Ok(end + 1)
+    }
+}
+
+pub fn write_bf(commands: &[Command]) -> Result<String, String> {
+    let mut writer = BfWriter {
+        out: String::new(),
+        logical: 0,
+        physical: 0,
+    };
+    writer.write_commands(commands)?;
+
+    let mut out = String::new();
+    for line in writer.out.as_bytes().chunks(LINE_WIDTH) {
+        // The writer only emits ASCII command characters
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::interp_io;
+    use crate::ir::parse_ir;
+    use crate::optimizer::tests::{optimized_bf, HELLO};
+    use crate::optimizer::InfiniteLoopMode;
+    use crate::parser::parse;
+
+    fn output(commands: &mut [Command]) -> Vec<u8> {
+        let mut output = vec![];
+        interp_io(commands, &mut std::io::empty(), &mut output);
+        output
+    }
+
+    #[test]
+    fn lowered_programs_behave_the_same() {
+        let programs = [
+            HELLO,
+            // A scan with a stride, and a multiplication of input into cells on
+            // both sides
+            "+>>+>>+>>+<<[<<]>>[.>>],[->+++<<++>]>.<<.",
+            // A conditional loop and a cleared cell
+            "+++[>+++++<[-]]>.[-]+.",
+        ];
+        for src in programs {
+            let expected = output(&mut parse(&src.to_string()));
+            let commands = optimized_bf(src, 3, InfiniteLoopMode::Ignore);
+            let bf = write_bf(&commands).unwrap();
+            assert!(bf.lines().all(|line| line.len() <= LINE_WIDTH));
+            assert_eq!(output(&mut parse(&bf)), expected, "{}", src);
+        }
+    }
+
+    #[test]
+    fn constant_output_is_an_error() {
+        let commands = parse_ir("out_const 65\n").unwrap();
+        let error = write_bf(&commands).unwrap_err();
+        assert!(error.starts_with("Error: Cannot lower constant output (65)"));
+    }
+}
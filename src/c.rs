@@ -0,0 +1,328 @@
+//! Translates the command tree into a self-contained C program.
+//!
+//! The output only needs a hosted C99 implementation. The tape is a static
+//! array and `p` points into it; cells are `unsigned char`, so arithmetic wraps
+//! the same way as in the interpreter. Unit-stride scans call `memchr`, and
+//! left scans use `memrchr` where the C library provides it.
+
+use crate::compiler::{escape_ascii, ExitCode, INIT_POINTER_LOC, INIT_TAPE_SIZE};
+use crate::parser::{divisor_mask, output_runs, run_bytes, Command, Direction, OutputType};
+
+struct CWriter {
+    out: String,
+    indent: usize,
+    /// Whether anything touches the tape. Programs reduced to constant
+    /// output by `--partial-eval` don't, and then get no tape at all.
+    uses_tape: bool,
+    /// Helper functions the program calls, which are only defined if used
+    uses_scan_right: bool,
+    uses_scan_left: bool,
+    uses_infinite_loop: bool,
+}
+
+impl CWriter {
+    fn line(&mut self, line: &str) {
+        self.out.push_str(&"    ".repeat(self.indent));
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn block(&mut self, header: &str, body: &[Command]) {
+        self.line(&format!("{} {{", header));
+        self.indent += 1;
+        self.write_commands(body);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn write_commands(&mut self, commands: &[Command]) {
+        for run in output_runs(commands) {
+            let command = &run[0];
+            if !matches!(
+                command,
+                Command::Output {
+                    out_type: OutputType::Const(_),
+                    ..
+                }
+            ) {
+                self.uses_tape = true;
+            }
+            match command {
+                Command::IncPointer { amount, .. } => self.line(&format!("p += {};", amount)),
+                Command::DecPointer { amount, .. } => self.line(&format!("p -= {};", amount)),
+                Command::IncData { offset, amount, .. } => {
+                    self.line(&format!("p[{}] += {};", offset, amount))
+                }
+                Command::DecData { offset, amount, .. } => {
+                    self.line(&format!("p[{}] -= {};", offset, amount))
+                }
+                Command::SetData { offset, value, .. } => {
+                    self.line(&format!("p[{}] = {};", offset, value))
+                }
+                Command::Scan {
+                    direction,
+                    skip_amount: 1,
+                    offset,
+                    ..
+                } => {
+                    let function = match direction {
+                        Direction::Right => {
+                            self.uses_scan_right = true;
+                            "scan_right"
+                        }
+                        Direction::Left => {
+                            self.uses_scan_left = true;
+                            "scan_left"
+                        }
+                    };
+                    self.line(&format!("p = {}(p + {}) - {};", function, offset, offset));
+                }
+                Command::Scan {
+                    direction,
+                    skip_amount,
+                    offset,
+                    ..
+                } => {
+                    let step = match direction {
+                        Direction::Left => "-=",
+                        Direction::Right => "+=",
+                    };
+                    self.line(&format!(
+                        "while (p[{}]) p {} {};",
+                        offset, step, skip_amount
+                    ));
+                }
+                Command::AddOffsetData {
+                    dest_offset,
+                    src_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                }
+                | Command::SubOffsetData {
+                    dest_offset,
+                    src_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    // Subtracting the inverted source is the same as adding it
+                    let add = matches!(command, Command::AddOffsetData { .. }) != *inverted;
+                    let op = if add { "+=" } else { "-=" };
+                    // Only the low byte of the product matters, and reducing
+                    // the multiplier keeps it from overflowing an int
+                    let multiplier = multiplier % 256;
+                    if multiplier == 1 {
+                        self.line(&format!("p[{}] {} p[{}];", dest_offset, op, src_offset));
+                    } else {
+                        self.line(&format!(
+                            "p[{}] {} p[{}] * {};",
+                            dest_offset, op, src_offset, multiplier
+                        ));
+                    }
+                }
+                Command::Output {
+                    out_type: OutputType::Const(val),
+                    ..
+                } => {
+                    let bytes = run_bytes(run);
+                    if bytes.len() == 1 {
+                        self.line(&format!("putchar({});", val));
+                    } else {
+                        // Escape `?` so that no trigraphs can form
+                        let escaped = escape_ascii(&bytes).replace('?', "\\?");
+                        self.line(&format!(
+                            "fwrite(\"{}\", 1, {}, stdout);",
+                            escaped,
+                            bytes.len()
+                        ));
+                    }
+                }
+                Command::Output {
+                    out_type: OutputType::Cell { offset },
+                    ..
+                } => self.line(&format!("putchar(p[{}]);", offset)),
+                Command::Input { offset, .. } => {
+                    // EOF converts to 255, like the interpreter stores
+                    self.line("fflush(stdout);");
+                    self.line(&format!("p[{}] = getchar();", offset));
+                }
+                Command::InfiniteLoop { id, divisor, .. } => {
+                    self.line("if (p[0]) {");
+                    self.indent += 1;
+                    if let Some(mask) = divisor_mask(*divisor) {
+                        self.line(&format!("if ((p[0] & {}) == 0) {{", mask));
+                        self.indent += 1;
+                        self.line("p[0] = 0;");
+                        self.indent -= 1;
+                        self.line("} else {");
+                        self.indent += 1;
+                    }
+                    self.uses_infinite_loop = true;
+                    self.line(&format!("infinite_loop({});", id));
+                    if divisor_mask(*divisor).is_some() {
+                        self.indent -= 1;
+                        self.line("}");
+                    }
+                    self.indent -= 1;
+                    self.line("}");
+                }
+                Command::If { body, .. } => self.block("if (p[0])", body),
+                Command::Loop { body, .. } => self.block("while (p[0])", body),
+            }
+        }
+    }
+}
+
+/// Returns C source for the program. `exit_code` decides what `main` returns,
+/// as with `--exit-code` when compiling.
+pub fn write_c(commands: &[Command], exit_code: ExitCode) -> String {
+    let mut writer = CWriter {
+        out: String::new(),
+        indent: 1,
+        uses_tape: false,
+        uses_scan_right: false,
+        uses_scan_left: false,
+        uses_infinite_loop: false,
+    };
+    writer.write_commands(commands);
+
+    let mut helpers = String::new();
+    if writer.uses_scan_right {
+        helpers.push_str(
+            r#"
+static unsigned char *scan_right(unsigned char *p)
+{
+    return memchr(p, 0, tape + TAPE_SIZE - p);
+}
+"#,
+        );
+    }
+    if writer.uses_scan_left {
+        helpers.push_str(
+            r#"
+static unsigned char *scan_left(unsigned char *p)
+{
+#ifdef __GLIBC__
+    return memrchr(tape, 0, p - tape + 1);
+#else
+    /* memrchr is a GNU extension */
+    while (*p)
+        p--;
+    return p;
+#endif
+}
+"#,
+        );
+    }
+    if writer.uses_infinite_loop {
+        helpers.push_str(
+            r#"
+static void infinite_loop(int id)
+{
+    fflush(stdout);
+    fprintf(stderr, "infinite loop detected at loop %d\n", id);
+    exit(1);
+}
+"#,
+        );
+    }
+
+    let status = match exit_code {
+        ExitCode::Zero => "0",
+        ExitCode::Cell => "p[0]",
+    };
+    let (tape, pointer) = if writer.uses_tape || exit_code == ExitCode::Cell {
+        (
+            format!(
+                "\n#define TAPE_SIZE {}\n\nstatic unsigned char tape[TAPE_SIZE];\n",
+                INIT_TAPE_SIZE
+            ),
+            format!("    unsigned char *p = tape + {};\n\n", INIT_POINTER_LOC),
+        )
+    } else {
+        (String::new(), String::new())
+    };
+    format!(
+        r#"/* Generated by bfr */
+#define _GNU_SOURCE
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+{tape}{helpers}
+int main(void)
+{{
+{pointer}{body}
+    fflush(stdout);
+    return {status};
+}}
+"#,
+        tape = tape,
+        pointer = pointer,
+        helpers = helpers,
+        body = writer.out,
+        status = status
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::{installed, run_with_input};
+    use crate::compiler::TempDir;
+    use crate::interp::interp_io;
+    use crate::optimizer::tests::{optimized_bf, HELLO};
+    use crate::optimizer::InfiniteLoopMode;
+
+    /// Compiles the C translation of `src` with `cc` and runs it on `input`
+    fn run_c(src: &str, input: &[u8]) -> std::process::Output {
+        let commands = optimized_bf(src, 3, InfiniteLoopMode::Trap);
+        let dir = TempDir::new().unwrap();
+        let (c_path, path) = (dir.file("a.c"), dir.file("a.out"));
+        std::fs::write(&c_path, write_c(&commands, ExitCode::Cell)).unwrap();
+        let status = std::process::Command::new("cc")
+            .args(["-std=c99", "-o", &path, &c_path])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        run_with_input(&mut std::process::Command::new(&path), input)
+    }
+
+    #[test]
+    fn behaves_like_the_interpreter() {
+        if !installed("cc") {
+            return;
+        }
+        let programs = [
+            HELLO,
+            // Scans with strides of one and two
+            "+>+>+>+>>+>>+>>+[<<]<[<]>[>]>>[>>]<+++++[<++++++++++>-]<.",
+            // Input multiplied into cells on both sides
+            ",[->+++<<++>]>.<<.",
+            // An infinite loop that ends because the input is even
+            ",[--]+++.",
+        ];
+        for src in programs {
+            let mut expected = vec![];
+            let cell = interp_io(
+                &mut crate::parser::parse(&src.to_string()),
+                &mut &b"xyz"[..],
+                &mut expected,
+            );
+            let output = run_c(src, b"xyz");
+            assert_eq!(output.stdout, expected, "{}", src);
+            assert_eq!(output.status.code(), Some(cell as i32), "{}", src);
+        }
+    }
+
+    #[test]
+    fn infinite_loops_trap() {
+        if !installed("cc") {
+            return;
+        }
+        let output = run_c("++.>+++[]", b"");
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stdout, b"\x02");
+        assert_eq!(output.stderr, b"infinite loop detected at loop 1\n");
+    }
+}
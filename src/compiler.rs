@@ -1,33 +1,29 @@
-use crate::parser::{Command, Direction, OutputType};
+use crate::debuginfo::{LoopPart, SourceMap};
+use crate::parser::{
+    divisor_mask, output_runs, pretty_command, run_bytes, Command, Direction, OutputType,
+};
 
-const INIT_TAPE_SIZE: usize = 0x200000;
-const INIT_POINTER_LOC: usize = 0x4000;
+pub(crate) const INIT_TAPE_SIZE: usize = 0x200000;
+pub(crate) const INIT_POINTER_LOC: usize = 0x4000;
+/// Vector scans load whole vectors around the cells they test, reaching up to
+/// one vector width past either end of the tape. The allocation is padded by
+/// the widest vector on both sides so those loads stay inside it.
+pub(crate) const TAPE_PADDING: usize = 32;
+/// Output is collected in a buffer of this many bytes and written in one go
+pub(crate) const OUTPUT_BUFFER_SIZE: usize = 8192;
 
-fn replace_extension_filepath(filepath: &str, ext: &str) -> String {
-    return if let Some(pos) = filepath.rfind('.') {
-        format!("{}{}", &filepath[..pos], ext)
-    } else {
-        format!("{}{}", &filepath, ext)
-    };
-}
-
-fn strip_directories_filepath(filepath: &str) -> &str {
-    return if let Some(pos) = filepath.rfind('/') {
-        &filepath[pos + 1..]
-    } else {
-        filepath
-    };
-}
-
-fn assemble(asm_string: &str, object_filepath: &str) -> Result<(), String> {
-    let mut as_process = std::process::Command::new("as")
+fn assemble(asm_string: &str, object_filepath: &str, target: Target) -> Result<(), String> {
+    let assembler = target.tool("as");
+    let mut as_process = std::process::Command::new(&assembler)
         .arg("-o")
         .arg(object_filepath)
         .stdin(std::process::Stdio::piped())
         .spawn()
         .map_err(|_| {
-            "Error: `as` assembler not found. Please ensure it is installed on your system."
-                .to_string()
+            format!(
+                "Error: `{}` assembler not found. Please ensure it is installed on your system.",
+                assembler
+            )
         })?;
 
     // Write the assembly code to the stdin of the `as` process
@@ -54,23 +50,39 @@ fn assemble(asm_string: &str, object_filepath: &str) -> Result<(), String> {
     Ok(())
 }
 
-fn link(object_filepath: &str, dest_file: &str, keep_object: bool) -> Result<(), String> {
-    let ld_status = std::process::Command::new("ld")
-        .arg("-o")
-        .arg(dest_file)
-        .arg("-dynamic-linker")
-        .arg("/lib64/ld-linux-x86-64.so.2")
-        .arg("/usr/lib/x86_64-linux-gnu/crt1.o")
-        .arg("/usr/lib/x86_64-linux-gnu/crti.o")
-        .arg("-lc")
-        .arg(object_filepath)
-        .arg("/usr/lib/x86_64-linux-gnu/crtn.o")
+fn link(
+    object_filepath: &str,
+    dest_file: &str,
+    freestanding: bool,
+    target: Target,
+) -> Result<(), String> {
+    let linker = target.tool("ld");
+    let mut ld_cmd = std::process::Command::new(&linker);
+    ld_cmd.arg("-o").arg(dest_file);
+    if freestanding {
+        // Nothing to link against, so no system paths are needed
+        ld_cmd.arg("-static").arg(object_filepath);
+    } else {
+        let lib_dir = target.lib_dir();
+        ld_cmd
+            .arg("-dynamic-linker")
+            .arg(target.dynamic_linker())
+            .arg(format!("{}/crt1.o", lib_dir))
+            .arg(format!("{}/crti.o", lib_dir))
+            .arg(format!("-L{}", lib_dir))
+            .arg("-lc")
+            .arg(object_filepath)
+            .arg(format!("{}/crtn.o", lib_dir));
+    }
+    let ld_status = ld_cmd
         .arg("-z")
         .arg("noexecstack")
         .spawn()
         .map_err(|_| {
-            "Error: `ld` linker not found. Please ensure it is installed on your system."
-                .to_string()
+            format!(
+                "Error: `{}` linker not found. Please ensure it is installed on your system.",
+                linker
+            )
         })?
         .wait()
         .map_err(|_| "Error: Failed to wait for linker process.".to_string())?;
@@ -82,25 +94,28 @@ fn link(object_filepath: &str, dest_file: &str, keep_object: bool) -> Result<(),
         ));
     }
 
-    // Delete the object file unless `keep_object` flag is set
-    if !keep_object {
-        std::fs::remove_file(object_filepath)
-            .map_err(|_| format!("Warning: Failed to delete object file: {}", object_filepath))?;
-    }
-
     Ok(())
 }
 
-fn clang(asm_file: &str, dest_file: &str, keep_object: bool) -> Result<(), String> {
+fn clang(
+    asm_file: &str,
+    dest_file: &str,
+    keep_object: bool,
+    freestanding: bool,
+    target: Target,
+) -> Result<(), String> {
     let mut clang_cmd = std::process::Command::new("clang");
 
     clang_cmd
+        .arg(format!("--target={}", target.triple()))
         .arg("-o")
         .arg(dest_file)
         .arg(asm_file);
 
     if keep_object {
         clang_cmd.arg("-c");
+    } else if freestanding {
+        clang_cmd.arg("-nostdlib").arg("-static");
     }
 
     let mut clang_process = clang_cmd.spawn().map_err(|_| {
@@ -123,21 +138,545 @@ fn clang(asm_file: &str, dest_file: &str, keep_object: bool) -> Result<(), Strin
     Ok(())
 }
 
-fn append_assembly_header(out_string: &mut String, ptr_reg: &str, full_byte_reg: &str) {
+/// A directory for intermediate files, removed with its contents when dropped
+pub(crate) struct TempDir {
+    path: std::path::PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new() -> Result<TempDir, String> {
+        let base = std::env::temp_dir();
+        for attempt in 0..100 {
+            let path = base.join(format!("bfr-{}-{}", std::process::id(), attempt));
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    return Err(format!(
+                        "Error: Failed to create a temporary directory in {}: {}",
+                        base.display(),
+                        e
+                    ))
+                }
+            }
+        }
+        Err(format!(
+            "Error: Failed to create a temporary directory in {}",
+            base.display()
+        ))
+    }
+
+    pub(crate) fn file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Where an output file goes
+pub enum Destination {
+    Stdout,
+    File(String),
+}
+
+impl Destination {
+    /// `-` means stdout
+    pub fn new(path: &str) -> Destination {
+        if path == "-" {
+            Destination::Stdout
+        } else {
+            Destination::File(path.to_string())
+        }
+    }
+
+    /// Writes `contents`, making the file executable if `executable` is set
+    pub fn write(&self, contents: &[u8], executable: bool) -> Result<(), String> {
+        match self {
+            Destination::Stdout => {
+                use std::io::Write;
+                let mut stdout = std::io::stdout().lock();
+                stdout
+                    .write_all(contents)
+                    .and_then(|()| stdout.flush())
+                    .map_err(|e| format!("Error: Failed to write to stdout: {}", e))
+            }
+            Destination::File(path) => {
+                std::fs::write(path, contents)
+                    .map_err(|e| format!("Error: Failed to write {}: {}", path, e))?;
+                if executable {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+                        .map_err(|e| format!("Error: Failed to make {} executable: {}", path, e))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What `compile` produces
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Artifact {
+    Assembly,
+    Object,
+    Executable,
+}
+
+/// Architecture and ABI to generate code for
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Target {
+    #[value(name = "x86_64-linux-gnu")]
+    X86_64,
+    #[value(name = "aarch64-linux-gnu")]
+    Aarch64,
+    #[value(name = "riscv64-linux-gnu", alias = "riscv64")]
+    Riscv64,
+}
+
+impl Target {
+    fn triple(self) -> &'static str {
+        match self {
+            Target::X86_64 => "x86_64-linux-gnu",
+            Target::Aarch64 => "aarch64-linux-gnu",
+            Target::Riscv64 => "riscv64-linux-gnu",
+        }
+    }
+
+    fn arch(self) -> &'static str {
+        match self {
+            Target::X86_64 => "x86_64",
+            Target::Aarch64 => "aarch64",
+            Target::Riscv64 => "riscv64",
+        }
+    }
+
+    fn is_native(self) -> bool {
+        self.arch() == std::env::consts::ARCH
+    }
+
+    /// Name of a binutils program, with the usual `<triple>-` prefix when cross compiling
+    fn tool(self, name: &str) -> String {
+        if self.is_native() {
+            name.to_string()
+        } else {
+            format!("{}-{}", self.triple(), name)
+        }
+    }
+
+    fn dynamic_linker(self) -> &'static str {
+        match self {
+            Target::X86_64 => "/lib64/ld-linux-x86-64.so.2",
+            Target::Aarch64 => "/lib/ld-linux-aarch64.so.1",
+            Target::Riscv64 => "/lib/ld-linux-riscv64-lp64d.so.1",
+        }
+    }
+
+    /// Directory with the C runtime objects and libc, using the layout of
+    /// Debian's cross compilation packages for foreign targets
+    fn lib_dir(self) -> String {
+        if self.is_native() {
+            format!("/usr/lib/{}", self.triple())
+        } else {
+            format!("/usr/{}/lib", self.triple())
+        }
+    }
+
+    /// Scan instructions used when neither `--simd` nor `--target-cpu` is given
+    pub fn default_simd(self) -> Simd {
+        match self {
+            Target::X86_64 => Simd::Avx2,
+            Target::Aarch64 => Simd::Neon,
+            // The vector extension is optional on RISC-V
+            Target::Riscv64 => Simd::None,
+        }
+    }
+
+    pub fn supports_simd(self, simd: Simd) -> bool {
+        match self {
+            Target::X86_64 => !matches!(simd, Simd::Neon | Simd::Rvv),
+            Target::Aarch64 => matches!(simd, Simd::Neon | Simd::None),
+            Target::Riscv64 => matches!(simd, Simd::Rvv | Simd::None),
+        }
+    }
+}
+
+/// Vector instructions used for scans
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Simd {
+    /// 32-byte AVX2 compares
+    Avx2,
+    /// 16-byte SSE2 compares, available on every x86-64 CPU
+    Sse2,
+    /// 16-byte NEON compares, available on every AArch64 CPU
+    Neon,
+    /// Strided loads from the RISC-V vector extension
+    Rvv,
+    /// Plain byte-at-a-time loops
+    None,
+    /// AVX2 if the CPU running the program supports it, SSE2 otherwise
+    Auto,
+}
+
+/// CPUs to generate code for, as an alternative to choosing `--simd` directly
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum TargetCpu {
+    /// Any x86-64 CPU
+    #[value(name = "x86-64")]
+    X86_64,
+    /// x86-64 CPUs with SSE4.2 and POPCNT
+    #[value(name = "x86-64-v2")]
+    X86_64V2,
+    /// x86-64 CPUs with AVX2, such as Haswell and later
+    #[value(name = "x86-64-v3")]
+    X86_64V3,
+    /// The CPU running the compiler
+    Native,
+}
+
+impl TargetCpu {
+    pub fn simd(self) -> Simd {
+        match self {
+            TargetCpu::X86_64 | TargetCpu::X86_64V2 => Simd::Sse2,
+            TargetCpu::X86_64V3 => Simd::Avx2,
+            TargetCpu::Native => {
+                #[cfg(target_arch = "x86_64")]
+                if std::arch::is_x86_feature_detected!("avx2") {
+                    return Simd::Avx2;
+                }
+                Simd::Sse2
+            }
+        }
+    }
+}
+
+/// What the exit status of a compiled program is
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ExitCode {
+    /// Always 0
+    Zero,
+    /// The value of the current cell when the program ends
+    Cell,
+}
+
+/// Syntax of x86-64 assembly output
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum AsmSyntax {
+    /// AT&T syntax, as used by the GNU assembler by default
+    Att,
+    /// Intel syntax, with `.intel_syntax noprefix`
+    Intel,
+}
+
+pub struct CompileOptions {
+    pub target: Target,
+    pub simd: Simd,
+    pub exit_code: ExitCode,
+    /// Use raw Linux syscalls and a `_start` entry point instead of libc
+    pub freestanding: bool,
+    /// Assemble and link with the built-in assembler instead of external tools
+    pub integrated_as: bool,
+    /// Syntax of the assembly written with `-S`. x86-64 only.
+    pub asm_syntax: AsmSyntax,
+    /// Source positions, when compiling Brainfuck with `-g` or `--annotate`
+    pub source_map: Option<SourceMap>,
+    /// Emit DWARF line info, with `-g`
+    pub debug_info: bool,
+    /// Comment each part of the code with its source and IR command
+    pub annotate: bool,
+    /// Include the counts from a profiling run in the annotations
+    pub counts: bool,
+}
+
+impl CompileOptions {
+    /// Symbol the program code starts at
+    pub fn entry(&self) -> &'static str {
+        if self.freestanding {
+            "_start"
+        } else {
+            "main"
+        }
+    }
+
+    /// The source map, when emitting debug info
+    pub fn debug_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref().filter(|_| self.debug_info)
+    }
+
+    /// Section the output runtime goes in. With debug info it gets a section
+    /// of its own, so nothing follows the program code in `.text` and the
+    /// assembler ends the line table at `.Lprogram_end`.
+    pub fn runtime_section(&self) -> &'static str {
+        if self.debug_map().is_some() {
+            ".text.runtime,\"ax\",%progbits"
+        } else {
+            ".text"
+        }
+    }
+
+    /// `.loc` and annotation for the code at the start of the program.
+    /// `comment` starts a comment in the target's assembly.
+    pub fn start_marker(&self, comment: &str) -> String {
+        let mut out = String::new();
+        if let Some(source_map) = self.debug_map() {
+            out.push_str(&source_map.start_loc());
+        }
+        if let Some(fragment) = self
+            .source_map
+            .as_ref()
+            .filter(|_| self.annotate)
+            .and_then(SourceMap::start_fragment)
+        {
+            out.push_str(&format!("    {} bf: {}\n", comment, fragment));
+        }
+        out
+    }
+
+    /// `.loc` and annotation for the start of the code for `command`
+    pub fn command_marker(&self, command: &Command, comment: &str) -> String {
+        let mut out = match command.loop_id() {
+            Some(id) => self.loop_marker(id, LoopPart::Open, comment),
+            None => String::new(),
+        };
+        if self.annotate {
+            out.push_str(&format!(
+                "    {} ir: {}{}\n",
+                comment,
+                pretty_command(command),
+                self.count_note(command.count())
+            ));
+        }
+        out
+    }
+
+    /// `.loc` and annotation for a part of loop `id`
+    pub fn loop_marker(&self, id: usize, part: LoopPart, comment: &str) -> String {
+        let mut out = String::new();
+        if let Some(source_map) = self.debug_map() {
+            out.push_str(&source_map.loop_loc(id, part));
+        }
+        if let Some(fragment) = self
+            .source_map
+            .as_ref()
+            .filter(|_| self.annotate)
+            .and_then(|source_map| source_map.loop_fragment(id, part))
+        {
+            out.push_str(&format!("    {} bf: {}\n", comment, fragment));
+        }
+        out
+    }
+
+    /// `.loc` and annotation for the jump back to the start of a `Loop`,
+    /// whose closing bracket ran `end_count` times
+    pub fn loop_end_marker(&self, id: usize, end_count: usize, comment: &str) -> String {
+        let mut out = self.loop_marker(id, LoopPart::Close, comment);
+        if self.annotate {
+            out.push_str(&format!(
+                "    {} ir: ]{}\n",
+                comment,
+                self.count_note(end_count)
+            ));
+        }
+        out
+    }
+
+    fn count_note(&self, count: usize) -> String {
+        match count {
+            _ if !self.counts => String::new(),
+            1 => String::from(", executed 1 time"),
+            _ => format!(", executed {} times", count),
+        }
+    }
+}
+
+/// Registers that hold cached cells, by their byte, 32-bit and 64-bit names.
+/// They're all caller saved, and free in straight-line code.
+const CACHE_REGS: [(&str, &str, &str); 8] = [
+    ("%cl", "%ecx", "%rcx"),
+    ("%dl", "%edx", "%rdx"),
+    ("%sil", "%esi", "%rsi"),
+    ("%dil", "%edi", "%rdi"),
+    ("%r8b", "%r8d", "%r8"),
+    ("%r9b", "%r9d", "%r9"),
+    ("%r10b", "%r10d", "%r10"),
+    ("%r11b", "%r11d", "%r11"),
+];
+
+struct CachedCell {
+    /// Relative to the current pointer
+    offset: isize,
+    /// Index into `CACHE_REGS`
+    reg: usize,
+    /// Whether the register holds a value the tape doesn't have yet
+    dirty: bool,
+}
+
+/// Tape cells held in registers within straight-line code. A cell is loaded
+/// on first use, and written back when it's evicted or the cache is spilled,
+/// which happens before anything that jumps, calls out or reads the tape
+/// through memory. Moving the pointer only renumbers the cached offsets.
+struct CellCache<'a> {
+    ptr_reg: &'a str,
+    /// Least recently used first
+    cells: Vec<CachedCell>,
+}
+
+impl<'a> CellCache<'a> {
+    fn new(ptr_reg: &'a str) -> CellCache<'a> {
+        CellCache {
+            ptr_reg,
+            cells: vec![],
+        }
+    }
+
+    fn write_back(&self, out_string: &mut String, cell: &CachedCell) {
+        if cell.dirty {
+            out_string.push_str(&format!(
+                "    movb {}, {}({})\n",
+                CACHE_REGS[cell.reg].0, cell.offset, self.ptr_reg
+            ));
+        }
+    }
+
+    /// Register holding the cell at `offset`, loading it unless `load` is
+    /// false because the caller overwrites it
+    fn get(&mut self, out_string: &mut String, offset: isize, load: bool) -> usize {
+        if let Some(idx) = self.cells.iter().position(|cell| cell.offset == offset) {
+            let cell = self.cells.remove(idx);
+            let reg = cell.reg;
+            self.cells.push(cell);
+            return reg;
+        }
+
+        let reg = if self.cells.len() == CACHE_REGS.len() {
+            let evicted = self.cells.remove(0);
+            self.write_back(out_string, &evicted);
+            evicted.reg
+        } else {
+            (0..CACHE_REGS.len())
+                .find(|&reg| self.cells.iter().all(|cell| cell.reg != reg))
+                .unwrap()
+        };
+        if load {
+            out_string.push_str(&format!(
+                "    movb {}({}), {}\n",
+                offset, self.ptr_reg, CACHE_REGS[reg].0
+            ));
+        }
+        self.cells.push(CachedCell {
+            offset,
+            reg,
+            dirty: false,
+        });
+        reg
+    }
+
+    /// Register for reading the cell at `offset`
+    fn read(&mut self, out_string: &mut String, offset: isize) -> usize {
+        self.get(out_string, offset, true)
+    }
+
+    /// Register for updating the cell at `offset` based on its value
+    fn modify(&mut self, out_string: &mut String, offset: isize) -> usize {
+        let reg = self.get(out_string, offset, true);
+        self.cells.last_mut().unwrap().dirty = true;
+        reg
+    }
+
+    /// Register for replacing the cell at `offset`
+    fn overwrite(&mut self, out_string: &mut String, offset: isize) -> usize {
+        let reg = self.get(out_string, offset, false);
+        self.cells.last_mut().unwrap().dirty = true;
+        reg
+    }
+
+    /// Accounts for the pointer moving by `amount`
+    fn shift(&mut self, amount: isize) {
+        for cell in &mut self.cells {
+            cell.offset -= amount;
+        }
+    }
+
+    /// Writes back every changed cell and empties the cache
+    fn spill(&mut self, out_string: &mut String) {
+        for cell in &self.cells {
+            self.write_back(out_string, cell);
+        }
+        self.cells.clear();
+    }
+}
+
+#[derive(Clone, Copy)]
+enum VectorIsa {
+    Avx2,
+    Sse2,
+}
+
+impl VectorIsa {
+    /// Bytes compared per iteration of a scan
+    fn width(self) -> usize {
+        match self {
+            VectorIsa::Avx2 => 32,
+            VectorIsa::Sse2 => 16,
+        }
+    }
+}
+
+/// OR mask for a scan window of `width` cells. Cells the scan steps on are 0x00
+/// and all others 0xFF, so only the former can compare equal to zero. Left
+/// scans load the window ending at the current cell, so their mask is mirrored.
+pub(crate) fn scan_mask(direction: &Direction, skip_amount: usize, width: usize) -> Vec<u8> {
+    let chunk = width - width % skip_amount;
+    (0..width)
+        .map(|i| {
+            let distance = match direction {
+                Direction::Left => width - 1 - i,
+                Direction::Right => i,
+            };
+            if distance % skip_amount == 0 && distance < chunk {
+                0x00
+            } else {
+                0xFF
+            }
+        })
+        .collect()
+}
+
+fn append_assembly_header(
+    out_string: &mut String,
+    ptr_reg: &str,
+    full_byte_reg: &str,
+    freestanding: bool,
+) {
+    if freestanding {
+        // The tape lives in `.bss`, which the kernel zeroes
+        out_string.push_str(&format!(
+            r#"
+.section .bss
+
+.lcomm tape, {}
+
+.section .text
+
+.globl _start
+
+_start:
+    leaq  tape+{}(%rip), {} # Point past the padding to the middle of the tape
+
+    # Begin program code
+"#,
+            INIT_TAPE_SIZE + 2 * TAPE_PADDING,
+            TAPE_PADDING + INIT_POINTER_LOC,
+            ptr_reg
+        ));
+        return;
+    }
+
     out_string.push_str(&format!(
         r#"
-.section .data
-
-.align 32
-mask_skip2:
-  .byte 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF
-mask_skip2_reverse:
-  .byte 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00
-mask_skip4:
-  .byte 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF
-mask_skip4_reverse:
-  .byte 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x00
-
 .section .text
 
 .globl main
@@ -153,40 +692,207 @@ main:
 
     pushq %rax            # Save tape address to the stack
     movq  %rax,      {} # Move tape address into callee saved register
-    addq  ${}, {} # Move the pointer to the middle of the tape
+    addq  ${}, {} # Move the pointer past the padding to the middle of the tape
 
     # Begin program code
 "#,
-        ptr_reg, full_byte_reg, INIT_TAPE_SIZE, ptr_reg, INIT_POINTER_LOC, ptr_reg
+        ptr_reg,
+        full_byte_reg,
+        INIT_TAPE_SIZE + 2 * TAPE_PADDING,
+        ptr_reg,
+        TAPE_PADDING + INIT_POINTER_LOC,
+        ptr_reg
+    ));
+}
+
+/// Sets `has_avx2` if the CPU supports AVX2 and the OS saves the YMM registers
+fn append_cpu_detection(out_string: &mut String) {
+    out_string.push_str(
+        r#"    # Detect AVX2 for scans
+    .pushsection .data
+has_avx2:
+    .byte 0
+    .popsection
+    pushq %rbx
+    movl  $1, %eax
+    cpuid
+    andl  $0x18000000, %ecx # OSXSAVE and AVX
+    cmpl  $0x18000000, %ecx
+    jne   cpu_detection_end
+    xorl  %ecx, %ecx
+    xgetbv
+    andl  $6, %eax          # XMM and YMM state
+    cmpl  $6, %eax
+    jne   cpu_detection_end
+    movl  $7, %eax
+    xorl  %ecx, %ecx
+    cpuid
+    shrl  $5, %ebx          # AVX2
+    andb  $1, %bl
+    movb  %bl, has_avx2(%rip)
+cpu_detection_end:
+    popq  %rbx
+
+"#,
+    );
+}
+
+/// Escapes bytes for an `.ascii` directive
+pub(crate) fn escape_ascii(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => escaped.push_str(&format!("\\{}", byte as char)),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    escaped
+}
+
+/// Routines for buffered output. `output_byte` appends the byte in `%dil`,
+/// `output_bytes` appends `%rdx` bytes starting at `%rsi` and `flush_output`
+/// writes out the buffer. They only clobber caller saved registers.
+fn append_output_runtime(out_string: &mut String, freestanding: bool, section: &str) {
+    let write = if freestanding {
+        "movl  $1, %eax # write\n    syscall"
+    } else {
+        "call  write"
+    };
+    out_string.push_str(&format!(
+        r#"
+.section .bss
+
+.lcomm output_buffer, {size}
+.lcomm output_len, 8
+
+.section {text}
+
+output_byte:
+    movq  output_len(%rip), %rax
+    leaq  output_buffer(%rip), %rdx
+    movb  %dil, (%rdx, %rax)
+    incq  %rax
+    movq  %rax, output_len(%rip)
+    cmpq  ${size}, %rax
+    je    flush_output
+    ret
+
+output_bytes:
+    movq  output_len(%rip), %rax
+    leaq  output_buffer(%rip), %rdi
+output_bytes_loop:
+    testq %rdx, %rdx
+    jz    output_bytes_end
+    movb  (%rsi), %cl
+    movb  %cl, (%rdi, %rax)
+    incq  %rsi
+    decq  %rdx
+    incq  %rax
+    cmpq  ${size}, %rax
+    jne   output_bytes_loop
+    movq  %rax, output_len(%rip)
+    pushq %rsi
+    pushq %rdx
+    call  flush_output
+    popq  %rdx
+    popq  %rsi
+    leaq  output_buffer(%rip), %rdi
+    xorl  %eax, %eax
+    jmp   output_bytes_loop
+output_bytes_end:
+    movq  %rax, output_len(%rip)
+    ret
+
+flush_output:
+    pushq %rbp
+    movq  %rsp, %rbp
+    pushq %rbx
+    pushq %r14
+    andq  $-16, %rsp
+    leaq  output_buffer(%rip), %rbx
+    movq  output_len(%rip), %r14
+flush_output_loop:
+    testq %r14, %r14
+    jle   flush_output_end
+    movl  $1, %edi
+    movq  %rbx, %rsi
+    movq  %r14, %rdx
+    {write}
+    testq %rax, %rax
+    jle   flush_output_end # Output is lost on write errors
+    addq  %rax, %rbx
+    subq  %rax, %r14
+    jmp   flush_output_loop
+flush_output_end:
+    movq  $0, output_len(%rip)
+    movq  -16(%rbp), %r14
+    movq  -8(%rbp), %rbx
+    movq  %rbp, %rsp
+    popq  %rbp
+    ret
+"#,
+        text = section,
+        size = OUTPUT_BUFFER_SIZE,
+        write = write
     ));
 }
 
-fn append_assembly_footer(out_string: &mut String, ptr_reg: &str, full_byte_reg: &str) {
+fn append_assembly_footer(
+    out_string: &mut String,
+    ptr_reg: &str,
+    full_byte_reg: &str,
+    freestanding: bool,
+    exit_code: ExitCode,
+) {
+    let exit_status = match exit_code {
+        ExitCode::Zero => String::from("xorl  %eax, %eax"),
+        ExitCode::Cell => format!("movzbl ({}), %eax", ptr_reg),
+    };
+
+    if freestanding {
+        out_string.push_str(&format!(
+            r#"    # At bottom of _start
+    call flush_output
+
+    {}
+    movl %eax, %edi
+    movl $231, %eax # exit_group
+    syscall
+"#,
+            exit_status
+        ));
+        return;
+    }
+
     out_string.push_str(&format!(
         r#"    # At bottom of main
-    
-    popq %rax
-    popq {}
-    popq {}
-    popq %rbp
+    call flush_output
+
+    {}
+    movl  %eax, {}d # Keep the exit status across the call to free
+    popq  %rdi      # Tape address
+    call  free
+    movl  {}d, %eax
+
+    popq  {}
+    popq  {}
+    popq  %rbp
 
     # Return to _start
-    movq $0, %rax
     ret
 "#,
-        full_byte_reg, ptr_reg
+        exit_status, full_byte_reg, full_byte_reg, full_byte_reg, ptr_reg
     ));
 }
 
+/// Compiles the program to `artifact` and writes it to `dest`
 pub fn compile(
     commands: &[Command],
-    src_filepath: &str,
-    dest_filename: &str,
-    output_binary_file: bool,
-    output_object_file: bool,
-) {
-    use std::io::Write;
-
+    artifact: Artifact,
+    dest: &Destination,
+    options: &CompileOptions,
+) -> Result<(), String> {
     fn append_pointer_op(
         out_string: &mut String,
         amount: usize,
@@ -206,58 +912,279 @@ pub fn compile(
 
     fn append_data_op(
         out_string: &mut String,
+        cache: &mut CellCache,
         offset: isize,
         amount: u8,
         single_op: &str,
         multi_op: &str,
-        reg: &str,
-        byte_reg: &str,
         comment: &str,
     ) {
-        let offset_str = if offset == 0 {
-            String::from("")
-        } else {
-            format!("{}", offset)
-        };
         out_string.push_str(&format!("    # {}\n", comment));
-        out_string.push_str(&format!("    movb {}({}), {}\n", offset_str, reg, byte_reg));
+        let reg = CACHE_REGS[cache.modify(out_string, offset)].0;
         if amount == 1 {
-            out_string.push_str(&format!("    {} {}\n", single_op, byte_reg));
+            out_string.push_str(&format!("    {} {}\n", single_op, reg));
+        } else {
+            out_string.push_str(&format!("    {} ${}, {}\n", multi_op, amount, reg));
+        }
+        out_string.push('\n');
+    }
+
+    /// Adds or subtracts `multiplier` times the cell at `src_offset` to the
+    /// cell at `dest_offset`
+    fn append_offset_data_op(
+        out_string: &mut String,
+        cache: &mut CellCache,
+        dest_offset: isize,
+        src_offset: isize,
+        multiplier: usize,
+        add: bool,
+    ) {
+        // Only the low byte matters, and subtracting `256 - m` times the
+        // source is the same as adding `m` times it
+        let mut multiplier = multiplier % 256;
+        let mut add = add;
+        if multiplier > 128 {
+            multiplier = 256 - multiplier;
+            add = !add;
+        }
+        if multiplier == 0 {
+            return;
+        }
+
+        let (src, src32, src64) = CACHE_REGS[cache.read(out_string, src_offset)];
+        let product = match multiplier {
+            1 => src,
+            2 | 3 | 5 | 9 => {
+                out_string.push_str(&format!(
+                    "    leal ({}, {}, {}), %eax\n",
+                    src64,
+                    src64,
+                    multiplier - 1
+                ));
+                "%al"
+            }
+            _ if multiplier.is_power_of_two() => {
+                out_string.push_str(&format!("    movl {}, %eax\n", src32));
+                out_string.push_str(&format!(
+                    "    shll ${}, %eax\n",
+                    multiplier.trailing_zeros()
+                ));
+                "%al"
+            }
+            _ => {
+                out_string.push_str(&format!("    imull ${}, {}, %eax\n", multiplier, src32));
+                "%al"
+            }
+        };
+        let dest = CACHE_REGS[cache.modify(out_string, dest_offset)].0;
+        let op = if add { "addb" } else { "subb" };
+        out_string.push_str(&format!("    {} {}, {}\n", op, product, dest));
+    }
+
+    fn append_scan(
+        out_string: &mut String,
+        id: usize,
+        direction: &Direction,
+        skip_amount: usize,
+        offset: isize,
+        ptr_reg: &str,
+        simd: Simd,
+    ) {
+        let step = match direction {
+            Direction::Left => "<",
+            Direction::Right => ">",
+        };
+        if offset == 0 {
+            out_string.push_str(&format!("    # [{}]\n", step.repeat(skip_amount)));
         } else {
-            out_string.push_str(&format!("    {} ${}, {}\n", multi_op, amount, byte_reg));
+            out_string.push_str(&format!(
+                "    # [{}] testing cell {}\n",
+                step.repeat(skip_amount),
+                offset
+            ));
+        }
+
+        let scan = |out_string: &mut String, label: &str, isa: Option<VectorIsa>| match isa {
+            Some(isa) if skip_amount <= isa.width() => append_vector_scan(
+                out_string,
+                label,
+                direction,
+                skip_amount,
+                offset,
+                ptr_reg,
+                isa,
+            ),
+            _ => append_scalar_scan(out_string, label, direction, skip_amount, offset, ptr_reg),
+        };
+
+        match simd {
+            Simd::Avx2 => scan(out_string, &format!("vector{}", id), Some(VectorIsa::Avx2)),
+            Simd::Sse2 => scan(out_string, &format!("vector{}", id), Some(VectorIsa::Sse2)),
+            Simd::None => scan(out_string, &format!("vector{}", id), None),
+            Simd::Neon | Simd::Rvv => unreachable!("{:?} scans on x86-64", simd),
+            Simd::Auto => {
+                out_string.push_str("    cmpb $0, has_avx2(%rip)\n");
+                out_string.push_str(&format!("    je   vector{}_sse2\n", id));
+                scan(out_string, &format!("vector{}", id), Some(VectorIsa::Avx2));
+                out_string.push_str(&format!("    jmp  vector{}_done\n", id));
+                out_string.push_str(&format!("vector{}_sse2:\n", id));
+                scan(
+                    out_string,
+                    &format!("vector{}_sse2", id),
+                    Some(VectorIsa::Sse2),
+                );
+                out_string.push_str(&format!("vector{}_done:\n", id));
+            }
         }
-        out_string.push_str(&format!("    movb {}, {}({})\n", byte_reg, offset_str, reg));
         out_string.push('\n');
     }
 
-    fn compile_rec(out_string: &mut String, commands: &[Command], ptr_reg: &str, byte_reg: &str) {
-        for command in commands {
+    fn append_scalar_scan(
+        out_string: &mut String,
+        label: &str,
+        direction: &Direction,
+        skip_amount: usize,
+        offset: isize,
+        ptr_reg: &str,
+    ) {
+        let add_op = match direction {
+            Direction::Left => "subq",
+            Direction::Right => "addq",
+        };
+        out_string.push_str(&format!("{}_loop_start:\n", label));
+        out_string.push_str(&format!("    cmpb $0, {}({})\n", offset, ptr_reg));
+        out_string.push_str(&format!("    je   {}_found_zero\n", label));
+        out_string.push_str(&format!("    {} ${}, {}\n", add_op, skip_amount, ptr_reg));
+        out_string.push_str(&format!("    jmp  {}_loop_start\n", label));
+        out_string.push_str(&format!("{}_found_zero:\n", label));
+    }
+
+    fn append_vector_scan(
+        out_string: &mut String,
+        label: &str,
+        direction: &Direction,
+        skip_amount: usize,
+        offset: isize,
+        ptr_reg: &str,
+        isa: VectorIsa,
+    ) {
+        let width = isa.width();
+        let add_op = match direction {
+            Direction::Left => "subq",
+            Direction::Right => "addq",
+        };
+        // Each iteration tests the cells on the stride within one vector, so
+        // the window advances by the largest multiple of the stride that fits
+        let chunk = width - width % skip_amount;
+        if skip_amount > 1 {
+            let mask = scan_mask(direction, skip_amount, width);
+            let mask_bytes: Vec<String> = mask.iter().map(|b| format!("0x{:02X}", b)).collect();
+            out_string.push_str("    .pushsection .rodata\n");
+            out_string.push_str(&format!("{}_mask:\n", label));
+            out_string.push_str(&format!("    .byte {}\n", mask_bytes.join(", ")));
+            out_string.push_str("    .popsection\n");
+        }
+        match direction {
+            Direction::Right => out_string.push_str("    xorq %rsi, %rsi\n"),
+            Direction::Left => out_string.push_str(&format!("    movq $-{}, %rsi\n", width - 1)),
+        }
+        match isa {
+            VectorIsa::Avx2 => {
+                if skip_amount > 1 {
+                    out_string.push_str(&format!("    vmovdqu {}_mask(%rip), %ymm3\n", label));
+                }
+                out_string.push_str("    vpxor %ymm1, %ymm1, %ymm1\n");
+                out_string.push_str(&format!("{}_loop_start:\n", label));
+                out_string.push_str(&format!(
+                    "    vmovdqu {}({}, %rsi), %ymm0\n",
+                    offset, ptr_reg
+                ));
+                if skip_amount > 1 {
+                    out_string.push_str("    vpor %ymm3, %ymm0, %ymm0\n");
+                }
+                out_string.push_str("    vpcmpeqb %ymm1, %ymm0, %ymm2\n");
+                out_string.push_str("    vpmovmskb %ymm2, %eax\n");
+            }
+            VectorIsa::Sse2 => {
+                if skip_amount > 1 {
+                    out_string.push_str(&format!("    movdqu {}_mask(%rip), %xmm3\n", label));
+                }
+                out_string.push_str("    pxor %xmm1, %xmm1\n");
+                out_string.push_str(&format!("{}_loop_start:\n", label));
+                out_string.push_str(&format!(
+                    "    movdqu {}({}, %rsi), %xmm0\n",
+                    offset, ptr_reg
+                ));
+                if skip_amount > 1 {
+                    out_string.push_str("    por %xmm3, %xmm0\n");
+                }
+                out_string.push_str("    pcmpeqb %xmm1, %xmm0\n");
+                out_string.push_str("    pmovmskb %xmm0, %eax\n");
+            }
+        }
+        out_string.push_str("    testl %eax, %eax\n");
+        out_string.push_str(&format!("    jnz {}_found_zero\n", label));
+        out_string.push_str(&format!("    {} ${}, %rsi\n", add_op, chunk));
+        out_string.push_str(&format!("    jmp {}_loop_start\n", label));
+        out_string.push_str(&format!("{}_found_zero:\n", label));
+        match direction {
+            // The zero nearest to the start of the scan
+            Direction::Right => out_string.push_str("    bsfl %eax, %eax\n"),
+            Direction::Left => out_string.push_str("    bsrl %eax, %eax\n"),
+        }
+        out_string.push_str("    addq %rax, %rsi\n");
+        out_string.push_str(&format!("    addq %rsi, {}\n", ptr_reg));
+    }
+
+    fn compile_rec(
+        out_string: &mut String,
+        commands: &[Command],
+        ptr_reg: &str,
+        byte_reg: &str,
+        options: &CompileOptions,
+    ) {
+        let mut cache = CellCache::new(ptr_reg);
+        for run in output_runs(commands) {
+            let command = &run[0];
+            // Everything else branches, calls out or reads the tape directly
+            if !matches!(
+                command,
+                Command::IncPointer { .. }
+                    | Command::DecPointer { .. }
+                    | Command::IncData { .. }
+                    | Command::DecData { .. }
+                    | Command::SetData { .. }
+                    | Command::AddOffsetData { .. }
+                    | Command::SubOffsetData { .. }
+            ) {
+                cache.spill(out_string);
+            }
+            out_string.push_str(&options.command_marker(command, "#"));
+
             match command {
                 Command::IncPointer { amount, .. } => {
                     append_pointer_op(out_string, *amount, "incq", "addq", ptr_reg, ">");
+                    cache.shift(*amount as isize);
                 }
                 Command::DecPointer { amount, .. } => {
                     append_pointer_op(out_string, *amount, "decq", "subq", ptr_reg, "<");
+                    cache.shift(-(*amount as isize));
                 }
                 Command::IncData { offset, amount, .. } => {
                     append_data_op(
-                        out_string, *offset, *amount, "incb", "addb", ptr_reg, byte_reg, "+",
+                        out_string, &mut cache, *offset, *amount, "incb", "addb", "+",
                     );
                 }
                 Command::DecData { offset, amount, .. } => {
                     append_data_op(
-                        out_string, *offset, *amount, "decb", "subb", ptr_reg, byte_reg, "-",
+                        out_string, &mut cache, *offset, *amount, "decb", "subb", "-",
                     );
                 }
                 Command::SetData { offset, value, .. } => {
-                    let offset_str = if *offset == 0 {
-                        String::from("")
-                    } else {
-                        format!("{}", offset)
-                    };
+                    let reg = CACHE_REGS[cache.overwrite(out_string, *offset)].0;
                     out_string.push_str(&format!(
                         "{:24} # ={}\n",
-                        format!("    movb ${}, {}({})", value, offset_str, ptr_reg),
+                        format!("    movb ${}, {}", value, reg),
                         value
                     ));
                 }
@@ -265,100 +1192,19 @@ pub fn compile(
                     id,
                     direction,
                     skip_amount,
+                    offset,
                     ..
                 } => {
-                    match skip_amount {
-                        1 | 2 | 4 => match direction {
-                            Direction::Right => {
-                                out_string
-                                    .push_str(&format!("    # [{}]\n", ">".repeat(*skip_amount)));
-                                if *skip_amount == 2 {
-                                    out_string.push_str("    vmovdqa mask_skip2(%rip), %ymm3\n")
-                                } else if *skip_amount == 4 {
-                                    out_string.push_str("    vmovdqa mask_skip4(%rip), %ymm3\n")
-                                }
-                                out_string.push_str("    xorq %rsi, %rsi\n");
-                                out_string.push_str("    vpxor %ymm1, %ymm1, %ymm1\n");
-                                out_string.push_str(&format!("vector{}_loop_start:\n", id));
-                                out_string
-                                    .push_str(&format!("    vmovdqu ({}, %rsi), %ymm0\n", ptr_reg));
-                                if *skip_amount == 2 || *skip_amount == 4 {
-                                    out_string.push_str("    vpor %ymm3, %ymm0, %ymm0\n");
-                                }
-                                out_string.push_str("    vpcmpeqb %ymm1, %ymm0, %ymm2\n");
-                                out_string.push_str("    vpmovmskb %ymm2, %eax\n");
-                                out_string.push_str("    testl %eax, %eax\n");
-                                out_string.push_str(&format!("    jnz vector{}_found_zero\n", id));
-                                out_string.push_str("    addq $32, %rsi\n");
-                                out_string.push_str(&format!("    jmp vector{}_loop_start\n", id));
-                                out_string.push_str(&format!("vector{}_found_zero:\n", id));
-                                out_string.push_str("    bsfl %eax, %eax\n");
-                                out_string.push_str("    addq %rax, %rsi\n");
-                                out_string.push_str(&format!("    addq %rsi, {}\n", ptr_reg));
-                                out_string.push('\n');
-                            }
-                            Direction::Left => {
-                                out_string
-                                    .push_str(&format!("    # [{}]\n", "<".repeat(*skip_amount)));
-                                if *skip_amount == 2 {
-                                    out_string
-                                        .push_str("    vmovdqa mask_skip2_reverse(%rip), %ymm3\n")
-                                } else if *skip_amount == 4 {
-                                    out_string
-                                        .push_str("    vmovdqa mask_skip4_reverse(%rip), %ymm3\n")
-                                }
-                                out_string.push_str("    movq $-31, %rsi\n");
-                                out_string.push_str("    vpxor %ymm1, %ymm1, %ymm1\n");
-                                out_string.push_str(&format!("vector{}_loop_start:\n", id));
-                                out_string
-                                    .push_str(&format!("    vmovdqu ({}, %rsi), %ymm0\n", ptr_reg));
-                                if *skip_amount == 2 || *skip_amount == 4 {
-                                    out_string.push_str("    vpor %ymm3, %ymm0, %ymm0\n");
-                                }
-                                out_string.push_str("    vpcmpeqb %ymm1, %ymm0, %ymm2\n");
-                                out_string.push_str("    vpmovmskb %ymm2, %eax\n");
-                                out_string.push_str("    testl %eax, %eax\n");
-                                out_string.push_str(&format!("    jnz vector{}_found_zero\n", id));
-                                out_string.push_str("    subq $32, %rsi\n");
-                                out_string.push_str(&format!("    jmp vector{}_loop_start\n", id));
-                                out_string.push_str(&format!("vector{}_found_zero:\n", id));
-                                out_string.push_str("    bsrl %eax, %eax\n");
-                                out_string.push_str("    addq %rax, %rsi\n");
-                                out_string.push_str(&format!("    addq %rsi, {}\n", ptr_reg));
-                                out_string.push('\n');
-                            }
-                        },
-                        _ => {
-                            // Run this as a normal loop
-                            let loop_cmd;
-                            match direction {
-                                Direction::Right => {
-                                    loop_cmd = Command::Loop {
-                                        id: *id,
-                                        body: vec![Command::IncPointer {
-                                            amount: *skip_amount,
-                                            count: 0,
-                                        }],
-                                        start_count: 0,
-                                        end_count: 0,
-                                    }
-                                }
-                                Direction::Left => {
-                                    loop_cmd = Command::Loop {
-                                        id: *id,
-                                        body: vec![Command::DecPointer {
-                                            amount: *skip_amount,
-                                            count: 0,
-                                        }],
-                                        start_count: 0,
-                                        end_count: 0,
-                                    }
-                                }
-                            }
-                            let new_cmds = vec![loop_cmd];
-                            compile_rec(out_string, &new_cmds, ptr_reg, byte_reg)
-                        }
-                    }
+                    append_scan(
+                        out_string,
+                        *id,
+                        direction,
+                        *skip_amount,
+                        *offset,
+                        ptr_reg,
+                        options.simd,
+                    );
+                    out_string.push_str(&options.loop_marker(*id, LoopPart::After, "#"));
                 }
                 Command::AddOffsetData {
                     src_offset,
@@ -367,25 +1213,15 @@ pub fn compile(
                     inverted,
                     ..
                 } => {
-                    out_string.push_str(&format!(
-                        "    movb {}({}), {}\n",
-                        src_offset, ptr_reg, byte_reg
-                    ));
-                    if *inverted {
-                        out_string.push_str(&format!("    notb {}\n", byte_reg));
-                        out_string.push_str(&format!("    incb {}\n", byte_reg));
-                    }
-                    out_string.push_str(&format!("    movb ${}, %al\n", multiplier));
-                    out_string.push_str(&format!("    mulb {}\n", byte_reg));
-                    out_string.push_str(&format!(
-                        "    movb {}({}), {}\n",
-                        dest_offset, ptr_reg, byte_reg
-                    ));
-                    out_string.push_str(&format!("    addb %al, {}\n", byte_reg));
-                    out_string.push_str(&format!(
-                        "    movb {}, {}({})\n",
-                        byte_reg, dest_offset, ptr_reg
-                    ));
+                    // Adding the inverted source is the same as subtracting it
+                    append_offset_data_op(
+                        out_string,
+                        &mut cache,
+                        *dest_offset,
+                        *src_offset,
+                        *multiplier,
+                        !*inverted,
+                    );
                 }
                 Command::SubOffsetData {
                     src_offset,
@@ -394,62 +1230,145 @@ pub fn compile(
                     inverted,
                     ..
                 } => {
-                    out_string.push_str(&format!(
-                        "    movb {}({}), {}\n",
-                        src_offset, ptr_reg, byte_reg
-                    ));
-                    if *inverted {
-                        out_string.push_str(&format!("    notb {}\n", byte_reg));
-                        out_string.push_str(&format!("    incb {}\n", byte_reg));
+                    append_offset_data_op(
+                        out_string,
+                        &mut cache,
+                        *dest_offset,
+                        *src_offset,
+                        *multiplier,
+                        *inverted,
+                    );
+                }
+                Command::Output {
+                    out_type: OutputType::Const(val),
+                    ..
+                } => {
+                    let bytes = run_bytes(run);
+                    if bytes.len() == 1 {
+                        out_string.push_str("    # .\n");
+                        out_string.push_str(&format!("    movl ${}, %edi\n", val));
+                        out_string.push_str("    call output_byte\n");
+                    } else {
+                        out_string.push_str(&format!("    # . x{}\n", bytes.len()));
+                        out_string.push_str("    .pushsection .rodata\n");
+                        out_string.push_str("2:\n");
+                        out_string.push_str(&format!("    .ascii \"{}\"\n", escape_ascii(&bytes)));
+                        out_string.push_str("    .popsection\n");
+                        out_string.push_str("    leaq 2b(%rip), %rsi\n");
+                        out_string.push_str(&format!("    movl ${}, %edx\n", bytes.len()));
+                        out_string.push_str("    call output_bytes\n");
                     }
-                    out_string.push_str(&format!("    movb ${}, %al\n", multiplier));
-                    out_string.push_str(&format!("    mulb {}\n", byte_reg));
-                    out_string.push_str(&format!(
-                        "    movb {}({}), {}\n",
-                        dest_offset, ptr_reg, byte_reg
-                    ));
-                    out_string.push_str(&format!("    subb %al, {}\n", byte_reg));
-                    out_string.push_str(&format!(
-                        "    movb {}, {}({})\n",
-                        byte_reg, dest_offset, ptr_reg
-                    ));
+                    out_string.push('\n');
                 }
-                Command::Output { out_type, .. } => {
+                Command::Output {
+                    out_type: OutputType::Cell { offset },
+                    ..
+                } => {
                     out_string.push_str("    # .\n");
-                    match out_type {
-                        OutputType::Const(val) => {
-                            out_string.push_str(&format!("    movl ${}, %edi\n", val))
-                        }
-                        OutputType::Cell { offset } => out_string
-                            .push_str(&format!("    movzbl {}({}), %edi\n", offset, ptr_reg)),
-                    }
-                    out_string.push_str("    call putchar\n");
+                    out_string.push_str(&format!("    movzbl {}({}), %edi\n", offset, ptr_reg));
+                    out_string.push_str("    call output_byte\n");
+                    out_string.push('\n');
+                }
+                Command::Input { offset, .. } if options.freestanding => {
+                    out_string.push_str("    # ,\n");
+                    out_string.push_str("    call flush_output\n");
+                    out_string.push_str(&format!("    leaq {}({}), %rsi\n", offset, ptr_reg));
+                    out_string.push_str("    xorl %edi, %edi\n");
+                    out_string.push_str("    movl $1, %edx\n");
+                    out_string.push_str("    xorl %eax, %eax # read\n");
+                    out_string.push_str("    syscall\n");
+                    // Store -1 on end of input or error, like getchar
+                    out_string.push_str("    testq %rax, %rax\n");
+                    out_string.push_str("    jg   1f\n");
+                    out_string.push_str(&format!("    movb $255, {}({})\n", offset, ptr_reg));
+                    out_string.push_str("1:\n");
                     out_string.push('\n');
                 }
                 Command::Input { offset, .. } => {
                     //append_io_syscall(out_string, 0, 0, *id, ptr_reg, ",");
                     out_string.push_str("    # ,\n");
+                    out_string.push_str("    call flush_output\n");
                     out_string.push_str("    call getchar\n");
                     out_string.push_str(&format!("    movb %al, {}({})\n", offset, ptr_reg));
                     out_string.push('\n');
                 }
-                Command::Loop { body, id, .. } => {
+                Command::InfiniteLoop { id, divisor, .. } => {
+                    let msg = format!("infinite loop detected at loop {}", id);
+                    out_string.push_str("    # [!]\n");
+                    out_string.push_str(&format!("    movb ({}), {}\n", ptr_reg, byte_reg));
+                    out_string.push_str(&format!("    cmpb $0,     {}\n", byte_reg));
+                    out_string.push_str(&format!("    je   infinite{}_end\n", id));
+                    if let Some(mask) = divisor_mask(*divisor) {
+                        out_string.push_str(&format!("    testb ${}, {}\n", mask, byte_reg));
+                        out_string.push_str(&format!("    jnz  infinite{}_trap\n", id));
+                        out_string.push_str(&format!("    movb $0, ({})\n", ptr_reg));
+                        out_string.push_str(&format!("    jmp  infinite{}_end\n", id));
+                    }
+                    out_string.push_str(&format!("infinite{}_trap:\n", id));
+                    out_string.push_str("    call flush_output\n");
+                    out_string.push_str("    movl $2, %edi\n");
+                    out_string.push_str(&format!("    leaq infinite{}_msg(%rip), %rsi\n", id));
+                    // Message length including the newline
+                    out_string.push_str(&format!("    movl ${}, %edx\n", msg.len() + 1));
+                    if options.freestanding {
+                        out_string.push_str("    movl $1, %eax # write\n");
+                        out_string.push_str("    syscall\n");
+                        out_string.push_str("    movl $1, %edi\n");
+                        out_string.push_str("    movl $231, %eax # exit_group\n");
+                        out_string.push_str("    syscall\n");
+                    } else {
+                        out_string.push_str("    call write\n");
+                        out_string.push_str("    movl $1, %edi\n");
+                        out_string.push_str("    call exit\n");
+                    }
+                    out_string.push_str("    .pushsection .rodata\n");
+                    out_string.push_str(&format!("infinite{}_msg:\n", id));
+                    out_string.push_str(&format!("    .ascii \"{}\\n\"\n", msg));
+                    out_string.push_str("    .popsection\n");
+                    out_string.push_str(&format!("infinite{}_end:\n", id));
+                    out_string.push_str(&options.loop_marker(*id, LoopPart::After, "#"));
+                    out_string.push('\n');
+                }
+                Command::If { body, id, .. } => {
+                    out_string.push_str("    # ?[\n");
+                    out_string.push_str(&format!("    movb ({}), {}\n", ptr_reg, byte_reg));
+                    out_string.push_str(&format!("    cmpb $0,     {}\n", byte_reg));
+                    out_string.push_str(&format!("    je   if{}_end\n", id));
+                    out_string.push('\n');
+                    out_string.push_str(&options.loop_marker(*id, LoopPart::Body, "#"));
+
+                    compile_rec(out_string, body, ptr_reg, byte_reg, options);
+
+                    out_string.push_str("     # ]\n");
+                    out_string.push_str(&format!("if{}_end:\n", id));
+                    out_string.push_str(&options.loop_marker(*id, LoopPart::After, "#"));
+                }
+                Command::Loop {
+                    body,
+                    id,
+                    end_count,
+                    ..
+                } => {
                     out_string.push_str("    # [\n");
                     out_string.push_str(&format!("loop{}:\n", id));
                     out_string.push_str(&format!("    movb ({}), {}\n", ptr_reg, byte_reg));
                     out_string.push_str(&format!("    cmpb $0,     {}\n", byte_reg));
                     out_string.push_str(&format!("    je   loop{}_end\n", id));
                     out_string.push('\n');
+                    out_string.push_str(&options.loop_marker(*id, LoopPart::Body, "#"));
 
-                    compile_rec(out_string, body, ptr_reg, byte_reg);
+                    compile_rec(out_string, body, ptr_reg, byte_reg, options);
 
                     out_string.push_str("     # ]\n");
+                    out_string.push_str(&options.loop_end_marker(*id, *end_count, "#"));
                     out_string.push_str(&format!("    jmp  loop{}\n", id));
                     out_string.push('\n');
                     out_string.push_str(&format!("loop{}_end:\n", id));
+                    out_string.push_str(&options.loop_marker(*id, LoopPart::After, "#"));
                 }
             }
         }
+        cache.spill(out_string);
     }
 
     let ptr_reg = "%r12";
@@ -457,38 +1376,618 @@ pub fn compile(
     let byte_reg = "%r13b";
 
     // Build assembly file
-    let mut asm = String::new();
-    append_assembly_header(&mut asm, ptr_reg, full_byte_reg);
-    compile_rec(&mut asm, commands, ptr_reg, byte_reg);
-    append_assembly_footer(&mut asm, ptr_reg, full_byte_reg);
-
-    let asm_filepath = replace_extension_filepath(src_filepath, ".s");
-    let asm_filepath = strip_directories_filepath(&asm_filepath);
-    let mut asm_file = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&asm_filepath)
-        .expect("Unable to open output file");
-    asm_file
-        .write_all(asm.as_bytes())
-        .expect("Unable to write to assembly file");
-
-    if output_binary_file {
-        // Assemble and link output
-        let object_filepath = replace_extension_filepath(src_filepath, ".o");
-        let object_filepath = strip_directories_filepath(&object_filepath);
-
-        if let Ok(()) = clang(&asm_filepath, dest_filename, output_object_file) {
-            return;
+    let asm = match options.target {
+        Target::X86_64 => {
+            let mut asm = String::new();
+            if let Some(source_map) = options.debug_map() {
+                asm.push_str(&source_map.file_directive());
+            }
+            append_assembly_header(&mut asm, ptr_reg, full_byte_reg, options.freestanding);
+            if options.simd == Simd::Auto {
+                append_cpu_detection(&mut asm);
+            }
+            asm.push_str(&options.start_marker("#"));
+            compile_rec(&mut asm, commands, ptr_reg, byte_reg, options);
+            append_assembly_footer(
+                &mut asm,
+                ptr_reg,
+                full_byte_reg,
+                options.freestanding,
+                options.exit_code,
+            );
+            if let Some(source_map) = options.debug_map() {
+                asm.push_str(&source_map.end_label());
+            }
+            append_output_runtime(&mut asm, options.freestanding, options.runtime_section());
+            if let Some(source_map) = options.debug_map() {
+                // `%r12` is register 12 in the x86-64 DWARF numbering
+                asm.push_str(&source_map.debug_sections(options.entry(), 12));
+            }
+            asm
         }
+        Target::Aarch64 => crate::aarch64::generate(commands, options),
+        Target::Riscv64 => crate::riscv64::generate(commands, options),
+    };
 
-        if let Err(e) = assemble(&asm, &object_filepath) {
-            eprintln!("{}", e);
-            return;
+    if artifact == Artifact::Assembly {
+        // Assemblers always get AT&T syntax, only the output is translated
+        if options.asm_syntax == AsmSyntax::Intel {
+            return dest.write(crate::intel::translate(&asm)?.as_bytes(), false);
+        }
+        return dest.write(asm.as_bytes(), false);
+    }
+    let executable = artifact == Artifact::Executable;
+
+    if options.integrated_as {
+        let object = crate::assembler::assemble(&asm)?;
+        let contents = if executable {
+            crate::elf::write_executable(&object, "_start")?
+        } else {
+            crate::elf::write_object(&object)
+        };
+        return dest.write(&contents, executable);
+    }
+
+    // Intermediate files, and the output until it's complete, live in a
+    // temporary directory so that nothing is left behind on failure
+    let temp_dir = TempDir::new()?;
+    let asm_filepath = temp_dir.file("program.s");
+    let object_filepath = temp_dir.file("program.o");
+    let output_filepath = if executable {
+        temp_dir.file("program")
+    } else {
+        object_filepath.clone()
+    };
+    std::fs::write(&asm_filepath, &asm)
+        .map_err(|e| format!("Error: Failed to write {}: {}", asm_filepath, e))?;
+
+    if clang(
+        &asm_filepath,
+        &output_filepath,
+        !executable,
+        options.freestanding,
+        options.target,
+    )
+    .is_err()
+    {
+        assemble(&asm, &object_filepath, options.target)?;
+        if executable {
+            link(
+                &object_filepath,
+                &output_filepath,
+                options.freestanding,
+                options.target,
+            )?;
+        }
+    }
+
+    let contents = std::fs::read(&output_filepath)
+        .map_err(|e| format!("Error: Failed to read {}: {}", output_filepath, e))?;
+    dest.write(&contents, executable)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::interp::interp_io;
+    use crate::ir::parse_ir;
+    use crate::optimizer::tests::{optimized_bf, HELLO};
+    use crate::optimizer::InfiniteLoopMode;
+
+    /// Modes whose vector code runs on this CPU
+    fn simd_modes() -> Vec<Simd> {
+        let mut modes = vec![Simd::Sse2, Simd::None, Simd::Auto];
+        if std::arch::is_x86_feature_detected!("avx2") {
+            modes.push(Simd::Avx2);
+        }
+        modes
+    }
+
+    /// Moves from the start cell to `stride * 20` cells away from an end of
+    /// the tape, fills the cells scanned over, and scans onto the end cell
+    fn scan_to_end(direction: &Direction, stride: usize) -> String {
+        let distance = stride * 20;
+        let (mut ir, sign, name) = match direction {
+            Direction::Left => (
+                format!("ptr_dec {}\n", INIT_POINTER_LOC - distance),
+                -1,
+                "left",
+            ),
+            Direction::Right => (
+                format!(
+                    "ptr_inc {}\n",
+                    INIT_TAPE_SIZE - 1 - distance - INIT_POINTER_LOC
+                ),
+                1,
+                "right",
+            ),
+        };
+        for cell in (0..distance).step_by(stride) {
+            ir.push_str(&format!("set @{} 1\n", sign * cell as isize));
+        }
+        ir.push_str(&format!("scan {} {}\n", name, stride));
+        // Leaves the pointer on the end cell only if the scan stopped there
+        let back = match direction {
+            Direction::Left => "inc",
+            Direction::Right => "dec",
+        };
+        ir.push_str(&format!(
+            "inc @0 66\nout @0\nptr_{} {}\nout @0\n",
+            back, stride
+        ));
+        ir
+    }
+
+    fn options(simd: Simd, freestanding: bool) -> CompileOptions {
+        CompileOptions {
+            target: Target::X86_64,
+            simd,
+            exit_code: ExitCode::Zero,
+            freestanding,
+            integrated_as: freestanding,
+            asm_syntax: AsmSyntax::Att,
+            source_map: None,
+            debug_info: false,
+            annotate: false,
+            counts: false,
+        }
+    }
+
+    fn run_compiled(commands: &[Command], simd: Simd, freestanding: bool) -> Vec<u8> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.file("a.out");
+        let options = options(simd, freestanding);
+        compile(
+            commands,
+            Artifact::Executable,
+            &Destination::File(path.clone()),
+            &options,
+        )
+        .unwrap();
+        let output = std::process::Command::new(&path).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{:?} exited with {}",
+            simd,
+            output.status
+        );
+        output.stdout
+    }
+
+    /// Infinite loops ending on each kind of divisor, scans both ways, input
+    /// and constant output, for checking how the other targets lower them
+    pub(crate) const LOWERINGS: &str = "out_const 72\nout_const 105\nout_const 10\n\
+        set @0 3\ninf_loop #1 1\nset @0 12\ninf_loop #2 4\ninc @0 48\nout @0\n\
+        set @1 1\nset @2 1\nset @3 1\nset @5 1\nscan @1 right 1\ninc @0 65\nout @0\n\
+        set @-2 1\nset @-4 1\nscan @-2 left 2\ninc @0 66\nout @0\nin @1\nout @1\nout_const 10\n";
+
+    /// Prints `Hi` and traps in infinite loop 3
+    pub(crate) const TRAP: &str = "out_const 72\nout_const 105\nset @0 6\ninf_loop #3 4\n";
+
+    /// Whether `program` is installed, for skipping tests that need it
+    pub(crate) fn installed(program: &str) -> bool {
+        std::process::Command::new(program)
+            .arg("--version")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok()
+    }
+
+    /// Runs `command` with `input` on stdin and collects its output
+    pub(crate) fn run_with_input(
+        command: &mut std::process::Command,
+        input: &[u8],
+    ) -> std::process::Output {
+        let mut child = command
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        use std::io::Write;
+        // Programs that don't read all of their input may exit first
+        let _ = child.stdin.take().unwrap().write_all(input);
+        child.wait_with_output().unwrap()
+    }
+
+    /// Options for a freestanding program for `target`
+    pub(crate) fn target_options(target: Target, simd: Simd) -> CompileOptions {
+        CompileOptions {
+            target,
+            integrated_as: false,
+            ..options(simd, true)
+        }
+    }
+
+    /// Assembles `asm` for `target` with its GNU assembler, or with `llvm-mc`
+    /// if that isn't installed. `None` if neither is.
+    pub(crate) fn cross_assemble(asm: &str, target: Target) -> Option<Result<(), String>> {
+        let dir = TempDir::new().unwrap();
+        let asm_path = dir.file("a.s");
+        std::fs::write(&asm_path, asm).unwrap();
+        let gnu_as = target.tool("as");
+        let mut command = if installed(&gnu_as) {
+            std::process::Command::new(gnu_as)
+        } else if installed("llvm-mc") {
+            let mut command = std::process::Command::new("llvm-mc");
+            command
+                .arg(format!("--triple={}", target.triple()))
+                .arg("--filetype=obj");
+            command
+        } else {
+            return None;
+        };
+        let output = command
+            .arg("-o")
+            .arg(dir.file("a.o"))
+            .arg(&asm_path)
+            .output()
+            .unwrap();
+        if output.status.success() {
+            Some(Ok(()))
+        } else {
+            Some(Err(String::from_utf8_lossy(&output.stderr).into_owned()))
+        }
+    }
+
+    /// Compiles `commands` for `options.target` and runs the program under
+    /// qemu with `input`. `None` if qemu isn't installed.
+    pub(crate) fn run_under_qemu(
+        commands: &[Command],
+        options: &CompileOptions,
+        input: &[u8],
+    ) -> Option<std::process::Output> {
+        let qemu = format!("qemu-{}", options.target.arch());
+        if !installed(&qemu) {
+            return None;
+        }
+        let dir = TempDir::new().unwrap();
+        let path = dir.file("a.out");
+        compile(
+            commands,
+            Artifact::Executable,
+            &Destination::File(path.clone()),
+            options,
+        )
+        .unwrap();
+        Some(run_with_input(
+            std::process::Command::new(qemu)
+                .arg("-L")
+                .arg(format!("/usr/{}", options.target.triple()))
+                .arg(&path),
+            input,
+        ))
+    }
+
+    #[test]
+    fn scans_at_tape_ends() {
+        for direction in [Direction::Left, Direction::Right] {
+            for stride in [1, 2, 3] {
+                let ir = scan_to_end(&direction, stride);
+                let mut commands = parse_ir(&ir).unwrap();
+                let mut expected = vec![];
+                interp_io(&mut commands, &mut std::io::empty(), &mut expected);
+                assert_eq!(expected, b"B\x01");
+                for simd in simd_modes() {
+                    for freestanding in [false, true] {
+                        assert_eq!(
+                            run_compiled(&commands, simd, freestanding),
+                            expected,
+                            "{:?} scan by {} with {:?}",
+                            direction,
+                            stride,
+                            simd
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scans_with_offsets() {
+        // Each scan passes 40 set cells in fresh parts of the tape, then the
+        // cell it stopped on and the one before it are printed
+        let mut ir = String::new();
+        for (direction, sign) in [(Direction::Right, 1), (Direction::Left, -1)] {
+            for stride in [1, 2, 5] {
+                for offset in [-3, 2] {
+                    for k in 0..40 {
+                        ir.push_str(&format!("set @{} 1\n", offset + sign * k * stride));
+                    }
+                    let name = match direction {
+                        Direction::Left => "left",
+                        Direction::Right => "right",
+                    };
+                    ir.push_str(&format!("scan @{} {} {}\n", offset, name, stride));
+                    ir.push_str(&format!("inc @{} 65\nout @{}\n", offset, offset));
+                    ir.push_str(&format!("out @{}\nptr_inc 1000\n", offset - sign * stride));
+                }
+            }
+        }
+        let mut commands = parse_ir(&ir).unwrap();
+        let mut expected = vec![];
+        interp_io(&mut commands, &mut std::io::empty(), &mut expected);
+        assert_eq!(expected, b"A\x01".repeat(12));
+        for simd in simd_modes() {
+            assert_eq!(run_compiled(&commands, simd, true), expected, "{:?}", simd);
+        }
+    }
+
+    #[test]
+    fn buffered_output() {
+        // 20000 bytes overflow the buffer twice, between runs of constant output
+        let ir = "out_const 72\nout_const 105\nout_const 10\nset @0 65\nset @1 100\nptr_inc 1\n\
+                  loop { set @1 200 ptr_inc 1 loop { out @-2 dec @0 1 } ptr_dec 1 dec @0 1 }\n\
+                  out_const 10\nout_const 10\n";
+        let commands = parse_ir(ir).unwrap();
+        let mut expected = vec![];
+        interp_io(&mut commands.clone(), &mut std::io::empty(), &mut expected);
+        assert_eq!(expected.len(), 20005);
+        for freestanding in [false, true] {
+            assert_eq!(run_compiled(&commands, Simd::Auto, freestanding), expected);
+        }
+    }
+
+    #[test]
+    fn output_is_flushed_before_traps() {
+        let commands = parse_ir("out_const 72\nout_const 105\nset @0 1\ninf_loop #7 0\n").unwrap();
+        for freestanding in [false, true] {
+            let dir = TempDir::new().unwrap();
+            let path = dir.file("a.out");
+            compile(
+                &commands,
+                Artifact::Executable,
+                &Destination::File(path.clone()),
+                &options(Simd::Auto, freestanding),
+            )
+            .unwrap();
+            let output = std::process::Command::new(&path).output().unwrap();
+            assert_eq!(output.status.code(), Some(1));
+            assert_eq!(output.stdout, b"Hi");
+            assert_eq!(output.stderr, b"infinite loop detected at loop 7\n");
+        }
+    }
+
+    #[test]
+    fn cached_cells_behave_the_same() {
+        let programs = [
+            HELLO,
+            // Cells written, moved between and read back across pointer moves
+            "++>+++[<+>-]<.>>+++++[<++>-]<.<[->>+<<]>>.<<+++.>>>-.<<<[-]>[-]+.",
+            // Cells changed inside conditional and scan loops
+            "+>+>+>+<<<[>]<<.+[<+++>[-]]<.>>>>+++++[<]>.",
+        ];
+        for src in programs {
+            let mut expected = vec![];
+            interp_io(
+                &mut crate::parser::parse(&src.to_string()),
+                &mut std::io::empty(),
+                &mut expected,
+            );
+            let commands = optimized_bf(src, 3, InfiniteLoopMode::Ignore);
+            assert_eq!(
+                run_compiled(&commands, Simd::Auto, true),
+                expected,
+                "{}",
+                src
+            );
+        }
+    }
+
+    /// Output of `objdump` with `args` on `path`
+    fn objdump(args: &[&str], path: &str) -> String {
+        let output = std::process::Command::new("objdump")
+            .args(args)
+            .arg(path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn line_table_ends_at_program_end() {
+        let src = String::from("+[->+<]>.");
+        let commands = crate::parser::parse(&src);
+        let dir = TempDir::new().unwrap();
+        let path = dir.file("a.o");
+        let options = CompileOptions {
+            source_map: Some(SourceMap::new("a.b", &src)),
+            debug_info: true,
+            ..options(Simd::Auto, false)
+        };
+        compile(
+            &commands,
+            Artifact::Object,
+            &Destination::File(path.clone()),
+            &options,
+        )
+        .unwrap();
+
+        // The program code is all of `.text`, and the runtime is in a section
+        // of its own
+        let headers = objdump(&["-h"], &path);
+        let text_size = headers
+            .lines()
+            .find_map(
+                |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                    [_, ".text", size, ..] => Some(u64::from_str_radix(size, 16).unwrap()),
+                    _ => None,
+                },
+            )
+            .unwrap();
+        assert!(headers.contains(".text.runtime"));
+
+        // The last row ends the sequence at `.Lprogram_end`
+        let lines = objdump(&["--dwarf=decodedline"], &path);
+        let rows: Vec<Vec<&str>> = lines
+            .lines()
+            .filter(|line| line.starts_with("a.b"))
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        let end = rows.last().unwrap();
+        assert_eq!(end[1], "-");
+        assert_eq!(u64::from_str_radix(&end[2][2..], 16).unwrap(), text_size);
+        assert!(rows[..rows.len() - 1].iter().all(|row| row[1] == "1"));
+    }
+
+    #[test]
+    fn annotations_only_add_comments() {
+        let src = String::from(",[>+<-]>.");
+        let mut commands = crate::parser::parse(&src);
+        interp_io(&mut commands, &mut &b"\x02"[..], &mut vec![]);
+        let dir = TempDir::new().unwrap();
+        let annotated = CompileOptions {
+            source_map: Some(SourceMap::new("a.b", &src)),
+            annotate: true,
+            counts: true,
+            ..options(Simd::Auto, false)
+        };
+        let asm_path = dir.file("a.s");
+        compile(
+            &commands,
+            Artifact::Assembly,
+            &Destination::File(asm_path.clone()),
+            &annotated,
+        )
+        .unwrap();
+        let asm = std::fs::read_to_string(&asm_path).unwrap();
+        for line in [
+            "    # bf: [>+<-]\n",
+            "    # ir: [, executed 2 times\n",
+            "    # ir: +, executed 2 times\n",
+            "    # ir: ., executed 1 time\n",
+        ] {
+            assert!(asm.contains(line), "{:?} missing from:\n{}", line, asm);
+        }
+
+        // The comments don't change the code
+        let disassembly: Vec<String> = [&annotated, &options(Simd::Auto, false)]
+            .into_iter()
+            .map(|options| {
+                let path = dir.file("a.o");
+                compile(
+                    &commands,
+                    Artifact::Object,
+                    &Destination::File(path.clone()),
+                    options,
+                )
+                .unwrap();
+                objdump(&["-dr"], &path)
+            })
+            .collect();
+        assert_eq!(disassembly[0], disassembly[1]);
+    }
+
+    #[test]
+    fn freestanding_executables_use_raw_syscalls() {
+        // Reads past the end of the input too
+        let ir = format!("{}in @0\nin @0\nin @0\nout @0\n", LOWERINGS);
+        let commands = parse_ir(&ir).unwrap();
+        let mut expected = vec![];
+        interp_io(&mut commands.clone(), &mut &b"xyz"[..], &mut expected);
+        assert!(expected.ends_with(b"\xff"));
+        for integrated_as in [false, true] {
+            let dir = TempDir::new().unwrap();
+            let path = dir.file("a.out");
+            let options = CompileOptions {
+                integrated_as,
+                ..options(Simd::Auto, true)
+            };
+            compile(
+                &commands,
+                Artifact::Executable,
+                &Destination::File(path.clone()),
+                &options,
+            )
+            .unwrap();
+            // Nothing to load, and nothing left for a dynamic linker to resolve
+            let headers = objdump(&["-p"], &path);
+            assert!(!headers.contains("INTERP"));
+            assert!(!headers.contains("NEEDED"));
+            assert!(!objdump(&["-t"], &path).contains("*UND*"));
+
+            let output = run_with_input(&mut std::process::Command::new(&path), b"xyz");
+            assert!(output.status.success());
+            assert_eq!(output.stdout, expected);
         }
-        if let Err(e) = link(&object_filepath, dest_filename, output_object_file) {
-            eprintln!("{}", e);
+    }
+
+    #[test]
+    fn exit_status_is_the_final_cell() {
+        let commands = parse_ir(LOWERINGS).unwrap();
+        let mut expected = vec![];
+        let cell = interp_io(&mut commands.clone(), &mut &b"xyz"[..], &mut expected);
+        for freestanding in [false, true] {
+            let dir = TempDir::new().unwrap();
+            let (asm_path, path) = (dir.file("a.s"), dir.file("a.out"));
+            let options = CompileOptions {
+                exit_code: ExitCode::Cell,
+                ..options(Simd::Auto, freestanding)
+            };
+            for (artifact, path) in [
+                (Artifact::Assembly, &asm_path),
+                (Artifact::Executable, &path),
+            ] {
+                compile(
+                    &commands,
+                    artifact,
+                    &Destination::File(path.clone()),
+                    &options,
+                )
+                .unwrap();
+            }
+            // The tape is freed and the output flushed before exiting
+            let asm = std::fs::read_to_string(&asm_path).unwrap();
+            assert_eq!(asm.contains("call  free"), !freestanding);
+            let output = run_with_input(&mut std::process::Command::new(&path), b"xyz");
+            assert_eq!(output.status.code(), Some(cell as i32));
+            assert_eq!(output.stdout, expected);
         }
     }
+
+    #[test]
+    fn writes_only_the_artifact() {
+        let commands = optimized_bf(HELLO, 3, InfiniteLoopMode::Trap);
+        for (integrated_as, freestanding) in [(false, false), (false, true), (true, true)] {
+            let options = CompileOptions {
+                integrated_as,
+                ..options(Simd::Auto, freestanding)
+            };
+            for artifact in [Artifact::Assembly, Artifact::Object, Artifact::Executable] {
+                let dir = TempDir::new().unwrap();
+                let path = dir.file("out");
+                compile(
+                    &commands,
+                    artifact,
+                    &Destination::File(path.clone()),
+                    &options,
+                )
+                .unwrap();
+                let entries: Vec<_> = std::fs::read_dir(&dir.path)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name())
+                    .collect();
+                assert_eq!(entries, ["out"], "{:?}", artifact);
+            }
+        }
+    }
+
+    #[test]
+    fn temp_dirs_are_removed_when_dropped() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.file("program.s"), "").unwrap();
+        let path = dir.path.clone();
+        assert!(path.is_dir());
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn dash_means_stdout() {
+        assert!(matches!(Destination::new("-"), Destination::Stdout));
+        assert!(matches!(Destination::new("./-"), Destination::File(path) if path == "./-"));
+    }
 }
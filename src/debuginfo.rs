@@ -0,0 +1,343 @@
+//! Source mapping for compiled programs, and DWARF debug info written as
+//! assembler directives.
+//!
+//! The optimizer merges and reorders commands, so only loops keep their
+//! identity in the command tree. Each loop's code is tied to its `[` and `]`,
+//! and the straight-line code after a bracket to the first command following
+//! it in the source. The assembler builds `.debug_line` from the `.loc`
+//! directives; `.debug_info` is written out here because it also describes
+//! the tape pointer register as a variable `p`, so `print *p` works in gdb.
+//!
+//! `--annotate` uses the same mapping to show the source of each part of the
+//! code in comments.
+
+use crate::compiler::escape_ascii;
+use crate::parser::{tokenize, TokenKind};
+
+#[derive(Clone, Copy)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+/// A part of a loop's code, for `SourceMap::loop_loc`
+#[derive(Clone, Copy)]
+pub enum LoopPart {
+    /// Testing the cell on entry, or the whole loop if it became a scan
+    Open,
+    /// From the first command of the body, unless the body starts with a bracket
+    Body,
+    /// Jumping back to the start
+    Close,
+    /// From the first command after the loop, unless a bracket comes next
+    After,
+}
+
+/// Label at the end of the program code, before the output runtime
+const PROGRAM_END: &str = ".Lprogram_end";
+
+/// Longest source fragment shown in annotated assembly
+const FRAGMENT_WIDTH: usize = 40;
+
+/// Source positions of the commands and loops in a program
+pub struct SourceMap {
+    file_name: String,
+    commands: Vec<(char, Position)>,
+    /// Indices of the brackets of each loop, indexed by loop id
+    loops: Vec<(usize, usize)>,
+}
+
+fn loc(position: Position) -> String {
+    format!("    .loc 1 {} {}\n", position.line, position.column)
+}
+
+impl SourceMap {
+    /// Maps the loops of `src` by the ids `parser::parse` gives them, which
+    /// count closing brackets from 1
+    pub fn new(file_name: &str, src: &str) -> SourceMap {
+        let commands: Vec<(char, Position)> = tokenize(src)
+            .into_iter()
+            .filter_map(|token| match token.kind {
+                TokenKind::Command(c) => Some((
+                    c,
+                    Position {
+                        line: token.line,
+                        column: token.column,
+                    },
+                )),
+                TokenKind::Comment(_) => None,
+            })
+            .collect();
+
+        let mut loops = vec![];
+        let mut open: Vec<usize> = vec![];
+        for (idx, (c, _)) in commands.iter().enumerate() {
+            match c {
+                '[' => open.push(idx),
+                ']' => {
+                    if let Some(start) = open.pop() {
+                        loops.push((start, idx));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        SourceMap {
+            file_name: file_name.to_string(),
+            commands,
+            loops,
+        }
+    }
+
+    /// Straight-line code starting at `idx`, if there is any
+    fn code_at(&self, idx: usize) -> Option<usize> {
+        match self.commands.get(idx) {
+            Some(('[' | ']', _)) | None => None,
+            Some(_) => Some(idx),
+        }
+    }
+
+    /// Index of the command that a part of loop `id` starts at, if that
+    /// part has any source of its own
+    fn part_start(&self, id: usize, part: LoopPart) -> Option<usize> {
+        let (open, close) = *id.checked_sub(1).and_then(|idx| self.loops.get(idx))?;
+        match part {
+            LoopPart::Open => Some(open),
+            LoopPart::Body => self.code_at(open + 1),
+            LoopPart::Close => Some(close),
+            LoopPart::After => self.code_at(close + 1),
+        }
+    }
+
+    /// Source of the commands from `start` to `end`, shortened to fit a line
+    fn fragment(&self, start: usize, end: usize) -> String {
+        let text: String = self.commands[start..end].iter().map(|(c, _)| c).collect();
+        if text.len() > FRAGMENT_WIDTH {
+            format!("{}...", &text[..FRAGMENT_WIDTH - 3])
+        } else {
+            text
+        }
+    }
+
+    /// Source of the straight-line code starting at `start`
+    fn straight_line(&self, start: usize) -> String {
+        let end = (start..self.commands.len())
+            .find(|idx| self.code_at(*idx).is_none())
+            .unwrap_or(self.commands.len());
+        self.fragment(start, end)
+    }
+
+    /// Declares the source file. Goes before any `.loc` directive.
+    pub fn file_directive(&self) -> String {
+        format!(
+            "    .file 1 \"{}\"\n",
+            escape_ascii(self.file_name.as_bytes())
+        )
+    }
+
+    /// `.loc` for the code at the start of the program
+    pub fn start_loc(&self) -> String {
+        match self.code_at(0) {
+            Some(idx) => loc(self.commands[idx].1),
+            None => String::new(),
+        }
+    }
+
+    /// `.loc` for a part of loop `id`, or nothing if that part has no
+    /// source of its own
+    pub fn loop_loc(&self, id: usize, part: LoopPart) -> String {
+        match self.part_start(id, part) {
+            Some(idx) => loc(self.commands[idx].1),
+            None => String::new(),
+        }
+    }
+
+    /// Source of the code at the start of the program
+    pub fn start_fragment(&self) -> Option<String> {
+        self.code_at(0).map(|idx| self.straight_line(idx))
+    }
+
+    /// Source of a part of loop `id`: the whole loop for `Open`, and the
+    /// straight-line code up to the next bracket for `Body` and `After`
+    pub fn loop_fragment(&self, id: usize, part: LoopPart) -> Option<String> {
+        let idx = self.part_start(id, part)?;
+        Some(match part {
+            LoopPart::Open => {
+                let (_, close) = self.loops[id - 1];
+                self.fragment(idx, close + 1)
+            }
+            LoopPart::Close => String::from("]"),
+            LoopPart::Body | LoopPart::After => self.straight_line(idx),
+        })
+    }
+
+    /// Label after the code that exits, where the line table ends. The output
+    /// runtime goes in a section of its own after it and is left out of the
+    /// compile unit, so debuggers step over calls into it.
+    pub fn end_label(&self) -> String {
+        format!("{}:\n", PROGRAM_END)
+    }
+
+    /// The `.debug_abbrev` and `.debug_info` sections, describing the code
+    /// from `entry` to the end of the program as one function. `ptr_reg` is
+    /// the DWARF number of the tape pointer register.
+    pub fn debug_sections(&self, entry: &str, ptr_reg: u8) -> String {
+        let comp_dir = std::env::current_dir()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut out = String::new();
+
+        out.push_str("\n    .section .debug_abbrev,\"\",@progbits\n");
+        out.push_str(".Ldebug_abbrev0:\n");
+        // Code, tag, whether it has children, then attribute and form pairs
+        type Abbrev = (u8, u8, bool, &'static [(u8, u8)]);
+        let abbrevs: [Abbrev; 5] = [
+            // DW_TAG_compile_unit: name, comp_dir, producer, language,
+            // low_pc, high_pc, stmt_list
+            (
+                1,
+                0x11,
+                true,
+                &[
+                    (0x03, 0x08),
+                    (0x1b, 0x08),
+                    (0x25, 0x08),
+                    (0x13, 0x05),
+                    (0x11, 0x01),
+                    (0x12, 0x07),
+                    (0x10, 0x17),
+                ],
+            ),
+            // DW_TAG_subprogram: name, external, low_pc, high_pc
+            (
+                2,
+                0x2e,
+                true,
+                &[(0x03, 0x08), (0x3f, 0x19), (0x11, 0x01), (0x12, 0x07)],
+            ),
+            // DW_TAG_variable: name, type, location
+            (3, 0x34, false, &[(0x03, 0x08), (0x49, 0x13), (0x02, 0x18)]),
+            // DW_TAG_base_type: name, encoding, byte_size
+            (4, 0x24, false, &[(0x03, 0x08), (0x3e, 0x0b), (0x0b, 0x0b)]),
+            // DW_TAG_pointer_type: byte_size, type
+            (5, 0x0f, false, &[(0x0b, 0x0b), (0x49, 0x13)]),
+        ];
+        for (code, tag, children, attributes) in abbrevs {
+            out.push_str(&format!("    .uleb128 {}\n", code));
+            out.push_str(&format!("    .uleb128 {:#x}\n", tag));
+            out.push_str(&format!("    .byte {}\n", children as u8));
+            for (attribute, form) in attributes {
+                out.push_str(&format!("    .uleb128 {:#x}\n", attribute));
+                out.push_str(&format!("    .uleb128 {:#x}\n", form));
+            }
+            out.push_str("    .byte 0\n    .byte 0\n");
+        }
+        out.push_str("    .byte 0\n");
+
+        // The language is C, so that gdb accepts expressions like `p[-1]`
+        out.push_str(&format!(
+            r#"
+    .section .debug_info,"",@progbits
+.Ldebug_info0:
+    .4byte .Ldebug_info_end - .Ldebug_info_start
+.Ldebug_info_start:
+    .2byte 4
+    .4byte .Ldebug_abbrev0
+    .byte 8
+
+    .uleb128 1
+    .asciz "{name}"
+    .asciz "{comp_dir}"
+    .asciz "bfr"
+    .2byte 0x0c
+    .8byte {entry}
+    .8byte {end} - {entry}
+    .4byte .Ldebug_line0
+
+    .uleb128 2
+    .asciz "{entry}"
+    .8byte {entry}
+    .8byte {end} - {entry}
+
+    .uleb128 3
+    .asciz "p"
+    .4byte .Ltype_pointer - .Ldebug_info0
+    .uleb128 1
+    .byte {location:#x}
+    .byte 0
+
+.Ltype_cell:
+    .uleb128 4
+    .asciz "unsigned char"
+    .byte 0x08
+    .byte 1
+.Ltype_pointer:
+    .uleb128 5
+    .byte 8
+    .4byte .Ltype_cell - .Ldebug_info0
+    .byte 0
+.Ldebug_info_end:
+
+    .section .debug_line,"",@progbits
+.Ldebug_line0:
+"#,
+            name = escape_ascii(self.file_name.as_bytes()),
+            comp_dir = escape_ascii(comp_dir.as_bytes()),
+            entry = entry,
+            end = PROGRAM_END,
+            // DW_OP_reg0 + n
+            location = 0x50 + ptr_reg
+        ));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, Command};
+
+    const SRC: &str = "+[>[-]<\n-]. done";
+
+    #[test]
+    fn loops_map_by_parser_ids() {
+        // Ids count closing brackets, so the inner loop comes first
+        let commands = parse(&SRC.to_string());
+        let Command::Loop { id, body, .. } = &commands[1] else {
+            panic!("expected a loop");
+        };
+        assert_eq!(*id, 2);
+        assert_eq!(body[1].loop_id(), Some(1));
+
+        let map = SourceMap::new("a.b", SRC);
+        assert_eq!(map.start_loc(), "    .loc 1 1 1\n");
+        assert_eq!(map.loop_loc(2, LoopPart::Open), "    .loc 1 1 2\n");
+        assert_eq!(map.loop_loc(2, LoopPart::Body), "    .loc 1 1 3\n");
+        assert_eq!(map.loop_loc(1, LoopPart::Body), "    .loc 1 1 5\n");
+        assert_eq!(map.loop_loc(1, LoopPart::After), "    .loc 1 1 7\n");
+        assert_eq!(map.loop_loc(2, LoopPart::Close), "    .loc 1 2 2\n");
+        assert_eq!(map.loop_loc(2, LoopPart::After), "    .loc 1 2 3\n");
+        // Ids the parser never gave out have no source
+        assert_eq!(map.loop_loc(3, LoopPart::Open), "");
+    }
+
+    #[test]
+    fn fragments() {
+        let map = SourceMap::new("a.b", SRC);
+        assert_eq!(map.start_fragment().as_deref(), Some("+"));
+        assert_eq!(
+            map.loop_fragment(2, LoopPart::Open).as_deref(),
+            Some("[>[-]<-]")
+        );
+        assert_eq!(map.loop_fragment(1, LoopPart::After).as_deref(), Some("<-"));
+        assert_eq!(map.loop_fragment(2, LoopPart::After).as_deref(), Some("."));
+        assert_eq!(map.loop_fragment(1, LoopPart::Close).as_deref(), Some("]"));
+
+        let long = format!("[{}]", "+".repeat(60));
+        let map = SourceMap::new("a.b", &long);
+        let fragment = map.loop_fragment(1, LoopPart::Open).unwrap();
+        assert_eq!(fragment.len(), FRAGMENT_WIDTH);
+        assert!(fragment.ends_with("+++..."));
+    }
+}
@@ -0,0 +1,452 @@
+//! ELF64 writer for the output of `assembler`.
+//!
+//! `write_object` produces a relocatable object like `as` would, to be linked
+//! by any linker. `write_executable` links the object on its own into a
+//! static, non-PIE executable, which only works for code that doesn't call
+//! into libc, such as that of `--freestanding`.
+
+use crate::assembler::{Object, RelocationTarget, BSS, DATA, RODATA, SECTION_NAMES, TEXT};
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Address executables are loaded at, the usual one for x86-64
+const BASE_ADDRESS: u64 = 0x400000;
+const PAGE_SIZE: usize = 0x1000;
+const SECTION_ALIGN: usize = 16;
+
+struct SectionHeader {
+    name: String,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl SectionHeader {
+    fn new(name: &str, kind: u32, flags: u64, offset: usize, size: usize) -> SectionHeader {
+        SectionHeader {
+            name: name.to_string(),
+            kind,
+            flags,
+            addr: 0,
+            offset,
+            size,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        }
+    }
+}
+
+/// Type and flags of the section headers for the assembler's sections
+fn section_kind(section: usize) -> (u32, u64) {
+    match section {
+        TEXT => (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR),
+        RODATA => (SHT_PROGBITS, SHF_ALLOC),
+        DATA => (SHT_PROGBITS, SHF_ALLOC | SHF_WRITE),
+        _ => (SHT_NOBITS, SHF_ALLOC | SHF_WRITE),
+    }
+}
+
+fn pad_to(out: &mut Vec<u8>, align: usize) {
+    out.resize(out.len().next_multiple_of(align), 0);
+}
+
+/// Appends `name` to a string table and returns its offset
+fn add_string(table: &mut Vec<u8>, name: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend(name.as_bytes());
+    table.push(0);
+    offset
+}
+
+fn write_symbol(out: &mut Vec<u8>, name: u32, info: u8, shndx: u16, value: u64) {
+    out.extend(name.to_le_bytes());
+    out.push(info);
+    out.push(0);
+    out.extend(shndx.to_le_bytes());
+    out.extend(value.to_le_bytes());
+    out.extend(0u64.to_le_bytes());
+}
+
+/// The symbol table and its string table
+struct SymbolTable {
+    symtab: Vec<u8>,
+    strtab: Vec<u8>,
+    /// Index of the first global symbol
+    first_global: u32,
+    /// Index of each symbol by name
+    indices: Vec<(String, u32)>,
+}
+
+impl SymbolTable {
+    fn index(&self, name: &str) -> Option<u32> {
+        self.indices
+            .iter()
+            .find(|(symbol, _)| symbol == name)
+            .map(|&(_, index)| index)
+    }
+}
+
+/// Builds the symbol table, with local symbols first as ELF requires.
+/// `section_addrs` gives the address of each section, and `section_symbols`
+/// adds the symbols for sections that relocations refer to.
+fn symbol_table(object: &Object, section_addrs: &[u64], section_symbols: bool) -> SymbolTable {
+    let mut table = SymbolTable {
+        symtab: vec![0; SYM_SIZE],
+        strtab: vec![0],
+        first_global: 0,
+        indices: vec![],
+    };
+    let mut count = 1;
+    if section_symbols {
+        for section in 0..SECTION_NAMES.len() {
+            let shndx = section as u16 + 1;
+            write_symbol(
+                &mut table.symtab,
+                0,
+                (STB_LOCAL << 4) | STT_SECTION,
+                shndx,
+                0,
+            );
+            count += 1;
+        }
+    }
+    for global in [false, true] {
+        if global {
+            table.first_global = count;
+        }
+        for symbol in object.symbols.iter().filter(|s| s.global == global) {
+            let name = add_string(&mut table.strtab, &symbol.name);
+            let bind = if global { STB_GLOBAL } else { STB_LOCAL };
+            let value = section_addrs[symbol.section] + symbol.offset as u64;
+            let shndx = symbol.section as u16 + 1;
+            write_symbol(
+                &mut table.symtab,
+                name,
+                (bind << 4) | STT_NOTYPE,
+                shndx,
+                value,
+            );
+            table.indices.push((symbol.name.clone(), count));
+            count += 1;
+        }
+    }
+    for symbol in &object.undefined {
+        let name = add_string(&mut table.strtab, symbol);
+        write_symbol(
+            &mut table.symtab,
+            name,
+            (STB_GLOBAL << 4) | STT_NOTYPE,
+            0,
+            0,
+        );
+        table.indices.push((symbol.clone(), count));
+        count += 1;
+    }
+    table
+}
+
+fn write_header(
+    out: &mut Vec<u8>,
+    kind: u16,
+    entry: u64,
+    phnum: usize,
+    shoff: usize,
+    shnum: usize,
+) {
+    out.extend([0x7F, b'E', b'L', b'F']);
+    // 64-bit, little endian, version 1, System V ABI
+    out.extend([2, 1, 1, 0]);
+    out.extend([0; 8]);
+    out.extend(kind.to_le_bytes());
+    out.extend(EM_X86_64.to_le_bytes());
+    out.extend(1u32.to_le_bytes());
+    out.extend(entry.to_le_bytes());
+    let phoff = if phnum == 0 { 0 } else { EHDR_SIZE as u64 };
+    out.extend(phoff.to_le_bytes());
+    out.extend((shoff as u64).to_le_bytes());
+    out.extend(0u32.to_le_bytes());
+    out.extend((EHDR_SIZE as u16).to_le_bytes());
+    out.extend((PHDR_SIZE as u16).to_le_bytes());
+    out.extend((phnum as u16).to_le_bytes());
+    out.extend((SHDR_SIZE as u16).to_le_bytes());
+    out.extend((shnum as u16).to_le_bytes());
+    // The section name table is always the last section
+    out.extend((shnum as u16 - 1).to_le_bytes());
+}
+
+/// Appends the symbol, string and section name tables and then the section
+/// headers, and returns the offset of the headers
+fn write_tables(out: &mut Vec<u8>, mut headers: Vec<SectionHeader>, symbols: SymbolTable) -> usize {
+    pad_to(out, 8);
+    // Header n in the list is section n + 1, after the null section
+    let strtab_index = headers.len() as u32 + 2;
+    let mut symtab = SectionHeader::new(".symtab", SHT_SYMTAB, 0, out.len(), symbols.symtab.len());
+    symtab.link = strtab_index;
+    symtab.info = symbols.first_global;
+    symtab.align = 8;
+    symtab.entsize = SYM_SIZE as u64;
+    headers.push(symtab);
+    out.extend(&symbols.symtab);
+    headers.push(SectionHeader::new(
+        ".strtab",
+        SHT_STRTAB,
+        0,
+        out.len(),
+        symbols.strtab.len(),
+    ));
+    out.extend(&symbols.strtab);
+
+    let mut shstrtab = vec![0];
+    let mut names: Vec<u32> = headers
+        .iter()
+        .map(|header| add_string(&mut shstrtab, &header.name))
+        .collect();
+    names.push(add_string(&mut shstrtab, ".shstrtab"));
+    headers.push(SectionHeader::new(
+        ".shstrtab",
+        SHT_STRTAB,
+        0,
+        out.len(),
+        shstrtab.len(),
+    ));
+    out.extend(&shstrtab);
+
+    pad_to(out, 8);
+    let shoff = out.len();
+    out.extend([0; SHDR_SIZE]);
+    for (header, name) in headers.iter().zip(names) {
+        out.extend(name.to_le_bytes());
+        out.extend(header.kind.to_le_bytes());
+        out.extend(header.flags.to_le_bytes());
+        out.extend(header.addr.to_le_bytes());
+        out.extend((header.offset as u64).to_le_bytes());
+        out.extend((header.size as u64).to_le_bytes());
+        out.extend(header.link.to_le_bytes());
+        out.extend(header.info.to_le_bytes());
+        out.extend(header.align.to_le_bytes());
+        out.extend(header.entsize.to_le_bytes());
+    }
+    shoff
+}
+
+/// Returns a relocatable object file
+pub fn write_object(object: &Object) -> Vec<u8> {
+    let mut out = vec![0; EHDR_SIZE];
+    // The null section isn't in the list, so section n has index n + 1
+    let mut headers = vec![];
+    for (i, section) in object.sections.iter().enumerate() {
+        let (kind, flags) = section_kind(i);
+        pad_to(&mut out, SECTION_ALIGN);
+        let mut header =
+            SectionHeader::new(SECTION_NAMES[i], kind, flags, out.len(), section.len());
+        header.align = SECTION_ALIGN as u64;
+        headers.push(header);
+        out.extend(&section.data);
+    }
+    // Marks the stack as non-executable
+    headers.push(SectionHeader::new(
+        ".note.GNU-stack",
+        SHT_PROGBITS,
+        0,
+        out.len(),
+        0,
+    ));
+
+    let symbols = symbol_table(object, &[0; SECTION_NAMES.len()], true);
+    let symtab_index = headers.len() as u32
+        + 1
+        + object
+            .sections
+            .iter()
+            .filter(|section| !section.relocations.is_empty())
+            .count() as u32;
+    for (i, section) in object.sections.iter().enumerate() {
+        if section.relocations.is_empty() {
+            continue;
+        }
+        pad_to(&mut out, 8);
+        let mut header = SectionHeader::new(
+            &format!(".rela{}", SECTION_NAMES[i]),
+            SHT_RELA,
+            SHF_INFO_LINK,
+            out.len(),
+            section.relocations.len() * RELA_SIZE,
+        );
+        header.link = symtab_index;
+        header.info = i as u32 + 1;
+        header.align = 8;
+        header.entsize = RELA_SIZE as u64;
+        headers.push(header);
+        for relocation in &section.relocations {
+            let (symbol, kind) = match &relocation.target {
+                RelocationTarget::Section(section) => (*section as u32 + 1, R_X86_64_PC32),
+                RelocationTarget::Symbol(name) => (
+                    symbols.index(name).unwrap(),
+                    if relocation.branch {
+                        R_X86_64_PLT32
+                    } else {
+                        R_X86_64_PC32
+                    },
+                ),
+            };
+            out.extend((relocation.offset as u64).to_le_bytes());
+            out.extend((((symbol as u64) << 32) | kind as u64).to_le_bytes());
+            out.extend(relocation.addend.to_le_bytes());
+        }
+    }
+
+    let shnum = headers.len() + 4;
+    let shoff = write_tables(&mut out, headers, symbols);
+    let mut header = vec![];
+    write_header(&mut header, ET_REL, 0, 0, shoff, shnum);
+    out[..EHDR_SIZE].copy_from_slice(&header);
+    out
+}
+
+/// Returns a static executable starting at `entry`
+pub fn write_executable(object: &Object, entry: &str) -> Result<Vec<u8>, String> {
+    if let Some(symbol) = object.undefined.first() {
+        return Err(format!(
+            "Error: Undefined symbol `{}`. Only programs compiled with --freestanding can be linked without a linker.",
+            symbol
+        ));
+    }
+    let entry = object
+        .symbol(entry)
+        .ok_or_else(|| format!("Error: Entry point `{}` not found", entry))?;
+    let text = &object.sections[TEXT];
+    let rodata = &object.sections[RODATA];
+    let data = &object.sections[DATA];
+    let bss = &object.sections[BSS];
+
+    // One segment each for code, read-only data, and writable data followed
+    // by the zeroed .bss, plus one marking the stack as non-executable
+    let has_rodata = rodata.len() > 0;
+    let has_data = data.len() + bss.len() > 0;
+    let phnum = 2 + has_rodata as usize + has_data as usize;
+    let mut offsets = [0; 4];
+    offsets[TEXT] = (EHDR_SIZE + phnum * PHDR_SIZE).next_multiple_of(SECTION_ALIGN);
+    offsets[RODATA] = (offsets[TEXT] + text.len()).next_multiple_of(PAGE_SIZE);
+    offsets[DATA] = (offsets[RODATA] + rodata.len()).next_multiple_of(PAGE_SIZE);
+    offsets[BSS] = (offsets[DATA] + data.len()).next_multiple_of(SECTION_ALIGN);
+    let addrs = offsets.map(|offset| BASE_ADDRESS + offset as u64);
+
+    let mut sections: Vec<Vec<u8>> = object.sections.iter().map(|s| s.data.clone()).collect();
+    for (i, section) in object.sections.iter().enumerate() {
+        for relocation in &section.relocations {
+            let target = match &relocation.target {
+                RelocationTarget::Section(section) => addrs[*section],
+                RelocationTarget::Symbol(name) => {
+                    let symbol = object.symbol(name).unwrap();
+                    addrs[symbol.section] + symbol.offset as u64
+                }
+            };
+            let place = addrs[i] + relocation.offset as u64;
+            // Sections are far smaller than 2 GiB, so this always fits
+            let value = (target as i64 + relocation.addend - place as i64) as i32;
+            sections[i][relocation.offset..relocation.offset + 4]
+                .copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    let entry = addrs[entry.section] + entry.offset as u64;
+    let mut out = vec![0; EHDR_SIZE];
+    let mut segment = |kind: u32, flags: u32, offset: usize, filesz: usize, memsz: usize| {
+        out.extend(kind.to_le_bytes());
+        out.extend(flags.to_le_bytes());
+        out.extend((offset as u64).to_le_bytes());
+        let addr = if kind == PT_LOAD {
+            BASE_ADDRESS + offset as u64
+        } else {
+            0
+        };
+        out.extend(addr.to_le_bytes());
+        out.extend(addr.to_le_bytes());
+        out.extend((filesz as u64).to_le_bytes());
+        out.extend((memsz as u64).to_le_bytes());
+        let align = if kind == PT_LOAD {
+            PAGE_SIZE as u64
+        } else {
+            16
+        };
+        out.extend(align.to_le_bytes());
+    };
+    // The code segment also maps the headers
+    let text_end = offsets[TEXT] + text.len();
+    segment(PT_LOAD, PF_R | PF_X, 0, text_end, text_end);
+    if has_rodata {
+        segment(PT_LOAD, PF_R, offsets[RODATA], rodata.len(), rodata.len());
+    }
+    if has_data {
+        let memsz = offsets[BSS] + bss.len() - offsets[DATA];
+        segment(PT_LOAD, PF_R | PF_W, offsets[DATA], data.len(), memsz);
+    }
+    segment(PT_GNU_STACK, PF_R | PF_W, 0, 0, 0);
+
+    let mut headers = vec![];
+    for (i, section) in sections.iter().enumerate() {
+        let (kind, flags) = section_kind(i);
+        if kind != SHT_NOBITS {
+            out.resize(offsets[i], 0);
+            out.extend(section);
+        }
+        let mut header = SectionHeader::new(
+            SECTION_NAMES[i],
+            kind,
+            flags,
+            offsets[i],
+            object.sections[i].len(),
+        );
+        header.addr = addrs[i];
+        header.align = SECTION_ALIGN as u64;
+        headers.push(header);
+    }
+
+    let symbols = symbol_table(object, &addrs, false);
+    let shnum = headers.len() + 4;
+    let shoff = write_tables(&mut out, headers, symbols);
+    let mut header = vec![];
+    write_header(&mut header, ET_EXEC, entry, phnum, shoff, shnum);
+    out[..EHDR_SIZE].copy_from_slice(&header);
+    Ok(out)
+}
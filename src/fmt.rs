@@ -0,0 +1,231 @@
+use crate::parser::{tokenize, Token, TokenKind};
+
+const INDENT: &str = "  ";
+
+fn check_brackets(tokens: &[Token]) -> Result<(), String> {
+    let mut open: Vec<&Token> = vec![];
+    for token in tokens {
+        match token.kind {
+            TokenKind::Command('[') => open.push(token),
+            TokenKind::Command(']') if open.pop().is_none() => {
+                return Err(format!(
+                    "Error: Unmatched ']' at line {}, column {}",
+                    token.line, token.column
+                ));
+            }
+            _ => (),
+        }
+    }
+    match open.pop() {
+        Some(token) => Err(format!(
+            "Error: Unmatched '[' at line {}, column {}",
+            token.line, token.column
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Strips comments and cancels adjacent `+-` and `<>` pairs. The result is
+/// one line ending in a newline, even for programs without commands.
+pub fn minify(src: &str) -> Result<String, String> {
+    let tokens = tokenize(src);
+    check_brackets(&tokens)?;
+
+    let mut out = String::new();
+    // Net effect of the current run of `+`/`-` or `<`/`>` commands
+    let mut data_delta: isize = 0;
+    let mut pointer_delta: isize = 0;
+
+    fn flush(out: &mut String, delta: &mut isize, inc: &str, dec: &str) {
+        if *delta > 0 {
+            out.push_str(&inc.repeat(*delta as usize));
+        } else {
+            out.push_str(&dec.repeat((-*delta) as usize));
+        }
+        *delta = 0;
+    }
+
+    for token in &tokens {
+        if let TokenKind::Command(c) = token.kind {
+            match c {
+                '+' | '-' => {
+                    flush(&mut out, &mut pointer_delta, ">", "<");
+                    data_delta += if c == '+' { 1 } else { -1 };
+                }
+                '>' | '<' => {
+                    flush(&mut out, &mut data_delta, "+", "-");
+                    pointer_delta += if c == '>' { 1 } else { -1 };
+                }
+                _ => {
+                    flush(&mut out, &mut data_delta, "+", "-");
+                    flush(&mut out, &mut pointer_delta, ">", "<");
+                    out.push(c);
+                }
+            }
+        }
+    }
+    flush(&mut out, &mut data_delta, "+", "-");
+    flush(&mut out, &mut pointer_delta, ">", "<");
+
+    out.push('\n');
+    Ok(out)
+}
+
+/// Indents loop nesting, packs commands into lines of at most `width`
+/// characters and puts each line of comment text on its own line. Loops without
+/// nested loops or comments stay inline when they fit.
+pub fn format(src: &str, width: usize) -> Result<String, String> {
+    struct Formatter {
+        out: String,
+        line: String,
+        depth: usize,
+        width: usize,
+    }
+
+    impl Formatter {
+        fn flush_line(&mut self) {
+            if !self.line.is_empty() {
+                self.out.push_str(&INDENT.repeat(self.depth));
+                self.out.push_str(&self.line);
+                self.out.push('\n');
+                self.line.clear();
+            }
+        }
+
+        fn push_code(&mut self, code: &str) {
+            let indent_len = INDENT.len() * self.depth;
+            if !self.line.is_empty() && indent_len + self.line.len() + code.len() > self.width {
+                self.flush_line();
+            }
+            self.line.push_str(code);
+        }
+
+        fn push_own_line(&mut self, text: &str) {
+            self.flush_line();
+            self.out.push_str(&INDENT.repeat(self.depth));
+            self.out.push_str(text);
+            self.out.push('\n');
+        }
+    }
+
+    /// Returns the loop starting at `tokens[start]` as one string if it can be
+    /// kept on a single line
+    fn inline_loop(tokens: &[Token], start: usize) -> Option<String> {
+        let mut code = String::from("[");
+        for token in &tokens[start + 1..] {
+            match &token.kind {
+                TokenKind::Command('[') => return None,
+                TokenKind::Command(']') => {
+                    code.push(']');
+                    return Some(code);
+                }
+                TokenKind::Command(c) => code.push(*c),
+                TokenKind::Comment(text) => {
+                    if !text.trim().is_empty() {
+                        return None;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    let tokens = tokenize(src);
+    check_brackets(&tokens)?;
+
+    let mut formatter = Formatter {
+        out: String::new(),
+        line: String::new(),
+        depth: 0,
+        width,
+    };
+
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i].kind {
+            TokenKind::Command('[') => {
+                let fits =
+                    |code: &String| INDENT.len() * formatter.depth + code.len() <= formatter.width;
+                match inline_loop(&tokens, i).filter(fits) {
+                    Some(code) => {
+                        formatter.push_code(&code);
+                        // Skip to the matching `]`
+                        while tokens[i].kind != TokenKind::Command(']') {
+                            i += 1;
+                        }
+                    }
+                    None => {
+                        formatter.push_own_line("[");
+                        formatter.depth += 1;
+                    }
+                }
+            }
+            TokenKind::Command(']') => {
+                formatter.flush_line();
+                formatter.depth -= 1;
+                formatter.push_own_line("]");
+            }
+            TokenKind::Command(c) => formatter.push_code(&c.to_string()),
+            TokenKind::Comment(text) => {
+                for comment_line in text.lines().map(str::trim) {
+                    if !comment_line.is_empty() {
+                        formatter.push_own_line(comment_line);
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+    formatter.flush_line();
+
+    Ok(formatter.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "Add two cells\n,>, [<+>-] <.\n\n+- >< nothing\n[->+>[-]<<]\n";
+
+    #[test]
+    fn minify_strips_comments_and_cancels_pairs() {
+        assert_eq!(minify(SRC).unwrap(), ",>,[<+>-]<.[->+>[-]<<]\n");
+        assert_eq!(minify("+++--<<>>>.").unwrap(), "+>.\n");
+    }
+
+    #[test]
+    fn minify_ends_with_a_newline() {
+        assert_eq!(minify("").unwrap(), "\n");
+        assert_eq!(minify("only a comment").unwrap(), "\n");
+        // So minified files pass `fmt --check --minify`
+        let minified = minify(SRC).unwrap();
+        assert_eq!(minify(&minified).unwrap(), minified);
+    }
+
+    #[test]
+    fn format_indents_and_wraps() {
+        assert_eq!(
+            format(SRC, 80).unwrap(),
+            "Add two cells\n,>,[<+>-]<.+-><\nnothing\n[\n  ->+>[-]<<\n]\n"
+        );
+        assert_eq!(
+            format(SRC, 8).unwrap(),
+            "Add two cells\n,>,\n[<+>-]<.\n+-><\nnothing\n[\n  ->+>\n  [-]<<\n]\n"
+        );
+        // Formatting again changes nothing
+        let formatted = format(SRC, 8).unwrap();
+        assert_eq!(format(&formatted, 8).unwrap(), formatted);
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert_eq!(
+            minify("+\n[[-]").unwrap_err(),
+            "Error: Unmatched '[' at line 2, column 1"
+        );
+        assert_eq!(
+            format("+]", 80).unwrap_err(),
+            "Error: Unmatched ']' at line 1, column 2"
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Translates the x86-64 backend's AT&T syntax output into Intel syntax.
+//!
+//! Code is always generated in AT&T syntax, which is what the integrated
+//! assembler reads, and only the `.s` written for `--asm-syntax=intel` is
+//! translated. Only the instructions and operand forms the backend emits are
+//! handled. Directives, labels and comments are kept as they are.
+
+/// Instructions that take an operand size suffix in AT&T syntax
+const SUFFIXED: [&str; 22] = [
+    "mov", "add", "sub", "and", "or", "xor", "cmp", "test", "inc", "dec", "not", "neg", "mul",
+    "imul", "shl", "shr", "sar", "lea", "push", "pop", "bsf", "bsr",
+];
+
+/// Splits an instruction line into the instruction and its comment
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.find('#') {
+        Some(idx) => (&line[..idx], Some(&line[idx..])),
+        None => (line, None),
+    }
+}
+
+/// Splits operands at commas outside parentheses
+fn split_operands(operands: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (idx, c) in operands.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(operands[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(operands[start..].trim());
+    parts
+}
+
+fn register(operand: &str) -> Result<&str, String> {
+    operand
+        .strip_prefix('%')
+        .ok_or_else(|| format!("expected a register, found `{}`", operand))
+}
+
+/// `disp(base, index, scale)` or `symbol+disp(%rip)` as `[base + index*scale + disp]`
+fn memory(operand: &str, size: Option<&str>) -> Result<String, String> {
+    let open = operand.find('(').unwrap();
+    let displacement = operand[..open].trim();
+    let inner = operand[open + 1..]
+        .strip_suffix(')')
+        .ok_or_else(|| format!("invalid memory operand `{}`", operand))?;
+
+    let mut terms: Vec<String> = vec![];
+    let parts: Vec<&str> = inner.split(',').map(str::trim).collect();
+    if let Some(base) = parts.first().filter(|base| !base.is_empty()) {
+        terms.push(register(base)?.to_string());
+    }
+    if let Some(index) = parts.get(1) {
+        let scale = parts.get(2).copied().unwrap_or("1");
+        if scale == "1" {
+            terms.push(register(index)?.to_string());
+        } else {
+            terms.push(format!("{}*{}", register(index)?, scale));
+        }
+    }
+    let mut address = terms.join(" + ");
+    match displacement.parse::<i64>() {
+        Ok(0) if !address.is_empty() => (),
+        Ok(disp) if disp < 0 && !address.is_empty() => address.push_str(&format!(" - {}", -disp)),
+        _ if displacement.is_empty() => (),
+        _ if address.is_empty() => address.push_str(displacement),
+        _ => address.push_str(&format!(" + {}", displacement)),
+    }
+
+    Ok(match size {
+        Some(size) => format!("{} ptr [{}]", size, address),
+        None => format!("[{}]", address),
+    })
+}
+
+fn operand(operand: &str, size: Option<&str>) -> Result<String, String> {
+    if let Some(imm) = operand.strip_prefix('$') {
+        Ok(imm.to_string())
+    } else if operand.starts_with('%') {
+        Ok(register(operand)?.to_string())
+    } else if operand.contains('(') {
+        memory(operand, size)
+    } else {
+        // Branch and call targets
+        Ok(operand.to_string())
+    }
+}
+
+/// Intel mnemonic and the size of its memory operands
+fn mnemonic(att: &str) -> (String, Option<&'static str>) {
+    if att == "movzbl" {
+        return (String::from("movzx"), Some("byte"));
+    }
+    let size = match att.chars().last() {
+        Some('b') => Some("byte"),
+        Some('l') => Some("dword"),
+        Some('q') => Some("qword"),
+        _ => None,
+    };
+    let base = &att[..att.len() - 1];
+    match size {
+        // `lea` only computes the address, so its operand has no size
+        Some(_) if base == "lea" => (base.to_string(), None),
+        Some(_) if SUFFIXED.contains(&base) => (base.to_string(), size),
+        _ => (att.to_string(), None),
+    }
+}
+
+fn instruction(text: &str) -> Result<String, String> {
+    let (att_mnemonic, operands) = match text.split_once(char::is_whitespace) {
+        Some((mnemonic, operands)) => (mnemonic, operands.trim()),
+        None => (text, ""),
+    };
+    let (intel_mnemonic, size) = mnemonic(att_mnemonic);
+    if operands.is_empty() {
+        return Ok(intel_mnemonic);
+    }
+    // Intel syntax lists the operands in the opposite order
+    let operands = split_operands(operands)
+        .into_iter()
+        .rev()
+        .map(|op| operand(op, size))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("{} {}", intel_mnemonic, operands.join(", ")))
+}
+
+/// Returns `asm` in Intel syntax
+pub fn translate(asm: &str) -> Result<String, String> {
+    let mut out = String::from(".intel_syntax noprefix\n");
+    for (idx, line) in asm.lines().enumerate() {
+        let trimmed = line.trim_start();
+        // Directives, labels, comments and blank lines
+        if trimmed.is_empty()
+            || trimmed.starts_with('.')
+            || trimmed.starts_with('#')
+            || trimmed.ends_with(':')
+        {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+
+        let (text, comment) = split_comment(line);
+        let indent = &line[..line.len() - trimmed.len()];
+        let translated = instruction(text.trim()).map_err(|e| {
+            format!(
+                "Error: Translating line {} to Intel syntax: {}: `{}`",
+                idx + 1,
+                e,
+                line
+            )
+        })?;
+        let translated = format!("{}{}", indent, translated);
+        match comment {
+            // Keep comments lined up where they were
+            Some(comment) => out.push_str(&format!(
+                "{:width$}{}\n",
+                translated,
+                comment,
+                width = text.len().max(translated.len() + 1)
+            )),
+            None => {
+                out.push_str(&translated);
+                out.push('\n');
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::{LOWERINGS, TRAP};
+    use crate::compiler::{
+        compile, Artifact, AsmSyntax, CompileOptions, Destination, ExitCode, Simd, Target, TempDir,
+    };
+    use crate::ir::parse_ir;
+    use crate::optimizer::tests::{optimized_bf, HELLO};
+    use crate::optimizer::InfiniteLoopMode;
+
+    #[test]
+    fn translates_lines() {
+        let att = ".text\nloop1:\n    movb 0(%r12), %cl\n    movb %dl, -3(%r12)\n    \
+                   addb $8, %cl\n    leaq  output_buffer(%rip), %rbx\n    \
+                   movq  $0, output_len(%rip)\n    movb  %cl, (%rdi, %rax)\n    \
+                   movzbl 0(%r12), %edi\n    vpcmpeqb (%r12), %ymm0, %ymm1\n    \
+                   pushq %rax # Save\n    jmp  loop1\n    ret\n";
+        let intel = ".intel_syntax noprefix\n.text\nloop1:\n    mov cl, byte ptr [r12]\n    \
+                     mov byte ptr [r12 - 3], dl\n    add cl, 8\n    \
+                     lea rbx, [rip + output_buffer]\n    \
+                     mov qword ptr [rip + output_len], 0\n    \
+                     mov byte ptr [rdi + rax], cl\n    movzx edi, byte ptr [r12]\n    \
+                     vpcmpeqb ymm1, ymm0, [r12]\n    push rax   # Save\n    jmp loop1\n    ret\n";
+        assert_eq!(translate(att).unwrap(), intel);
+    }
+
+    #[test]
+    fn reports_untranslatable_lines() {
+        let error = translate("    nop\n    movb %cl, 0(r12)\n").unwrap_err();
+        assert_eq!(
+            error,
+            "Error: Translating line 2 to Intel syntax: expected a register, found `r12`: `    movb %cl, 0(r12)`"
+        );
+    }
+
+    /// Disassembly of `asm` assembled with `as`, with relocations
+    fn disassemble(asm: &str) -> String {
+        let dir = TempDir::new().unwrap();
+        let (asm_path, object_path) = (dir.file("a.s"), dir.file("a.o"));
+        std::fs::write(&asm_path, asm).unwrap();
+        let status = std::process::Command::new("as")
+            .arg("-o")
+            .arg(&object_path)
+            .arg(&asm_path)
+            .status()
+            .unwrap();
+        assert!(status.success(), "as failed on:\n{}", asm);
+        let output = std::process::Command::new("objdump")
+            .arg("-dr")
+            .arg(&object_path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn assembles_to_the_same_code() {
+        let programs = [
+            optimized_bf(HELLO, 3, InfiniteLoopMode::Trap),
+            optimized_bf("+[->>+<<]>>[<]>,[-->+<]>.", 1, InfiniteLoopMode::Ignore),
+            parse_ir(LOWERINGS).unwrap(),
+            parse_ir(TRAP).unwrap(),
+        ];
+        for commands in &programs {
+            for simd in [Simd::None, Simd::Sse2, Simd::Avx2, Simd::Auto] {
+                for freestanding in [false, true] {
+                    let dir = TempDir::new().unwrap();
+                    let listings: Vec<String> = [AsmSyntax::Att, AsmSyntax::Intel]
+                        .into_iter()
+                        .map(|asm_syntax| {
+                            let path = dir.file("a.s");
+                            let options = CompileOptions {
+                                target: Target::X86_64,
+                                simd,
+                                exit_code: ExitCode::Cell,
+                                freestanding,
+                                integrated_as: false,
+                                asm_syntax,
+                                source_map: None,
+                                debug_info: false,
+                                annotate: false,
+                                counts: false,
+                            };
+                            compile(
+                                commands,
+                                Artifact::Assembly,
+                                &Destination::File(path.clone()),
+                                &options,
+                            )
+                            .unwrap();
+                            disassemble(&std::fs::read_to_string(&path).unwrap())
+                        })
+                        .collect();
+                    assert_eq!(listings[0], listings[1], "{:?} {}", simd, freestanding);
+                }
+            }
+        }
+    }
+}
@@ -1,14 +1,25 @@
-use crate::parser::{Command, Direction, OutputType};
+use crate::parser::{divisor_mask, Command, Direction, OutputType};
+use std::io::{Read, Write};
 
 const INIT_TAPE_SIZE: usize = 0x200000;
 const INIT_POINTER_LOC: usize = 0x4000;
 
-pub fn interp(commands: &mut [Command]) {
+/// Runs the program on stdin and stdout and returns the value of the current
+/// cell when it ends
+pub fn interp(commands: &mut [Command]) -> u8 {
+    interp_io(commands, &mut std::io::stdin(), &mut std::io::stdout())
+}
+
+/// Runs the program reading from `input` and writing to `output`, and returns
+/// the value of the current cell when it ends
+pub fn interp_io(commands: &mut [Command], input: &mut dyn Read, output: &mut dyn Write) -> u8 {
     fn interp_rec(
         commands: &mut [Command],
         tape: &mut [u8],
         pointer: &mut usize,
         pc: &mut usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
     ) -> std::io::Result<()> {
         while *pc < commands.len() {
             match &mut commands[*pc] {
@@ -56,10 +67,11 @@ pub fn interp(commands: &mut [Command]) {
                     id: _,
                     direction,
                     skip_amount,
+                    offset,
                     ref mut count,
                 } => {
                     *count += 1;
-                    while tape[*pointer] != 0 {
+                    while tape[pointer.wrapping_add_signed(*offset)] != 0 {
                         match direction {
                             Direction::Left => *pointer -= *skip_amount,
                             Direction::Right => *pointer += *skip_amount,
@@ -104,8 +116,6 @@ pub fn interp(commands: &mut [Command]) {
                     out_type,
                     ref mut count,
                 } => {
-                    use std::io::Write;
-
                     *count += 1;
                     let buf: Vec<u8>;
                     match out_type {
@@ -114,22 +124,50 @@ pub fn interp(commands: &mut [Command]) {
                             buf = vec![tape[pointer.wrapping_add_signed(*offset)]]
                         }
                     }
-                    std::io::stdout().write_all(&buf)?;
+                    output.write_all(&buf)?;
                 }
                 Command::Input {
                     offset,
                     ref mut count,
                 } => {
-                    use std::io::Read;
-
                     *count += 1;
                     let mut input_buf: [u8; 1] = [0; 1];
-                    if let Err(..) = std::io::stdin().read_exact(&mut input_buf) {
+                    if let Err(..) = input.read_exact(&mut input_buf) {
                         tape[pointer.wrapping_add_signed(*offset)] = 255; // -1
                     } else {
                         tape[pointer.wrapping_add_signed(*offset)] = input_buf[0];
                     }
                 }
+                Command::InfiniteLoop {
+                    id,
+                    divisor,
+                    ref mut count,
+                } => {
+                    *count += 1;
+                    let cell = tape[*pointer];
+                    if cell != 0 {
+                        if divisor_mask(*divisor).is_some_and(|mask| cell & mask == 0) {
+                            tape[*pointer] = 0;
+                        } else {
+                            output.flush()?;
+                            eprintln!("infinite loop detected at loop {}", id);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                Command::If {
+                    body,
+                    id: _,
+                    ref mut count,
+                } => {
+                    *count += 1;
+                    if tape[*pointer] != 0 {
+                        let mut if_pc = 0;
+                        if let Err(e) = interp_rec(body, tape, pointer, &mut if_pc, input, output) {
+                            eprintln!("{}", e);
+                        }
+                    }
+                }
                 Command::Loop {
                     body,
                     id: _,
@@ -139,7 +177,8 @@ pub fn interp(commands: &mut [Command]) {
                     *start_count += 1;
                     while tape[*pointer] != 0 {
                         let mut loop_pc = 0;
-                        if let Err(e) = interp_rec(body, tape, pointer, &mut loop_pc) {
+                        if let Err(e) = interp_rec(body, tape, pointer, &mut loop_pc, input, output)
+                        {
                             eprintln!("{}", e);
                         }
 
@@ -159,7 +198,11 @@ pub fn interp(commands: &mut [Command]) {
     let mut tape: Vec<u8> = vec![0; INIT_TAPE_SIZE];
     let mut pointer = INIT_POINTER_LOC;
     let mut pc = 0;
-    if let Err(e) = interp_rec(commands, &mut tape, &mut pointer, &mut pc) {
+    if let Err(e) = interp_rec(commands, &mut tape, &mut pointer, &mut pc, input, output) {
         eprintln!("{}", e);
     };
+    if let Err(e) = output.flush() {
+        eprintln!("{}", e);
+    }
+    tape[pointer]
 }
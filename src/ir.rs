@@ -0,0 +1,625 @@
+//! Round-trippable textual form of the command tree.
+//!
+//! The IR is a whitespace separated token stream. Each command starts with a
+//! mnemonic followed by its operands; `;` starts a comment that runs to the end
+//! of the line. Offsets are written `@N` and are relative to the tape pointer.
+//!
+//! ```text
+//! ptr_inc N               IncPointer { amount: N }
+//! ptr_dec N               DecPointer { amount: N }
+//! inc @O N                IncData { offset: O, amount: N }
+//! dec @O N                DecData { offset: O, amount: N }
+//! set @O V                SetData { offset: O, value: V }
+//! scan [#ID] [@O] right|left N
+//!                         Scan { direction, skip_amount: N, offset: O }
+//! add_mul @D @S M [inv]   AddOffsetData { dest_offset: D, src_offset: S, multiplier: M, inverted }
+//! sub_mul @D @S M [inv]   SubOffsetData { dest_offset: D, src_offset: S, multiplier: M, inverted }
+//! out @O                  Output { out_type: Cell { offset: O } }
+//! out_const V             Output { out_type: Const(V) }
+//! in @O                   Input { offset: O }
+//! inf_loop [#ID] D        InfiniteLoop { id, divisor: D }, D is 0 or a power of two
+//! if [#ID] { ... }        If { id, body }
+//! loop [#ID] { ... }      Loop { id, body }
+//! ```
+//!
+//! Loop, if, scan and infinite loop ids are optional when writing IR by hand.
+//! Missing ids (or `#0`) are replaced by fresh ids larger than any id in the
+//! program, and an id given twice is an error, so the compiler still gets
+//! unique labels. A scan without an offset tests the cell under the pointer.
+//! Profiling counts are not part of the IR.
+
+use crate::parser::{is_valid_divisor, Command, Direction, OutputType};
+use std::collections::HashSet;
+
+pub fn write_ir(commands: &[Command]) -> String {
+    fn write_ir_rec(out: &mut String, commands: &[Command], indent_level: usize) {
+        let indent = "  ".repeat(indent_level);
+
+        for command in commands {
+            out.push_str(&indent);
+            match command {
+                Command::IncPointer { amount, .. } => {
+                    out.push_str(&format!("ptr_inc {}\n", amount));
+                }
+                Command::DecPointer { amount, .. } => {
+                    out.push_str(&format!("ptr_dec {}\n", amount));
+                }
+                Command::IncData { offset, amount, .. } => {
+                    out.push_str(&format!("inc @{} {}\n", offset, amount));
+                }
+                Command::DecData { offset, amount, .. } => {
+                    out.push_str(&format!("dec @{} {}\n", offset, amount));
+                }
+                Command::SetData { offset, value, .. } => {
+                    out.push_str(&format!("set @{} {}\n", offset, value));
+                }
+                Command::Scan {
+                    id,
+                    direction,
+                    skip_amount,
+                    offset,
+                    ..
+                } => {
+                    let direction_str = match direction {
+                        Direction::Left => "left",
+                        Direction::Right => "right",
+                    };
+                    out.push_str(&format!(
+                        "scan #{} @{} {} {}\n",
+                        id, offset, direction_str, skip_amount
+                    ));
+                }
+                Command::AddOffsetData {
+                    dest_offset,
+                    src_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    let inverted_str = if *inverted { " inv" } else { "" };
+                    out.push_str(&format!(
+                        "add_mul @{} @{} {}{}\n",
+                        dest_offset, src_offset, multiplier, inverted_str
+                    ));
+                }
+                Command::SubOffsetData {
+                    dest_offset,
+                    src_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    let inverted_str = if *inverted { " inv" } else { "" };
+                    out.push_str(&format!(
+                        "sub_mul @{} @{} {}{}\n",
+                        dest_offset, src_offset, multiplier, inverted_str
+                    ));
+                }
+                Command::Output { out_type, .. } => match out_type {
+                    OutputType::Const(val) => out.push_str(&format!("out_const {}\n", val)),
+                    OutputType::Cell { offset } => out.push_str(&format!("out @{}\n", offset)),
+                },
+                Command::Input { offset, .. } => {
+                    out.push_str(&format!("in @{}\n", offset));
+                }
+                Command::InfiniteLoop { id, divisor, .. } => {
+                    out.push_str(&format!("inf_loop #{} {}\n", id, divisor));
+                }
+                Command::If { id, body, .. } => {
+                    out.push_str(&format!("if #{} {{\n", id));
+                    write_ir_rec(out, body, indent_level + 1);
+                    out.push_str(&format!("{}}}\n", indent));
+                }
+                Command::Loop { id, body, .. } => {
+                    out.push_str(&format!("loop #{} {{\n", id));
+                    write_ir_rec(out, body, indent_level + 1);
+                    out.push_str(&format!("{}}}\n", indent));
+                }
+            }
+        }
+    }
+
+    let mut out = String::new();
+    write_ir_rec(&mut out, commands, 0);
+    out
+}
+
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    col: usize,
+}
+
+fn tokenize(src: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    for (line_idx, line) in src.lines().enumerate() {
+        let line = match line.find(';') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        let mut start: Option<usize> = None;
+        for (idx, c) in line
+            .char_indices()
+            .chain(std::iter::once((line.len(), ' ')))
+        {
+            if c.is_whitespace() {
+                if let Some(s) = start.take() {
+                    tokens.push(Token {
+                        text: &line[s..idx],
+                        line: line_idx + 1,
+                        col: s + 1,
+                    });
+                }
+            } else if start.is_none() {
+                start = Some(idx);
+            }
+        }
+    }
+    tokens
+}
+
+struct IrParser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    max_id: usize,
+    /// Ids given explicitly so far, which must be unique
+    ids: HashSet<usize>,
+}
+
+impl<'a> IrParser<'a> {
+    fn error(&self, msg: &str) -> String {
+        match self.tokens.get(self.pos).or(self.tokens.last()) {
+            Some(token) => format!(
+                "Error parsing IR at line {}, column {}: {}",
+                token.line, token.col, msg
+            ),
+            None => format!("Error parsing IR: {}", msg),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(|t| t.text)
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.text)
+            }
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, String> {
+        let text = self.next()?;
+        text.parse().map_err(|_| {
+            self.pos -= 1;
+            self.error(&format!("Expected {}, found `{}`", what, text))
+        })
+    }
+
+    fn offset(&mut self) -> Result<isize, String> {
+        let text = self.next()?;
+        match text.strip_prefix('@').map(|n| n.parse()) {
+            Some(Ok(offset)) => Ok(offset),
+            _ => {
+                self.pos -= 1;
+                Err(self.error(&format!("Expected offset `@N`, found `{}`", text)))
+            }
+        }
+    }
+
+    fn id(&mut self) -> Result<usize, String> {
+        match self.peek() {
+            Some(text) if text.starts_with('#') => {
+                let id = text[1..]
+                    .parse()
+                    .map_err(|_| self.error(&format!("Expected id `#N`, found `{}`", text)))?;
+                if id != 0 && !self.ids.insert(id) {
+                    return Err(self.error(&format!("Duplicate id `{}`", text)));
+                }
+                self.pos += 1;
+                self.max_id = self.max_id.max(id);
+                Ok(id)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn open_brace(&mut self, mnemonic: &str) -> Result<(), String> {
+        if self.next()? != "{" {
+            self.pos -= 1;
+            return Err(self.error(&format!("Expected `{{` after `{}`", mnemonic)));
+        }
+        Ok(())
+    }
+
+    fn inverted(&mut self) -> bool {
+        if self.peek() == Some("inv") {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_commands(&mut self, in_loop: bool) -> Result<Vec<Command>, String> {
+        let mut commands = vec![];
+        loop {
+            let mnemonic = match self.peek() {
+                Some("}") if in_loop => {
+                    self.pos += 1;
+                    return Ok(commands);
+                }
+                Some(mnemonic) => mnemonic,
+                None if in_loop => return Err(self.error("Unmatched `{`")),
+                None => return Ok(commands),
+            };
+            self.pos += 1;
+            let command = match mnemonic {
+                "ptr_inc" => Command::IncPointer {
+                    amount: self.number("pointer amount")?,
+                    count: 0,
+                },
+                "ptr_dec" => Command::DecPointer {
+                    amount: self.number("pointer amount")?,
+                    count: 0,
+                },
+                "inc" => Command::IncData {
+                    offset: self.offset()?,
+                    amount: self.number("byte amount")?,
+                    count: 0,
+                },
+                "dec" => Command::DecData {
+                    offset: self.offset()?,
+                    amount: self.number("byte amount")?,
+                    count: 0,
+                },
+                "set" => Command::SetData {
+                    offset: self.offset()?,
+                    value: self.number("byte value")?,
+                    count: 0,
+                },
+                "scan" => {
+                    let id = self.id()?;
+                    let offset = match self.peek() {
+                        Some(text) if text.starts_with('@') => self.offset()?,
+                        _ => 0,
+                    };
+                    let direction = match self.next()? {
+                        "left" => Direction::Left,
+                        "right" => Direction::Right,
+                        other => {
+                            self.pos -= 1;
+                            return Err(self
+                                .error(&format!("Expected `left` or `right`, found `{}`", other)));
+                        }
+                    };
+                    Command::Scan {
+                        id,
+                        direction,
+                        skip_amount: self.number("skip amount")?,
+                        offset,
+                        count: 0,
+                    }
+                }
+                "add_mul" => Command::AddOffsetData {
+                    dest_offset: self.offset()?,
+                    src_offset: self.offset()?,
+                    multiplier: self.number("multiplier")?,
+                    inverted: self.inverted(),
+                    count: 0,
+                },
+                "sub_mul" => Command::SubOffsetData {
+                    dest_offset: self.offset()?,
+                    src_offset: self.offset()?,
+                    multiplier: self.number("multiplier")?,
+                    inverted: self.inverted(),
+                    count: 0,
+                },
+                "out" => Command::Output {
+                    out_type: OutputType::Cell {
+                        offset: self.offset()?,
+                    },
+                    count: 0,
+                },
+                "out_const" => Command::Output {
+                    out_type: OutputType::Const(self.number("byte value")?),
+                    count: 0,
+                },
+                "in" => Command::Input {
+                    offset: self.offset()?,
+                    count: 0,
+                },
+                "inf_loop" => {
+                    let id = self.id()?;
+                    let divisor = self.number("divisor")?;
+                    if !is_valid_divisor(divisor) {
+                        self.pos -= 1;
+                        return Err(self.error(&format!(
+                            "Divisor must be 0 or a power of two, found `{}`",
+                            divisor
+                        )));
+                    }
+                    Command::InfiniteLoop {
+                        id,
+                        divisor,
+                        count: 0,
+                    }
+                }
+                "if" => {
+                    let id = self.id()?;
+                    self.open_brace(mnemonic)?;
+                    Command::If {
+                        id,
+                        body: self.parse_commands(true)?,
+                        count: 0,
+                    }
+                }
+                "loop" => {
+                    let id = self.id()?;
+                    self.open_brace(mnemonic)?;
+                    Command::Loop {
+                        id,
+                        body: self.parse_commands(true)?,
+                        start_count: 0,
+                        end_count: 0,
+                    }
+                }
+                "}" => {
+                    self.pos -= 1;
+                    return Err(self.error("Unmatched `}`"));
+                }
+                other => {
+                    self.pos -= 1;
+                    return Err(self.error(&format!("Unknown command `{}`", other)));
+                }
+            };
+            commands.push(command);
+        }
+    }
+}
+
+fn assign_missing_ids(commands: &mut [Command], next_id: &mut usize) {
+    for command in commands {
+        match command {
+            Command::Scan { id, .. } | Command::InfiniteLoop { id, .. } if *id == 0 => {
+                *next_id += 1;
+                *id = *next_id;
+            }
+            Command::If { id, body, .. } | Command::Loop { id, body, .. } => {
+                if *id == 0 {
+                    *next_id += 1;
+                    *id = *next_id;
+                }
+                assign_missing_ids(body, next_id);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn collect_ids(commands: &[Command], ids: &mut HashSet<usize>) -> Result<(), usize> {
+    for command in commands {
+        let id = match command {
+            Command::Scan { id, .. }
+            | Command::InfiniteLoop { id, .. }
+            | Command::If { id, .. }
+            | Command::Loop { id, .. } => *id,
+            _ => continue,
+        };
+        if id != 0 && !ids.insert(id) {
+            return Err(id);
+        }
+        if let Command::If { body, .. } | Command::Loop { body, .. } = command {
+            collect_ids(body, ids)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the ids in a command tree read from elsewhere are unique, and
+/// numbers the commands without one like `parse_ir` does. Returns the
+/// duplicated id on failure.
+pub fn assign_ids(commands: &mut [Command]) -> Result<(), usize> {
+    let mut ids = HashSet::new();
+    collect_ids(commands, &mut ids)?;
+    let mut next_id = ids.into_iter().max().unwrap_or(0);
+    assign_missing_ids(commands, &mut next_id);
+    Ok(())
+}
+
+pub fn parse_ir(src: &str) -> Result<Vec<Command>, String> {
+    let mut parser = IrParser {
+        tokens: tokenize(src),
+        pos: 0,
+        max_id: 0,
+        ids: HashSet::new(),
+    };
+    let mut commands = parser.parse_commands(false)?;
+    let mut next_id = parser.max_id;
+    assign_missing_ids(&mut commands, &mut next_id);
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::tests::{optimized_bf, HELLO};
+    use crate::optimizer::InfiniteLoopMode;
+
+    fn optimized(src: &str, level: u8) -> Vec<Command> {
+        optimized_bf(src, level, InfiniteLoopMode::Trap)
+    }
+
+    #[test]
+    fn round_trips() {
+        let programs = [
+            HELLO,
+            "+[->,+[-<+>]<]>[-]++[>+++<-]>[<<+>>-]<<.",
+            "+++[>[-]>>+<<<-]>[>>]<<[<<]+[--]-[>+<---]",
+        ];
+        for src in programs {
+            for level in 0..=3 {
+                let ir = write_ir(&optimized(src, level));
+                assert_eq!(
+                    write_ir(&parse_ir(&ir).unwrap()),
+                    ir,
+                    "{} at -O{}",
+                    src,
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn golden_hello_world() {
+        assert_eq!(
+            write_ir(&optimized(HELLO, 3)),
+            "\
+inc @0 8
+loop #3 {
+  ptr_inc 1
+  inc @0 4
+  add_mul @1 @0 2
+  add_mul @2 @0 3
+  add_mul @3 @0 3
+  add_mul @4 @0 1
+  set @0 0
+  ptr_inc 1
+  inc @0 1
+  ptr_inc 1
+  inc @0 1
+  ptr_inc 1
+  dec @0 1
+  ptr_inc 2
+  inc @0 1
+  scan #2 @0 left 1
+  ptr_dec 1
+  dec @0 1
+}
+ptr_inc 2
+out @0
+ptr_inc 1
+dec @0 3
+out @0
+inc @0 7
+out @0
+out @0
+inc @0 3
+out @0
+ptr_inc 2
+out @0
+ptr_dec 1
+dec @0 1
+out @0
+ptr_dec 1
+out @0
+inc @0 3
+out @0
+dec @0 6
+out @0
+dec @0 8
+out @0
+ptr_inc 2
+inc @0 1
+out @0
+ptr_inc 1
+inc @0 2
+out @0
+"
+        );
+    }
+
+    #[test]
+    fn hand_written() {
+        let ir = "\
+; every command, without ids
+ptr_inc 2 ptr_dec 1
+inc @0 3  dec @-1 4  set @2 255
+scan left 1
+scan @3 right 2
+add_mul @1 @0 3
+sub_mul @-2 @0 5 inv
+out @1 out_const 72 in @0
+inf_loop 2
+if { loop #7 { dec @0 1 } }
+";
+        assert_eq!(
+            write_ir(&parse_ir(ir).unwrap()),
+            "\
+ptr_inc 2
+ptr_dec 1
+inc @0 3
+dec @-1 4
+set @2 255
+scan #8 @0 left 1
+scan #9 @3 right 2
+add_mul @1 @0 3
+sub_mul @-2 @0 5 inv
+out @1
+out_const 72
+in @0
+inf_loop #10 2
+if #11 {
+  loop #7 {
+    dec @0 1
+  }
+}
+"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("inc @0", "line 1, column 5: Unexpected end of input"),
+            (
+                "inc 0 1",
+                "line 1, column 5: Expected offset `@N`, found `0`",
+            ),
+            (
+                "ptr_inc x",
+                "line 1, column 9: Expected pointer amount, found `x`",
+            ),
+            (
+                "inc @0 256",
+                "line 1, column 8: Expected byte amount, found `256`",
+            ),
+            (
+                "scan up 1",
+                "line 1, column 6: Expected `left` or `right`, found `up`",
+            ),
+            (
+                "loop inc @0 1",
+                "line 1, column 6: Expected `{` after `loop`",
+            ),
+            ("loop {\n  out @0\n", "line 2, column 7: Unmatched `{`"),
+            ("out @0 }", "line 1, column 8: Unmatched `}`"),
+            ("jump @0", "line 1, column 1: Unknown command `jump`"),
+            (
+                "loop #x { }",
+                "line 1, column 6: Expected id `#N`, found `#x`",
+            ),
+            (
+                "loop #5 { }\nloop #5 { }",
+                "line 2, column 6: Duplicate id `#5`",
+            ),
+            (
+                "set @0 3\ninf_loop 3\n",
+                "line 2, column 10: Divisor must be 0 or a power of two, found `3`",
+            ),
+        ];
+        for (ir, error) in cases {
+            assert_eq!(
+                parse_ir(ir).unwrap_err(),
+                format!("Error parsing IR at {}", error),
+                "{:?}",
+                ir
+            );
+        }
+        assert!(parse_ir("inf_loop 0\ninf_loop 1\ninf_loop 64\n").is_ok());
+    }
+}
@@ -0,0 +1,114 @@
+//! JSON form of the command tree.
+//!
+//! The document is an object with a schema `version` and the top level
+//! `commands`. Each command is an object tagged by `op`, whose remaining keys
+//! are the fields of the matching `Command` variant:
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "commands": [
+//!     { "op": "inc_data", "offset": 0, "amount": 8, "count": 1 },
+//!     { "op": "loop", "id": 1, "start_count": 8, "end_count": 8, "body": [
+//!       { "op": "scan", "id": 2, "direction": "left", "skip_amount": 1, "count": 8 },
+//!       { "op": "add_offset_data", "dest_offset": 1, "src_offset": 0,
+//!         "multiplier": 4, "inverted": true, "count": 8 },
+//!       { "op": "output", "out_type": { "const": 72 }, "count": 8 },
+//!       { "op": "output", "out_type": { "cell": { "offset": 0 } }, "count": 8 }
+//!     ] }
+//!   ]
+//! }
+//! ```
+//!
+//! Counts are zero unless the program was run with `--profile`. Ids must be
+//! unique, and commands with an id of 0 are given fresh ones as in the IR.
+
+use crate::ir::assign_ids;
+use crate::parser::Command;
+use serde::{Deserialize, Serialize};
+
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Document {
+    version: u32,
+    commands: Vec<Command>,
+}
+
+pub fn write_json(commands: &[Command]) -> String {
+    let document = Document {
+        version: SCHEMA_VERSION,
+        commands: commands.to_vec(),
+    };
+    serde_json::to_string_pretty(&document).expect("Command tree is always serializable")
+}
+
+pub fn parse_json(src: &str) -> Result<Vec<Command>, String> {
+    let document: Document =
+        serde_json::from_str(src).map_err(|e| format!("Error parsing JSON: {}", e))?;
+    if document.version != SCHEMA_VERSION {
+        return Err(format!(
+            "Error parsing JSON: Unsupported schema version {} (expected {})",
+            document.version, SCHEMA_VERSION
+        ));
+    }
+    let mut commands = document.commands;
+    assign_ids(&mut commands)
+        .map_err(|id| format!("Error parsing JSON: Duplicate id `#{}`", id))?;
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{parse_ir, write_ir};
+
+    #[test]
+    fn rejects_divisors_that_are_not_powers_of_two() {
+        let json = r#"{ "version": 1, "commands": [
+            { "op": "infinite_loop", "id": 1, "divisor": 6, "count": 0 }
+        ] }"#;
+        let error = parse_json(json).unwrap_err();
+        assert!(error.contains("divisor must be 0 or a power of two, found 6"));
+        assert!(parse_json(&json.replace('6', "8")).is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let json = r#"{ "version": 1, "commands": [
+            { "op": "loop", "id": 1, "start_count": 0, "end_count": 0, "body": [
+                { "op": "scan", "id": 0, "direction": "left", "skip_amount": 1, "count": 0 }
+            ] },
+            { "op": "loop", "id": 1, "start_count": 0, "end_count": 0, "body": [] }
+        ] }"#;
+        let error = parse_json(json).unwrap_err();
+        assert_eq!(error, "Error parsing JSON: Duplicate id `#1`");
+
+        // Commands without an id get fresh ones, as in the IR
+        let commands = parse_json(&json.replacen("\"id\": 1", "\"id\": 4", 1)).unwrap();
+        assert_eq!(
+            write_ir(&commands),
+            "loop #4 {\n  scan #5 @0 left 1\n}\nloop #1 {\n}\n"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let ir = "inc @0 8\nloop #3 {\n  scan #1 @2 left 3\n  add_mul @1 @0 4 inv\n  \
+                  out_const 72\n  out @-1\n  if #2 {\n    inf_loop #4 2\n  }\n}\n";
+        let commands = parse_ir(ir).unwrap();
+        let json = write_json(&commands);
+        assert_eq!(write_ir(&parse_json(&json).unwrap()), ir);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let error = parse_json(r#"{ "version": 2, "commands": [] }"#).unwrap_err();
+        assert_eq!(
+            error,
+            "Error parsing JSON: Unsupported schema version 2 (expected 1)"
+        );
+        let error = parse_json(r#"{ "version": 1, "commands": [{ "op": "jump" }] }"#).unwrap_err();
+        assert!(error.starts_with("Error parsing JSON: unknown variant `jump`"));
+    }
+}
@@ -0,0 +1,397 @@
+use crate::parser::{tokenize, TokenKind};
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Warning {
+    pub line: usize,
+    pub column: usize,
+    /// Short stable name of the check, e.g. `infinite-loop`
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Source commands nested by loop, keeping their positions
+enum Node {
+    Op {
+        c: char,
+        line: usize,
+        column: usize,
+    },
+    Loop {
+        body: Vec<Node>,
+        line: usize,
+        column: usize,
+    },
+}
+
+impl Node {
+    fn position(&self) -> (usize, usize) {
+        match self {
+            Node::Op { line, column, .. } | Node::Loop { line, column, .. } => (*line, *column),
+        }
+    }
+}
+
+fn build_tree(src: &str) -> Result<Vec<Node>, String> {
+    let mut stack: Vec<(Vec<Node>, usize, usize)> = vec![(vec![], 0, 0)];
+    for token in tokenize(src) {
+        match token.kind {
+            TokenKind::Command('[') => stack.push((vec![], token.line, token.column)),
+            TokenKind::Command(']') => {
+                if stack.len() == 1 {
+                    return Err(format!(
+                        "Error: Unmatched ']' at line {}, column {}",
+                        token.line, token.column
+                    ));
+                }
+                let (body, line, column) = stack.pop().unwrap();
+                let parent = &mut stack.last_mut().unwrap().0;
+                parent.push(Node::Loop { body, line, column });
+            }
+            TokenKind::Command(c) => stack.last_mut().unwrap().0.push(Node::Op {
+                c,
+                line: token.line,
+                column: token.column,
+            }),
+            TokenKind::Comment(_) => (),
+        }
+    }
+    if stack.len() > 1 {
+        let (_, line, column) = stack.pop().unwrap();
+        return Err(format!(
+            "Error: Unmatched '[' at line {}, column {}",
+            line, column
+        ));
+    }
+    Ok(stack.pop().unwrap().0)
+}
+
+/// Effect of a loop body without nested loops
+struct FlatSummary {
+    pointer_delta: isize,
+    /// Net change of the loop cell, modulo 256
+    cell_delta: u8,
+    /// The loop cell is overwritten by input
+    reads_cell: bool,
+}
+
+fn summarize_flat(body: &[Node]) -> Option<FlatSummary> {
+    let mut summary = FlatSummary {
+        pointer_delta: 0,
+        cell_delta: 0,
+        reads_cell: false,
+    };
+    for node in body {
+        match node {
+            Node::Op { c: '>', .. } => summary.pointer_delta += 1,
+            Node::Op { c: '<', .. } => summary.pointer_delta -= 1,
+            Node::Op { c: '+', .. } if summary.pointer_delta == 0 => {
+                summary.cell_delta = summary.cell_delta.wrapping_add(1)
+            }
+            Node::Op { c: '-', .. } if summary.pointer_delta == 0 => {
+                summary.cell_delta = summary.cell_delta.wrapping_sub(1)
+            }
+            Node::Op { c: ',', .. } if summary.pointer_delta == 0 => summary.reads_cell = true,
+            Node::Op { .. } => (),
+            Node::Loop { .. } => return None,
+        }
+    }
+    Some(summary)
+}
+
+/// Whether every iteration of the loop leaves the pointer where it started
+fn is_balanced(body: &[Node]) -> bool {
+    let mut pointer_delta = 0;
+    for node in body {
+        match node {
+            Node::Op { c: '>', .. } => pointer_delta += 1,
+            Node::Op { c: '<', .. } => pointer_delta -= 1,
+            Node::Op { .. } => (),
+            Node::Loop { body, .. } => {
+                if !is_balanced(body) {
+                    return false;
+                }
+            }
+        }
+    }
+    pointer_delta == 0
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// What is known about the tape while walking straight-line code
+struct State {
+    /// Pointer relative to the point where cell knowledge was last reset
+    pointer: isize,
+    /// Absolute position of `pointer == 0` relative to the starting cell, if known
+    origin: Option<isize>,
+    /// Cells mapped to `None` are unknown
+    cells: HashMap<isize, Option<u8>>,
+    /// Cells missing from `cells` are zero rather than unknown
+    default_zero: bool,
+}
+
+impl State {
+    fn cell(&self, offset: isize) -> Option<u8> {
+        match self.cells.get(&offset) {
+            Some(val) => *val,
+            None if self.default_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn forget_cells(&mut self) {
+        self.cells.clear();
+        self.default_zero = false;
+    }
+
+    fn lose_pointer(&mut self) {
+        self.forget_cells();
+        self.pointer = 0;
+        self.origin = None;
+    }
+}
+
+fn lint_body(body: &[Node], state: &mut State, warnings: &mut Vec<Warning>) {
+    for (idx, node) in body.iter().enumerate() {
+        match node {
+            Node::Op { c, line, column } => match c {
+                '>' => state.pointer += 1,
+                '<' => {
+                    state.pointer -= 1;
+                    if state.origin.map(|o| o + state.pointer) == Some(-1) {
+                        warnings.push(Warning {
+                            line: *line,
+                            column: *column,
+                            code: "negative-pointer",
+                            message: "pointer moves left of the starting cell".to_string(),
+                        });
+                    }
+                }
+                '+' | '-' => {
+                    let new_val = state.cell(state.pointer).map(|val| {
+                        if *c == '+' {
+                            val.wrapping_add(1)
+                        } else {
+                            val.wrapping_sub(1)
+                        }
+                    });
+                    state.cells.insert(state.pointer, new_val);
+                }
+                ',' => {
+                    state.cells.insert(state.pointer, None);
+                }
+                _ => (),
+            },
+            Node::Loop {
+                body: loop_body,
+                line,
+                column,
+            } => {
+                let entry_value = state.cell(state.pointer);
+                let balanced = is_balanced(loop_body);
+                let mut never_terminates = false;
+
+                // Loops that are never entered can't misbehave
+                let summary = summarize_flat(loop_body).filter(|_| entry_value != Some(0));
+                if let Some(summary) = summary {
+                    if loop_body.is_empty() {
+                        warnings.push(Warning {
+                            line: *line,
+                            column: *column,
+                            code: "infinite-loop",
+                            message: "empty loop never terminates if the current cell is non-zero"
+                                .to_string(),
+                        });
+                        never_terminates = true;
+                    } else if summary.pointer_delta == 0 && !summary.reads_cell {
+                        if summary.cell_delta == 0 {
+                            warnings.push(Warning {
+                                line: *line,
+                                column: *column,
+                                code: "infinite-loop",
+                                message: "loop never changes its own cell, so it never terminates if entered"
+                                    .to_string(),
+                            });
+                            never_terminates = true;
+                        } else if summary.cell_delta % 2 == 0 {
+                            let step = gcd(summary.cell_delta as usize, 256);
+                            // Loops entered with a known multiple of the step end
+                            let terminates =
+                                entry_value.is_some_and(|val| (val as usize).is_multiple_of(step));
+                            if !terminates {
+                                warnings.push(Warning {
+                                    line: *line,
+                                    column: *column,
+                                    code: "even-step",
+                                    message: format!(
+                                        "loop changes its cell by an even step, so it never terminates unless the cell is a multiple of {}",
+                                        step
+                                    ),
+                                });
+                                never_terminates = entry_value.is_some();
+                            }
+                        }
+                    } else if summary.pointer_delta != 0 && summary.cell_delta != 0 {
+                        warnings.push(Warning {
+                            line: *line,
+                            column: *column,
+                            code: "unbalanced-loop",
+                            message: format!(
+                                "loop changes its own cell but moves the pointer by {} per iteration",
+                                summary.pointer_delta
+                            ),
+                        });
+                    }
+                }
+
+                // The body runs with an unknown non-zero current cell
+                let mut body_state = State {
+                    pointer: 0,
+                    origin: if balanced {
+                        state.origin.map(|o| o + state.pointer)
+                    } else {
+                        None
+                    },
+                    cells: HashMap::new(),
+                    default_zero: false,
+                };
+                if entry_value != Some(0) {
+                    lint_body(loop_body, &mut body_state, warnings);
+                }
+
+                if never_terminates && entry_value.is_some_and(|val| val != 0) {
+                    if let Some(next) = body.get(idx + 1) {
+                        let (next_line, next_column) = next.position();
+                        warnings.push(Warning {
+                            line: next_line,
+                            column: next_column,
+                            code: "unreachable",
+                            message: format!(
+                                "unreachable code after the infinite loop at line {}, column {}",
+                                line, column
+                            ),
+                        });
+                    }
+                    return;
+                }
+
+                if entry_value != Some(0) {
+                    if balanced {
+                        state.forget_cells();
+                    } else {
+                        state.lose_pointer();
+                    }
+                }
+                // A loop always exits on a zero cell
+                state.cells.insert(state.pointer, Some(0));
+            }
+        }
+    }
+}
+
+/// Flags command characters that are glued to words, such as the `.` in
+/// `hello world.`, which are executed even though they read as prose
+fn lint_comments(src: &str, warnings: &mut Vec<Warning>) {
+    let tokens = tokenize(src);
+    for (idx, token) in tokens.iter().enumerate() {
+        let TokenKind::Command(c) = token.kind else {
+            continue;
+        };
+        let after_word = match idx.checked_sub(1).map(|i| &tokens[i].kind) {
+            Some(TokenKind::Comment(text)) => {
+                text.chars().last().is_some_and(char::is_alphanumeric)
+            }
+            _ => false,
+        };
+        let before_word = match tokens.get(idx + 1).map(|t| &t.kind) {
+            Some(TokenKind::Comment(text)) => {
+                text.chars().next().is_some_and(char::is_alphanumeric)
+            }
+            _ => false,
+        };
+        if after_word || before_word {
+            warnings.push(Warning {
+                line: token.line,
+                column: token.column,
+                code: "command-in-comment",
+                message: format!("comment text contains the command character '{}'", c),
+            });
+        }
+    }
+}
+
+pub fn lint(src: &str) -> Result<Vec<Warning>, String> {
+    let tree = build_tree(src)?;
+    let mut warnings = vec![];
+
+    let mut state = State {
+        pointer: 0,
+        origin: Some(0),
+        cells: HashMap::new(),
+        default_zero: true,
+    };
+    lint_body(&tree, &mut state, &mut warnings);
+    lint_comments(src, &mut warnings);
+
+    warnings.sort_by_key(|w| (w.line, w.column));
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Position and code of each warning for `src`
+    fn codes(src: &str) -> Vec<(usize, usize, &'static str)> {
+        lint(src)
+            .unwrap()
+            .into_iter()
+            .map(|w| (w.line, w.column, w.code))
+            .collect()
+    }
+
+    #[test]
+    fn warnings() {
+        assert_eq!(
+            codes("+[]<"),
+            [(1, 2, "infinite-loop"), (1, 4, "unreachable")]
+        );
+        assert_eq!(codes("+\n [>+<]"), [(2, 2, "infinite-loop")]);
+        assert_eq!(
+            codes("+[--]."),
+            [(1, 2, "even-step"), (1, 6, "unreachable")]
+        );
+        // The cell might be even, so the code after the loop can run
+        assert_eq!(codes(",[--]."), [(1, 2, "even-step")]);
+        // Cells known to be a multiple of the step are cleared by the loop
+        assert_eq!(codes("++[--]."), []);
+        assert_eq!(codes("++++[----]+[--]"), [(1, 12, "even-step")]);
+        assert_eq!(codes("<"), [(1, 1, "negative-pointer")]);
+        // The scan can end anywhere, so the pointer is no longer tracked
+        assert_eq!(codes(">[<]<"), []);
+        assert_eq!(codes("+[->]"), [(1, 2, "unbalanced-loop")]);
+        assert_eq!(codes("hello world."), [(1, 12, "command-in-comment")]);
+        // Loops that are never entered are left alone
+        assert_eq!(codes("[]+[-]"), []);
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert_eq!(
+            lint("[[]").unwrap_err(),
+            "Error: Unmatched '[' at line 1, column 1"
+        );
+        assert_eq!(
+            lint("+[-]]").unwrap_err(),
+            "Error: Unmatched ']' at line 1, column 5"
+        );
+    }
+}
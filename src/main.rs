@@ -1,19 +1,131 @@
+mod aarch64;
+mod assembler;
+mod bf;
+mod c;
 mod compiler;
+mod debuginfo;
+mod elf;
+mod fmt;
+mod intel;
 mod interp;
+mod ir;
+mod json;
+mod lint;
 mod optimizer;
 mod parser;
 mod partial;
 mod profiler;
+mod riscv64;
+mod rust;
+mod wasm;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum InputFormat {
+    /// Brainfuck source
+    Bf,
+    /// Textual IR, as written by `--emit=ir`
+    Ir,
+    /// JSON, as written by `--emit=json`
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Emit {
+    /// Textual IR of the optimized program
+    Ir,
+    /// JSON command tree, including profiling counts when run with `--profile`
+    Json,
+    /// Plain Brainfuck equivalent to the optimized program
+    Bf,
+    /// Self-contained C program
+    C,
+    /// Rust source with a reusable `run` function and a `main`
+    Rust,
+    /// WebAssembly text format for WASI runtimes
+    Wat,
+    /// WebAssembly binary module for WASI runtimes
+    Wasm,
+    /// Assembly for the target
+    Asm,
+    /// Object file
+    Obj,
+    /// Executable, the default when compiling
+    Exe,
+}
+
+impl Emit {
+    /// What to compile the program to, for the formats that need a backend
+    fn artifact(self) -> Option<compiler::Artifact> {
+        match self {
+            Emit::Asm => Some(compiler::Artifact::Assembly),
+            Emit::Obj => Some(compiler::Artifact::Object),
+            Emit::Exe => Some(compiler::Artifact::Executable),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum SubCommand {
+    /// Format or minify Brainfuck source files
+    Fmt(FmtArgs),
+    /// Warn about likely bugs in Brainfuck source files
+    Lint(LintArgs),
+}
+
+#[derive(clap::Args)]
+struct LintArgs {
+    /// Source files
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Print warnings as a JSON array instead of `file:line:column: warning: ...` lines
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct FmtArgs {
+    /// Source files
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Strip comments and cancel adjacent `+-` and `<>` pairs instead of formatting
+    #[arg(short, long)]
+    minify: bool,
+
+    /// Maximum line width when formatting
+    #[arg(short, long, default_value_t = 80)]
+    width: usize,
+
+    /// Don't print anything, exit with status 1 if any file would change
+    #[arg(long)]
+    check: bool,
+}
 
 #[derive(Parser)]
 #[command(name = "bfr")]
 #[command(version = "1.0")]
 #[command(about = "A simple Brainfuck interpreter written in Rust", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Source file
-    file_name: String,
+    #[command(subcommand)]
+    command: Option<SubCommand>,
+
+    /// Source file, or `-` for stdin
+    #[arg(required = true)]
+    file_name: Option<String>,
+
+    /// Format of the source file
+    #[arg(long = "input-format", value_enum, default_value_t = InputFormat::Bf)]
+    input_format: InputFormat,
+
+    /// What to produce. `asm`, `obj` and `exe` compile the program, the other formats
+    /// translate it instead of running or compiling it
+    #[arg(long, value_enum)]
+    emit: Option<Emit>,
 
     /// Enable profiler. Also enables interpretation
     #[arg(short = 'p', long)]
@@ -23,48 +135,271 @@ struct Args {
     #[arg(short = 'P', long)]
     pretty_print: bool,
 
-    /// Name of the output file
-    #[arg(short, long = "output", value_name = "FILE", default_value_t = String::from("a.out"))]
-    out_file: String,
+    /// Name of the output file, or `-` for stdout. Defaults to `a.out` for executables,
+    /// `<stem>.s` and `<stem>.o` for assembly and object files, and stdout otherwise
+    #[arg(short, long = "output", value_name = "FILE")]
+    out_file: Option<String>,
 
     /// Interpret source file without compiling
     #[arg(short, long)]
     interp: bool,
 
-    /// Output assembly file
-    #[arg(long = "no-binary")]
+    /// Output assembly file, like `--emit=asm`
+    #[arg(short = 'S', long = "no-binary", conflicts_with = "emit")]
     no_binary: bool,
 
-    /// Output object file
-    #[arg(short = 'c', long = "object")]
+    /// Output object file, like `--emit=obj`
+    #[arg(short = 'c', long = "object", conflicts_with_all = ["emit", "no_binary"])]
     output_object: bool,
 
     /// Optimization level (0-3)
     #[arg(short = 'O', default_value_t = 1)]
     optimization_level: u8,
 
+    /// Architecture to compile for
+    #[arg(long, value_enum, default_value_t = compiler::Target::X86_64)]
+    target: compiler::Target,
+
+    /// Vector instructions used for scans. Overrides `--target-cpu`
+    #[arg(long, value_enum)]
+    simd: Option<compiler::Simd>,
+
+    /// CPU to generate code for. Defaults to one with AVX2
+    #[arg(long = "target-cpu", value_name = "CPU", value_enum)]
+    target_cpu: Option<compiler::TargetCpu>,
+
+    /// Produce a static executable that uses raw Linux syscalls instead of libc
+    #[arg(long)]
+    freestanding: bool,
+
+    /// Assemble and link with the built-in x86-64 assembler instead of clang or as and ld.
+    /// Executables must be `--freestanding`
+    #[arg(long = "integrated-as")]
+    integrated_as: bool,
+
+    /// Emit DWARF debug info tying the machine code to the Brainfuck source
+    #[arg(short = 'g')]
+    debug_info: bool,
+
+    /// Syntax of the assembly output. x86-64 only
+    #[arg(long = "asm-syntax", value_name = "SYNTAX", value_enum, default_value_t = compiler::AsmSyntax::Att)]
+    asm_syntax: compiler::AsmSyntax,
+
+    /// Comment the assembly output with the source and IR command of each part of the
+    /// code, and with execution counts when combined with `--profile`
+    #[arg(long)]
+    annotate: bool,
+
+    /// Exit status of the program, when interpreting or compiling
+    #[arg(long = "exit-code", value_name = "MODE", value_enum, default_value_t = compiler::ExitCode::Zero)]
+    exit_code: compiler::ExitCode,
+
+    /// Code generation option, e.g. `-funroll-limit=N`. May be repeated
+    #[arg(short = 'f', value_name = "OPTION")]
+    codegen_options: Vec<String>,
+
     /// Disables partial evaluation when compiling
     #[arg(long = "partial-eval")]
     partial_eval: bool,
+
+    /// What to do with loops that provably never terminate once entered
+    #[arg(long = "infinite-loops", value_name = "MODE", value_enum, default_value_t = optimizer::InfiniteLoopMode::Ignore)]
+    infinite_loops: optimizer::InfiniteLoopMode,
+
+    /// Dump the IR after the given optimization passes (comma separated, or `all`)
+    #[arg(long = "dump-ir-after", value_name = "PASS", value_delimiter = ',')]
+    dump_ir_after: Vec<String>,
+
+    /// Write each IR dump to its own file in this directory instead of stderr
+    #[arg(long = "dump-ir-dir", value_name = "DIR")]
+    dump_ir_dir: Option<String>,
+}
+
+/// Options given with `-f`
+struct CodegenOptions {
+    /// Maximum number of commands a single loop may be unrolled into
+    unroll_limit: usize,
+}
+
+fn parse_codegen_options(options: &[String]) -> Result<CodegenOptions, String> {
+    let mut codegen_options = CodegenOptions {
+        unroll_limit: optimizer::DEFAULT_UNROLL_LIMIT,
+    };
+    for option in options {
+        if let Some(limit) = option.strip_prefix("unroll-limit=") {
+            codegen_options.unroll_limit = limit
+                .parse()
+                .map_err(|_| format!("Error: Invalid unroll limit '{}'", limit))?;
+        } else {
+            return Err(format!("Error: Unknown option '-f{}'", option));
+        }
+    }
+    Ok(codegen_options)
+}
+
+/// Output file when `-o` isn't given: `a.out` for executables, and the
+/// source's name with the artifact's extension in the current directory
+/// for assembly and object files
+fn default_output(file_name: &str, artifact: compiler::Artifact) -> String {
+    let extension = match artifact {
+        compiler::Artifact::Assembly => "s",
+        compiler::Artifact::Object => "o",
+        compiler::Artifact::Executable => return String::from("a.out"),
+    };
+    let stem = match std::path::Path::new(file_name).file_stem() {
+        Some(stem) if file_name != "-" => stem.to_string_lossy().into_owned(),
+        _ => String::from("a"),
+    };
+    format!("{}.{}", stem, extension)
+}
+
+fn read_source(file_name: &str) -> String {
+    let contents = if file_name == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(file_name)
+    };
+    match contents {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Error reading file {}: {}", file_name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_fmt(args: &FmtArgs) {
+    let mut unformatted = false;
+    for file_name in &args.files {
+        let src_contents = read_source(file_name);
+        let result = if args.minify {
+            fmt::minify(&src_contents)
+        } else {
+            fmt::format(&src_contents, args.width)
+        };
+        let formatted = match result {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("{}: {}", file_name, e);
+                std::process::exit(1);
+            }
+        };
+
+        if args.check {
+            if formatted != src_contents {
+                eprintln!("{} is not formatted", file_name);
+                unformatted = true;
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+
+    if unformatted {
+        std::process::exit(1);
+    }
+}
+
+fn run_lint(args: &LintArgs) {
+    #[derive(serde::Serialize)]
+    struct FileWarning<'a> {
+        file: &'a str,
+        #[serde(flatten)]
+        warning: lint::Warning,
+    }
+
+    let mut all_warnings: Vec<FileWarning> = vec![];
+    for file_name in &args.files {
+        let src_contents = read_source(file_name);
+        match lint::lint(&src_contents) {
+            Ok(warnings) => {
+                all_warnings.extend(warnings.into_iter().map(|warning| FileWarning {
+                    file: file_name,
+                    warning,
+                }));
+            }
+            Err(e) => {
+                eprintln!("{}: {}", file_name, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&all_warnings).unwrap());
+    } else {
+        for w in &all_warnings {
+            println!(
+                "{}:{}:{}: warning: {} [{}]",
+                w.file, w.warning.line, w.warning.column, w.warning.message, w.warning.code
+            );
+        }
+    }
+
+    if !all_warnings.is_empty() {
+        std::process::exit(1);
+    }
 }
 
 fn main() {
     let args = Args::parse();
 
-    // Read the file contents
-    let src_contents = match std::fs::read_to_string(&args.file_name) {
-        Ok(contents) => contents,
+    match &args.command {
+        Some(SubCommand::Fmt(fmt_args)) => {
+            run_fmt(fmt_args);
+            return;
+        }
+        Some(SubCommand::Lint(lint_args)) => {
+            run_lint(lint_args);
+            return;
+        }
+        None => (),
+    }
+
+    let file_name = args.file_name.as_deref().unwrap();
+    let src_contents = read_source(file_name);
+
+    let codegen_options = match parse_codegen_options(&args.codegen_options) {
+        Ok(codegen_options) => codegen_options,
         Err(e) => {
-            eprintln!("Error reading file {}: {}", args.file_name, e);
+            eprintln!("{}", e);
             std::process::exit(1);
         }
     };
 
-    let mut commands = parser::parse(&src_contents);
-    optimizer::optimize(&mut commands, args.optimization_level);
+    let ir_dump = match optimizer::IrDump::new(&args.dump_ir_after, args.dump_ir_dir.clone()) {
+        Ok(ir_dump) => ir_dump,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut commands = match args.input_format {
+        InputFormat::Bf => parser::parse(&src_contents),
+        InputFormat::Ir => ir::parse_ir(&src_contents).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        InputFormat::Json => json::parse_json(&src_contents).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+    };
+    optimizer::optimize(
+        &mut commands,
+        args.optimization_level,
+        args.infinite_loops,
+        codegen_options.unroll_limit,
+        &ir_dump,
+    );
 
     if args.partial_eval && !args.interp {
-        commands = partial::partial_eval(&commands);
+        commands = partial::partial_eval(
+            &commands,
+            args.exit_code == compiler::ExitCode::Cell,
+        );
+        ir_dump.dump("partial_eval", &commands);
     }
 
     if args.pretty_print {
@@ -72,20 +407,155 @@ fn main() {
         return;
     }
 
-    if args.interp || args.profile {
-        interp::interp(&mut commands);
+    if let Some(emit) = args.emit.filter(|emit| emit.artifact().is_none()) {
+        if args.profile {
+            interp::interp(&mut commands);
+        }
+        let contents = match emit {
+            Emit::Ir => ir::write_ir(&commands).into_bytes(),
+            Emit::Json => format!("{}\n", json::write_json(&commands)).into_bytes(),
+            Emit::C => c::write_c(&commands, args.exit_code).into_bytes(),
+            Emit::Rust => rust::write_rust(&commands, args.exit_code).into_bytes(),
+            Emit::Wat | Emit::Wasm => {
+                let options = wasm::WasmOptions {
+                    simd: args.simd != Some(compiler::Simd::None),
+                    exit_code: args.exit_code,
+                };
+                if emit == Emit::Wat {
+                    wasm::write_wat(&commands, &options).into_bytes()
+                } else {
+                    wasm::write_wasm(&commands, &options)
+                }
+            }
+            Emit::Bf => match bf::write_bf(&commands) {
+                Ok(bf) => bf.into_bytes(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            },
+            Emit::Asm | Emit::Obj | Emit::Exe => unreachable!(),
+        };
+        let dest = compiler::Destination::new(args.out_file.as_deref().unwrap_or("-"));
+        if let Err(e) = dest.write(&contents, false) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.interp || (args.profile && !args.annotate) {
+        let final_cell = interp::interp(&mut commands);
         if args.profile {
             profiler::print_profile(&commands);
         }
+        if args.exit_code == compiler::ExitCode::Cell {
+            std::process::exit(final_cell as i32);
+        }
         return;
     }
 
 
-    compiler::compile(
+    let simd = args
+        .simd
+        .or(args.target_cpu.map(compiler::TargetCpu::simd))
+        .unwrap_or(args.target.default_simd());
+    if !args.target.supports_simd(simd) {
+        eprintln!(
+            "Error: {} scans are not available on {}",
+            simd.to_possible_value().unwrap().get_name(),
+            args.target.to_possible_value().unwrap().get_name()
+        );
+        std::process::exit(1);
+    }
+
+    let artifact = match args.emit.and_then(Emit::artifact) {
+        Some(artifact) => artifact,
+        None if args.no_binary => compiler::Artifact::Assembly,
+        None if args.output_object => compiler::Artifact::Object,
+        None => compiler::Artifact::Executable,
+    };
+
+    if args.integrated_as && artifact != compiler::Artifact::Assembly {
+        if args.target != compiler::Target::X86_64 {
+            eprintln!("Error: The integrated assembler only supports x86_64-linux-gnu");
+            std::process::exit(1);
+        }
+        if !args.freestanding && artifact == compiler::Artifact::Executable {
+            eprintln!("Error: The integrated assembler can't link against libc. Use --freestanding, or -c for an object file");
+            std::process::exit(1);
+        }
+        if args.debug_info {
+            eprintln!("Error: The integrated assembler doesn't support -g");
+            std::process::exit(1);
+        }
+    }
+
+    if args.debug_info && args.input_format != InputFormat::Bf {
+        eprintln!("Error: -g needs Brainfuck source to map the program back to");
+        std::process::exit(1);
+    }
+
+    if args.annotate && artifact != compiler::Artifact::Assembly {
+        eprintln!("Error: --annotate only applies to assembly output. Use -S or --emit=asm");
+        std::process::exit(1);
+    }
+
+    if args.asm_syntax == compiler::AsmSyntax::Intel && args.target != compiler::Target::X86_64 {
+        eprintln!("Error: --asm-syntax=intel is only available for x86_64-linux-gnu");
+        std::process::exit(1);
+    }
+
+    let out_file = args
+        .out_file
+        .clone()
+        .unwrap_or_else(|| default_output(file_name, artifact));
+
+    // The counts in the annotations come from running the optimized program,
+    // like with `--emit=json`
+    if args.annotate && args.profile {
+        if out_file == "-" {
+            eprintln!("Error: The program's output would be mixed with the annotated assembly on stdout. Use -o to write the assembly to a file");
+            std::process::exit(1);
+        }
+        interp::interp(&mut commands);
+    }
+    let source_name = if file_name == "-" { "<stdin>" } else { file_name };
+    let result = compiler::compile(
         &commands,
-        &args.file_name,
-        &args.out_file,
-        !args.no_binary,
-        args.output_object,
+        artifact,
+        &compiler::Destination::new(&out_file),
+        &compiler::CompileOptions {
+            target: args.target,
+            simd,
+            exit_code: args.exit_code,
+            freestanding: args.freestanding,
+            integrated_as: args.integrated_as,
+            asm_syntax: args.asm_syntax,
+            source_map: ((args.debug_info || args.annotate)
+                && args.input_format == InputFormat::Bf)
+                .then(|| debuginfo::SourceMap::new(source_name, &src_contents)),
+            debug_info: args.debug_info,
+            annotate: args.annotate,
+            counts: args.annotate && args.profile,
+        },
     );
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::Artifact;
+
+    #[test]
+    fn default_outputs() {
+        assert_eq!(default_output("dir/hello.b", Artifact::Executable), "a.out");
+        assert_eq!(default_output("dir/hello.b", Artifact::Assembly), "hello.s");
+        assert_eq!(default_output("hello", Artifact::Object), "hello.o");
+        assert_eq!(default_output("-", Artifact::Assembly), "a.s");
+    }
 }
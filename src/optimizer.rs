@@ -1,4 +1,76 @@
-use crate::parser::{Command, Direction};
+use crate::ir::write_ir;
+use crate::parser::{Command, Direction, OutputType};
+use std::collections::HashMap;
+
+/// Names accepted by `--dump-ir-after`, in the order the passes run
+pub const PASSES: [&str; 8] = [
+    "collapse",
+    "fold_zero_loop",
+    "unroll_loops",
+    "replace_simple_loops",
+    "replace_scans",
+    "replace_conditional_loops",
+    "detect_infinite_loops",
+    "partial_eval",
+];
+
+/// What to do with loops that provably never terminate once entered
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum InfiniteLoopMode {
+    /// Leave them as they are
+    Ignore,
+    /// Print a warning for each one
+    Warn,
+    /// Replace them with a runtime trap that reports the loop and exits
+    Trap,
+}
+
+/// Writes the command tree after selected optimization passes
+pub struct IrDump {
+    passes: Vec<String>,
+    dir: Option<String>,
+}
+
+impl IrDump {
+    pub fn new(passes: &[String], dir: Option<String>) -> Result<IrDump, String> {
+        for pass in passes {
+            if pass != "all" && !PASSES.contains(&pass.as_str()) {
+                return Err(format!(
+                    "Error: Unknown pass `{}`. Expected one of: all, {}",
+                    pass,
+                    PASSES.join(", ")
+                ));
+            }
+        }
+        Ok(IrDump {
+            passes: passes.to_vec(),
+            dir,
+        })
+    }
+
+    /// Dumps `commands` if `pass` was requested. Goes to stderr unless a
+    /// directory was given, in which case each pass gets its own file.
+    pub fn dump(&self, pass: &str, commands: &[Command]) {
+        if !self.passes.iter().any(|p| p == "all" || p == pass) {
+            return;
+        }
+        let ir = write_ir(commands);
+        match &self.dir {
+            Some(dir) => {
+                let idx = PASSES
+                    .iter()
+                    .position(|p| *p == pass)
+                    .unwrap_or(PASSES.len());
+                let path = format!("{}/{}-{}.ir", dir, idx + 1, pass);
+                if let Err(e) = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, ir))
+                {
+                    eprintln!("Warning: Failed to write IR dump {}: {}", path, e);
+                }
+            }
+            None => eprint!("; IR after {}\n{}", pass, ir),
+        }
+    }
+}
 
 pub fn is_simple_loop(loop_cmd: &Command) -> (bool, isize) {
     if let Command::Loop { body, .. } = loop_cmd {
@@ -114,109 +186,49 @@ pub fn collapse(commands: &mut Vec<Command>) {
                     continue;
                 }
             }
-            Command::IncData { offset, amount, .. } => {
-                let mut total_amount: isize = *amount as isize;
-                while read_idx + 1 < commands.len() {
-                    match &commands[read_idx + 1] {
-                        Command::IncData {
-                            offset: next_offset,
-                            amount: next_amount,
-                            ..
-                        } => {
-                            if next_offset == offset {
-                                total_amount += *next_amount as isize;
-                                read_idx += 1;
-                            } else {
-                                break;
-                            }
-                        }
-                        Command::DecData {
-                            offset: next_offset,
-                            amount: next_amount,
-                            ..
-                        } => {
-                            if next_offset == offset {
-                                total_amount -= *next_amount as isize;
-                                read_idx += 1;
-                            } else {
-                                break;
-                            }
-                        }
+            Command::IncData { .. } | Command::DecData { .. } => {
+                // Changes to different cells can be reordered, so each run of
+                // them is merged into one change per cell, in the order the
+                // cells are first changed
+                let mut totals: Vec<(isize, isize)> = vec![];
+                while let Some(command) = commands.get(read_idx) {
+                    let (offset, amount) = match command {
+                        Command::IncData { offset, amount, .. } => (*offset, *amount as isize),
+                        Command::DecData { offset, amount, .. } => (*offset, -(*amount as isize)),
                         _ => break,
-                    }
-                }
-
-                if total_amount > 0 {
-                    commands[write_idx] = Command::IncData {
-                        offset: *offset,
-                        amount: total_amount as u8,
-                        count: 0,
                     };
-                } else if total_amount < 0 {
-                    commands[write_idx] = Command::DecData {
-                        offset: *offset,
-                        amount: (-total_amount) as u8,
-                        count: 0,
-                    };
-                } else {
-                    read_idx += 1; // Don't increment write idx
-                    continue;
-                }
-            }
-            Command::DecData { offset, amount, .. } => {
-                let mut total_amount: isize = *amount as isize;
-                while read_idx + 1 < commands.len() {
-                    match &commands[read_idx + 1] {
-                        Command::DecData {
-                            offset: next_offset,
-                            amount: next_amount,
-                            ..
-                        } => {
-                            if next_offset == offset {
-                                total_amount += *next_amount as isize;
-                                read_idx += 1;
-                            } else {
-                                break;
-                            }
-                        }
-                        Command::IncData {
-                            offset: next_offset,
-                            amount: next_amount,
-                            ..
-                        } => {
-                            if next_offset == offset {
-                                total_amount -= *next_amount as isize;
-                                read_idx += 1;
-                            } else {
-                                break;
-                            }
-                        }
-                        _ => break,
+                    match totals.iter_mut().find(|(cell, _)| *cell == offset) {
+                        Some((_, total)) => *total += amount,
+                        None => totals.push((offset, amount)),
                     }
+                    read_idx += 1;
                 }
-
-                if total_amount > 0 {
-                    commands[write_idx] = Command::DecData {
-                        offset: *offset,
-                        amount: total_amount as u8,
-                        count: 0,
-                    };
-                } else if total_amount < 0 {
-                    commands[write_idx] = Command::IncData {
-                        offset: *offset,
-                        amount: (-total_amount) as u8,
-                        count: 0,
-                    };
-                } else {
-                    read_idx += 1; // Don't increment write idx
-                    continue;
+                for (offset, total_amount) in totals {
+                    if total_amount > 0 {
+                        commands[write_idx] = Command::IncData {
+                            offset,
+                            amount: total_amount as u8,
+                            count: 0,
+                        };
+                    } else if total_amount < 0 {
+                        commands[write_idx] = Command::DecData {
+                            offset,
+                            amount: (-total_amount) as u8,
+                            count: 0,
+                        };
+                    } else {
+                        continue;
+                    }
+                    write_idx += 1;
                 }
+                continue;
             }
             // Non-collapsible commands
             Command::SetData { .. }
             | Command::Scan { .. }
             | Command::Output { .. }
             | Command::Input { .. }
+            | Command::InfiniteLoop { .. }
             | Command::AddOffsetData { .. }
             | Command::SubOffsetData { .. } => {
                 commands[write_idx] = current_command.clone();
@@ -237,6 +249,13 @@ pub fn collapse(commands: &mut Vec<Command>) {
                 }
                 commands[write_idx] = current_loop;
             }
+            Command::If { .. } => {
+                let mut current_if = commands[read_idx].clone();
+                if let Command::If { ref mut body, .. } = current_if {
+                    collapse(body);
+                }
+                commands[write_idx] = current_if;
+            }
         }
         read_idx += 1;
         write_idx += 1;
@@ -342,50 +361,846 @@ fn replace_simple_loops(commands: &mut Vec<Command>) {
     }
 }
 
-fn replace_scans(commands: &mut Vec<Command>) {
-    for i in 0..commands.len() {
-        let current_command = &mut commands[i];
+/// Net pointer movement of a loop body made only of pointer moves
+fn scan_stride(body: &[Command]) -> Option<isize> {
+    let mut stride: isize = 0;
+    for cmd in body {
+        stride += pointer_move(cmd)?;
+    }
+    if stride == 0 {
+        return None;
+    }
+    Some(stride)
+}
 
-        match current_command {
-            Command::Loop {
-                id, ref mut body, ..
-            } => {
-                if body.len() != 1 {
-                    fold_zero_loop(body);
-                    continue;
-                }
-                if let Command::IncPointer { amount, .. } = &body[0] {
-                    commands[i] = Command::Scan {
+fn pointer_move(command: &Command) -> Option<isize> {
+    match command {
+        Command::IncPointer { amount, .. } => Some(*amount as isize),
+        Command::DecPointer { amount, .. } => Some(-(*amount as isize)),
+        _ => None,
+    }
+}
+
+fn replace_scans(commands: &mut Vec<Command>) {
+    for command in commands.iter_mut() {
+        match command {
+            Command::Loop { id, body, .. } => match scan_stride(body) {
+                Some(stride) => {
+                    *command = Command::Scan {
                         id: *id,
-                        direction: Direction::Right,
-                        skip_amount: *amount,
+                        direction: if stride > 0 {
+                            Direction::Right
+                        } else {
+                            Direction::Left
+                        },
+                        skip_amount: stride.unsigned_abs(),
+                        offset: 0,
                         count: 0,
                     }
-                } else if let Command::DecPointer { amount, .. } = &body[0] {
-                    commands[i] = Command::Scan {
-                        id: *id,
-                        direction: Direction::Left,
-                        skip_amount: *amount,
+                }
+                None => replace_scans(body),
+            },
+            Command::If { body, .. } => replace_scans(body),
+            _ => (),
+        }
+    }
+
+    // A scan between two pointer moves, like `>[>>]<`, can instead test a cell
+    // at an offset from the pointer, leaving a single move in front of it
+    let mut i = 0;
+    while i + 2 < commands.len() {
+        let before = pointer_move(&commands[i]);
+        let after = pointer_move(&commands[i + 2]);
+        match (&mut commands[i + 1], before, after) {
+            (Command::Scan { offset, .. }, Some(before), Some(after)) => {
+                *offset -= after;
+                commands.remove(i + 2);
+                let moved = before + after;
+                if moved > 0 {
+                    commands[i] = Command::IncPointer {
+                        amount: moved as usize,
                         count: 0,
-                    }
+                    };
+                } else if moved < 0 {
+                    commands[i] = Command::DecPointer {
+                        amount: (-moved) as usize,
+                        count: 0,
+                    };
                 } else {
-                    replace_scans(body);
+                    commands.remove(i);
+                }
+            }
+            _ => i += 1,
+        }
+    }
+}
+
+/// Default for `-funroll-limit`
+pub const DEFAULT_UNROLL_LIMIT: usize = 256;
+
+/// Rewrites a loop body without nested loops so that every command addresses
+/// cells relative to the loop cell. Returns `None` if the body moves the
+/// pointer overall or changes the loop cell other than by adding to it.
+fn flatten_loop_body(body: &[Command]) -> Option<Vec<Command>> {
+    let mut flat = vec![];
+    let mut loop_ptr: isize = 0;
+    for cmd in body {
+        match cmd {
+            Command::IncPointer { amount, .. } => loop_ptr += *amount as isize,
+            Command::DecPointer { amount, .. } => loop_ptr -= *amount as isize,
+            Command::IncData { offset, amount, .. } => flat.push(Command::IncData {
+                offset: loop_ptr + offset,
+                amount: *amount,
+                count: 0,
+            }),
+            Command::DecData { offset, amount, .. } => flat.push(Command::DecData {
+                offset: loop_ptr + offset,
+                amount: *amount,
+                count: 0,
+            }),
+            Command::SetData { offset, value, .. } => {
+                if loop_ptr + offset == 0 {
+                    return None;
+                }
+                flat.push(Command::SetData {
+                    offset: loop_ptr + offset,
+                    value: *value,
+                    count: 0,
+                });
+            }
+            Command::AddOffsetData {
+                dest_offset,
+                src_offset,
+                multiplier,
+                inverted,
+                ..
+            } => {
+                if loop_ptr + dest_offset == 0 {
+                    return None;
+                }
+                flat.push(Command::AddOffsetData {
+                    dest_offset: loop_ptr + dest_offset,
+                    src_offset: loop_ptr + src_offset,
+                    multiplier: *multiplier,
+                    inverted: *inverted,
+                    count: 0,
+                });
+            }
+            Command::SubOffsetData {
+                dest_offset,
+                src_offset,
+                multiplier,
+                inverted,
+                ..
+            } => {
+                if loop_ptr + dest_offset == 0 {
+                    return None;
+                }
+                flat.push(Command::SubOffsetData {
+                    dest_offset: loop_ptr + dest_offset,
+                    src_offset: loop_ptr + src_offset,
+                    multiplier: *multiplier,
+                    inverted: *inverted,
+                    count: 0,
+                });
+            }
+            Command::Output { out_type, .. } => flat.push(Command::Output {
+                out_type: match out_type {
+                    OutputType::Cell { offset } => OutputType::Cell {
+                        offset: loop_ptr + offset,
+                    },
+                    OutputType::Const(val) => OutputType::Const(*val),
+                },
+                count: 0,
+            }),
+            Command::Input { offset, .. } => {
+                if loop_ptr + offset == 0 {
+                    return None;
+                }
+                flat.push(Command::Input {
+                    offset: loop_ptr + offset,
+                    count: 0,
+                });
+            }
+            Command::Scan { .. }
+            | Command::InfiniteLoop { .. }
+            | Command::If { .. }
+            | Command::Loop { .. } => return None,
+        }
+    }
+    if loop_ptr != 0 {
+        return None;
+    }
+    Some(flat)
+}
+
+/// Number of iterations a loop whose cell starts at `start` and changes by
+/// `delta` per iteration runs for, or `None` if it never terminates
+fn trip_count(start: u8, delta: u8) -> Option<usize> {
+    let mut val = start;
+    for trips in 0..=256 {
+        if val == 0 {
+            return Some(trips);
+        }
+        val = val.wrapping_add(delta);
+    }
+    None
+}
+
+/// Emits `times` copies of a flattened loop body, merging additions to the same
+/// cell until something reads or overwrites it
+fn repeat_flat_body(flat: &[Command], times: usize) -> Vec<Command> {
+    let mut out = vec![];
+    // Pending additions, in the order the cells were first touched
+    let mut pending: Vec<(isize, u8)> = vec![];
+
+    fn flush(out: &mut Vec<Command>, pending: &mut Vec<(isize, u8)>, offset: isize) {
+        if let Some(idx) = pending.iter().position(|(o, _)| *o == offset) {
+            let (offset, delta) = pending.remove(idx);
+            if delta > 128 {
+                out.push(Command::DecData {
+                    offset,
+                    amount: 0u8.wrapping_sub(delta),
+                    count: 0,
+                });
+            } else if delta > 0 {
+                out.push(Command::IncData {
+                    offset,
+                    amount: delta,
+                    count: 0,
+                });
+            }
+        }
+    }
+
+    fn add(pending: &mut Vec<(isize, u8)>, offset: isize, amount: u8) {
+        match pending.iter_mut().find(|(o, _)| *o == offset) {
+            Some((_, delta)) => *delta = delta.wrapping_add(amount),
+            None => pending.push((offset, amount)),
+        }
+    }
+
+    for _ in 0..times {
+        for cmd in flat {
+            match cmd {
+                Command::IncData { offset, amount, .. } => add(&mut pending, *offset, *amount),
+                Command::DecData { offset, amount, .. } => {
+                    add(&mut pending, *offset, 0u8.wrapping_sub(*amount))
                 }
+                // Overwritten anyway
+                Command::SetData { offset, .. } | Command::Input { offset, .. } => {
+                    pending.retain(|(o, _)| o != offset);
+                    out.push(cmd.clone());
+                }
+                Command::AddOffsetData {
+                    dest_offset,
+                    src_offset,
+                    ..
+                }
+                | Command::SubOffsetData {
+                    dest_offset,
+                    src_offset,
+                    ..
+                } => {
+                    flush(&mut out, &mut pending, *dest_offset);
+                    flush(&mut out, &mut pending, *src_offset);
+                    out.push(cmd.clone());
+                }
+                Command::Output {
+                    out_type: OutputType::Cell { offset },
+                    ..
+                } => {
+                    flush(&mut out, &mut pending, *offset);
+                    out.push(cmd.clone());
+                }
+                _ => out.push(cmd.clone()),
+            }
+        }
+    }
+    while let Some((offset, _)) = pending.first() {
+        let offset = *offset;
+        flush(&mut out, &mut pending, offset);
+    }
+    out
+}
+
+/// Net change of the loop cell per iteration of a flattened loop body
+fn loop_cell_delta(flat: &[Command]) -> u8 {
+    flat.iter().fold(0u8, |delta, cmd| match cmd {
+        Command::IncData {
+            offset: 0, amount, ..
+        } => delta.wrapping_add(*amount),
+        Command::DecData {
+            offset: 0, amount, ..
+        } => delta.wrapping_sub(*amount),
+        _ => delta,
+    })
+}
+
+/// Cell values known while walking straight-line code
+struct KnownCells {
+    /// Pointer relative to the point where knowledge was last reset
+    ptr: isize,
+    /// Cells mapped to `None` are unknown
+    cells: HashMap<isize, Option<u8>>,
+    /// Cells missing from `cells` are zero rather than unknown
+    default_zero: bool,
+}
+
+impl KnownCells {
+    fn get(&self, offset: isize) -> Option<u8> {
+        match self.cells.get(&(self.ptr + offset)) {
+            Some(val) => *val,
+            None if self.default_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, offset: isize, val: Option<u8>) {
+        self.cells.insert(self.ptr + offset, val);
+    }
+
+    /// After a loop the pointer may be anywhere, but the cell the loop
+    /// tested, at `offset` from it, is zero
+    fn reset_after_loop(&mut self, offset: isize) {
+        self.cells.clear();
+        self.default_zero = false;
+        self.ptr = 0;
+        self.set(offset, Some(0));
+    }
+}
+
+/// Unrolls loops whose trip count is known from the value of their cell on
+/// entry. Loops that would unroll to more than `limit` commands are instead
+/// partially unrolled by the largest factor of the trip count that fits.
+/// `start_zero` is whether all cells are zero when `commands` starts running.
+fn unroll_loops(commands: &mut Vec<Command>, limit: usize, start_zero: bool) {
+    let mut known = KnownCells {
+        ptr: 0,
+        cells: HashMap::new(),
+        default_zero: start_zero,
+    };
+
+    let mut i = 0;
+    while i < commands.len() {
+        match &mut commands[i] {
+            Command::IncPointer { amount, .. } => known.ptr += *amount as isize,
+            Command::DecPointer { amount, .. } => known.ptr -= *amount as isize,
+            Command::IncData { offset, amount, .. } => {
+                let val = known.get(*offset).map(|v| v.wrapping_add(*amount));
+                known.set(*offset, val);
+            }
+            Command::DecData { offset, amount, .. } => {
+                let val = known.get(*offset).map(|v| v.wrapping_sub(*amount));
+                known.set(*offset, val);
+            }
+            Command::SetData { offset, value, .. } => known.set(*offset, Some(*value)),
+            Command::AddOffsetData { dest_offset, .. }
+            | Command::SubOffsetData { dest_offset, .. } => known.set(*dest_offset, None),
+            Command::Input { offset, .. } => known.set(*offset, None),
+            Command::Output { .. } => (),
+            Command::InfiniteLoop { .. } => known.set(0, Some(0)),
+            Command::Scan { offset, .. } => known.reset_after_loop(*offset),
+            Command::If { body, .. } => {
+                unroll_loops(body, limit, false);
+                known.reset_after_loop(0);
+            }
+            Command::Loop { body, .. } => {
+                let flat = known.get(0).zip(flatten_loop_body(body));
+                let unrolled = flat.and_then(|(start, flat)| {
+                    trip_count(start, loop_cell_delta(&flat)).map(|trips| (trips, flat))
+                });
+                match unrolled {
+                    Some((trips, flat)) if trips * flat.len() <= limit => {
+                        // Continue the walk over the straight-line replacement
+                        commands.splice(i..i + 1, repeat_flat_body(&flat, trips));
+                        continue;
+                    }
+                    Some((trips, flat)) => {
+                        // Loops that `replace_simple_loops` turns into
+                        // multiplications are better left alone
+                        let (is_simple, _) = is_simple_loop(&commands[i]);
+                        let factor = (2..trips)
+                            .rev()
+                            .find(|k| trips % k == 0 && k * flat.len() <= limit);
+                        if let (false, Some(factor), Command::Loop { body, .. }) =
+                            (is_simple, factor, &mut commands[i])
+                        {
+                            *body = repeat_flat_body(&flat, factor);
+                        }
+                    }
+                    None => unroll_loops(body, limit, false),
+                }
+                known.reset_after_loop(0);
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Whether the pointer ends up where it started after running `body`
+fn is_balanced(body: &[Command]) -> bool {
+    let mut loop_ptr: isize = 0;
+    for cmd in body {
+        match cmd {
+            Command::IncPointer { amount, .. } => loop_ptr += *amount as isize,
+            Command::DecPointer { amount, .. } => loop_ptr -= *amount as isize,
+            Command::Scan { .. } => return false,
+            Command::If { body, .. } | Command::Loop { body, .. } if !is_balanced(body) => {
+                return false;
             }
             _ => (),
         }
     }
+    loop_ptr == 0
+}
+
+/// Whether a loop with this body returns the pointer to its cell and always
+/// leaves that cell zero, so the body runs at most once
+fn is_conditional_loop(body: &[Command]) -> bool {
+    let mut loop_ptr: isize = 0;
+    let mut cell_zero = false;
+
+    for cmd in body {
+        match cmd {
+            Command::IncPointer { amount, .. } => loop_ptr += *amount as isize,
+            Command::DecPointer { amount, .. } => loop_ptr -= *amount as isize,
+            Command::IncData { offset, .. }
+            | Command::DecData { offset, .. }
+            | Command::Input { offset, .. } => {
+                if loop_ptr.wrapping_add(*offset) == 0 {
+                    cell_zero = false;
+                }
+            }
+            Command::SetData { offset, value, .. } => {
+                if loop_ptr.wrapping_add(*offset) == 0 {
+                    cell_zero = *value == 0;
+                }
+            }
+            Command::AddOffsetData { dest_offset, .. }
+            | Command::SubOffsetData { dest_offset, .. } => {
+                if loop_ptr.wrapping_add(*dest_offset) == 0 {
+                    cell_zero = false;
+                }
+            }
+            Command::Output { .. } => (),
+            // Exits with its own cell zero, if it exits at all
+            Command::InfiniteLoop { .. } => cell_zero |= loop_ptr == 0,
+            Command::Scan { .. } => return false,
+            Command::If { body, .. } | Command::Loop { body, .. } => {
+                if !is_balanced(body) {
+                    return false;
+                }
+                // A nested loop on the same cell exits with it zero. Anywhere else,
+                // assume it may write the cell.
+                cell_zero = loop_ptr == 0;
+            }
+        }
+    }
+
+    loop_ptr == 0 && cell_zero
+}
+
+fn replace_conditional_loops(commands: &mut [Command]) {
+    for command in commands.iter_mut() {
+        if let Command::Loop { id, body, .. } = command {
+            replace_conditional_loops(body);
+            if is_conditional_loop(body) {
+                *command = Command::If {
+                    id: *id,
+                    body: std::mem::take(body),
+                    count: 0,
+                };
+            }
+        }
+    }
 }
 
-pub fn optimize(commands: &mut Vec<Command>, optimization_level: u8) {
+/// Returns the divisor of the `InfiniteLoop` equivalent to a loop with this
+/// body, if the loop never terminates for some non-zero cell values.
+/// Loops that do I/O are left alone, since spinning forever is observable.
+fn infinite_loop_divisor(body: &[Command]) -> Option<u8> {
+    let mut loop_ptr: isize = 0;
+    let mut induction_delta: u8 = 0;
+    let mut only_induction = true;
+    let mut writes_induction = false;
+
+    for cmd in body {
+        match cmd {
+            Command::IncPointer { amount, .. } => {
+                loop_ptr += *amount as isize;
+                only_induction = false;
+            }
+            Command::DecPointer { amount, .. } => {
+                loop_ptr -= *amount as isize;
+                only_induction = false;
+            }
+            Command::IncData { offset, amount, .. } => {
+                if loop_ptr.wrapping_add(*offset) == 0 {
+                    induction_delta = induction_delta.wrapping_add(*amount);
+                    writes_induction = true;
+                } else {
+                    only_induction = false;
+                }
+            }
+            Command::DecData { offset, amount, .. } => {
+                if loop_ptr.wrapping_add(*offset) == 0 {
+                    induction_delta = induction_delta.wrapping_sub(*amount);
+                    writes_induction = true;
+                } else {
+                    only_induction = false;
+                }
+            }
+            Command::SetData { offset, .. } => {
+                writes_induction |= loop_ptr.wrapping_add(*offset) == 0;
+                only_induction = false;
+            }
+            Command::AddOffsetData { dest_offset, .. }
+            | Command::SubOffsetData { dest_offset, .. } => {
+                writes_induction |= loop_ptr.wrapping_add(*dest_offset) == 0;
+                only_induction = false;
+            }
+            _ => return None,
+        }
+    }
+
+    if loop_ptr != 0 {
+        None
+    } else if only_induction {
+        // Stepping by an even amount only reaches zero from multiples of
+        // gcd(step, 256), which is the largest power of two dividing the step
+        match induction_delta {
+            0 => Some(0),
+            delta if delta % 2 == 0 => Some(1 << delta.trailing_zeros()),
+            _ => None,
+        }
+    } else if !writes_induction {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+fn detect_infinite_loops(commands: &mut [Command], mode: InfiniteLoopMode) {
+    for command in commands.iter_mut() {
+        let infinite_loop = match command {
+            Command::Loop { id, body, .. } => match infinite_loop_divisor(body) {
+                Some(divisor) => Some((*id, divisor)),
+                None => {
+                    detect_infinite_loops(body, mode);
+                    None
+                }
+            },
+            Command::If { body, .. } => {
+                detect_infinite_loops(body, mode);
+                None
+            }
+            // `InfiniteLoop` tests the cell under the pointer, so scans testing
+            // another cell are left alone
+            Command::Scan {
+                id,
+                skip_amount: 0,
+                offset: 0,
+                ..
+            } => Some((*id, 0)),
+            _ => None,
+        };
+
+        if let Some((id, divisor)) = infinite_loop {
+            match mode {
+                InfiniteLoopMode::Ignore => (),
+                InfiniteLoopMode::Warn => {
+                    if divisor == 0 {
+                        eprintln!("Warning: loop {} never terminates if entered", id);
+                    } else {
+                        eprintln!(
+                            "Warning: loop {} never terminates unless its cell is a multiple of {}",
+                            id, divisor
+                        );
+                    }
+                }
+                InfiniteLoopMode::Trap => {
+                    *command = Command::InfiniteLoop {
+                        id,
+                        divisor,
+                        count: 0,
+                    };
+                }
+            }
+        }
+    }
+}
+
+pub fn optimize(
+    commands: &mut Vec<Command>,
+    optimization_level: u8,
+    infinite_loops: InfiniteLoopMode,
+    unroll_limit: usize,
+    ir_dump: &IrDump,
+) {
     if optimization_level > 0 {
         collapse(commands);
+        ir_dump.dump("collapse", commands);
     }
     if optimization_level > 1 {
         fold_zero_loop(commands);
+        ir_dump.dump("fold_zero_loop", commands);
     }
     if optimization_level > 2 {
+        unroll_loops(commands, unroll_limit, true);
+        // Unrolled bodies leave changes to the same cells next to each other
+        collapse(commands);
+        ir_dump.dump("unroll_loops", commands);
         replace_simple_loops(commands);
+        ir_dump.dump("replace_simple_loops", commands);
         replace_scans(commands);
+        ir_dump.dump("replace_scans", commands);
+        replace_conditional_loops(commands);
+        ir_dump.dump("replace_conditional_loops", commands);
+    }
+    if infinite_loops != InfiniteLoopMode::Ignore {
+        detect_infinite_loops(commands, infinite_loops);
+        ir_dump.dump("detect_infinite_loops", commands);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::interp::interp_io;
+    use crate::ir::parse_ir;
+    use crate::parser::parse;
+
+    /// Hello world, with a scan and a few multiplication loops
+    pub(crate) const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+    /// `commands` optimized at `level`
+    fn optimize_all(mut commands: Vec<Command>, level: u8, mode: InfiniteLoopMode) -> Vec<Command> {
+        let ir_dump = IrDump::new(&[], None).unwrap();
+        optimize(&mut commands, level, mode, DEFAULT_UNROLL_LIMIT, &ir_dump);
+        commands
+    }
+
+    /// `ir` optimized at `level`
+    fn optimized(ir: &str, level: u8, mode: InfiniteLoopMode) -> Vec<Command> {
+        optimize_all(parse_ir(ir).unwrap(), level, mode)
+    }
+
+    /// The Brainfuck program `src` optimized at `level`
+    pub(crate) fn optimized_bf(src: &str, level: u8, mode: InfiniteLoopMode) -> Vec<Command> {
+        optimize_all(parse(&src.to_string()), level, mode)
+    }
+
+    /// Output of `commands` when run on `input`
+    fn run(mut commands: Vec<Command>, input: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        interp_io(&mut commands, &mut &input[..], &mut output);
+        output
+    }
+
+    /// Output of the program in `ir` optimized at `level`
+    fn run_ir(ir: &str, level: u8) -> Vec<u8> {
+        run(optimized(ir, level, InfiniteLoopMode::Ignore), &[])
+    }
+
+    #[test]
+    fn unroll_after_offset_scan() {
+        // The scan stops on cell 1, so cell 0 keeps its value
+        let ir = "set @0 51\nscan @1 right 1\nloop { out @0 dec @0 1 }\n";
+        let expected = run_ir(ir, 0);
+        assert_eq!(expected.len(), 51);
+        assert_eq!(run_ir(ir, 3), expected);
+    }
+
+    #[test]
+    fn trap_only_scans_of_the_current_cell() {
+        let mut commands = parse_ir("scan @1 right 0\nscan right 0\n").unwrap();
+        detect_infinite_loops(&mut commands, InfiniteLoopMode::Trap);
+        assert!(matches!(commands[0], Command::Scan { offset: 1, .. }));
+        assert!(matches!(
+            commands[1],
+            Command::InfiniteLoop { divisor: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn dump_ir_after_passes() {
+        let error = IrDump::new(&[String::from("inline")], None).err().unwrap();
+        assert!(error.starts_with("Error: Unknown pass `inline`"));
+
+        let dir = std::env::temp_dir().join(format!("bfr-ir-dump-{}", std::process::id()));
+        let dir_name = dir.to_string_lossy().into_owned();
+        let passes = [String::from("collapse"), String::from("replace_scans")];
+        let ir_dump = IrDump::new(&passes, Some(dir_name)).unwrap();
+        let mut commands = crate::parser::parse(&String::from("+++[>]<<."));
+        optimize(
+            &mut commands,
+            3,
+            InfiniteLoopMode::Ignore,
+            DEFAULT_UNROLL_LIMIT,
+            &ir_dump,
+        );
+        // Files are numbered by the position of the pass in `PASSES`
+        let collapse = std::fs::read_to_string(dir.join("1-collapse.ir")).unwrap();
+        let scans = std::fs::read_to_string(dir.join("5-replace_scans.ir")).unwrap();
+        let written = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written, 2);
+        assert!(collapse.starts_with("inc @0 3\n"));
+        assert!(parse_ir(&scans)
+            .unwrap()
+            .iter()
+            .any(|command| matches!(command, Command::Scan { .. })));
+    }
+
+    #[test]
+    fn infinite_loop_divisors() {
+        let divisor = |ir: &str| infinite_loop_divisor(&parse_ir(ir).unwrap());
+        assert_eq!(divisor(""), Some(0));
+        assert_eq!(divisor("inc @0 2"), Some(2));
+        // 12 steps through the multiples of 4
+        assert_eq!(divisor("dec @0 12"), Some(4));
+        assert_eq!(divisor("inc @0 1 inc @0 1 inc @0 128"), Some(2));
+        assert_eq!(divisor("inc @0 3"), None);
+        // Never touches its own cell
+        assert_eq!(divisor("ptr_inc 1 set @0 5 ptr_dec 1"), Some(0));
+        assert_eq!(divisor("ptr_inc 1 dec @-1 2 ptr_dec 1"), None);
+        assert_eq!(divisor("out @0"), None);
+        assert_eq!(divisor("ptr_inc 1"), None);
+    }
+
+    #[test]
+    fn trapped_loops_still_terminate_on_multiples() {
+        let ir = "in @0\nloop { dec @0 4 }\nout_const 33\nin @0\nloop { out @0 ptr_inc 1 }\n";
+        let trapped = optimized(ir, 3, InfiniteLoopMode::Trap);
+        assert!(matches!(
+            trapped[1],
+            Command::InfiniteLoop { divisor: 4, .. }
+        ));
+        assert!(matches!(trapped[4], Command::Loop { .. }));
+        let expected = run(optimized(ir, 0, InfiniteLoopMode::Ignore), b"\x0c\x00");
+        assert_eq!(expected, b"!");
+        assert_eq!(run(trapped, b"\x0c\x00"), expected);
+    }
+
+    #[test]
+    fn conditional_loops() {
+        let conditional = |ir: &str| is_conditional_loop(&parse_ir(ir).unwrap());
+        assert!(conditional("ptr_inc 1 inc @0 5 ptr_dec 1 set @0 0"));
+        assert!(conditional("set @1 0 out @0 set @0 0"));
+        // A nested loop on the same cell exits with it zero
+        assert!(conditional(
+            "ptr_inc 1 out @0 ptr_dec 1 loop { dec @0 1 out @0 }"
+        ));
+        assert!(!conditional("set @0 0 inc @0 1"));
+        assert!(!conditional("set @0 0 in @0"));
+        assert!(!conditional("set @0 0 ptr_inc 1"));
+        assert!(!conditional("set @0 0 scan right 1"));
+        assert!(!conditional("set @0 0 ptr_inc 1 loop { out @0 } ptr_dec 1"));
+    }
+
+    #[test]
+    fn conditional_loops_run_at_most_once() {
+        let ir = "in @0\nloop { ptr_inc 1 inc @0 65 out @0 ptr_dec 1 set @0 0 }\n\
+                  in @0\nloop { out @0 loop { dec @0 1 out_const 46 } }\nout_const 10\n";
+        let commands = optimized(ir, 3, InfiniteLoopMode::Ignore);
+        assert!(matches!(commands[1], Command::If { .. }));
+        assert!(matches!(commands[3], Command::If { .. }));
+        for input in [b"\x00\x00", b"\x01\x03", b"\x07\x00"] {
+            assert_eq!(
+                run(commands.clone(), input),
+                run(optimized(ir, 0, InfiniteLoopMode::Ignore), input)
+            );
+        }
+    }
+
+    #[test]
+    fn trip_counts() {
+        assert_eq!(trip_count(0, 1), Some(0));
+        assert_eq!(trip_count(5, 255), Some(5));
+        assert_eq!(trip_count(4, 254), Some(2));
+        assert_eq!(trip_count(250, 3), Some(2));
+        assert_eq!(trip_count(3, 2), None);
+        assert_eq!(trip_count(1, 0), None);
+    }
+
+    #[test]
+    fn unrolled_loops_behave_the_same() {
+        let ir = "set @0 10\nloop { ptr_inc 1 inc @0 3 out @0 ptr_dec 1 dec @0 1 }\n\
+                  set @0 250\nloop { inc @0 3 out @0 }\n\
+                  set @0 12\nloop { out @0 dec @0 1 }\n";
+        let expected = run_ir(ir, 0);
+        assert_eq!(expected.len(), 24);
+        assert_eq!(run_ir(ir, 3), expected);
+
+        // Fully unrolled
+        let mut commands = parse_ir(ir).unwrap();
+        unroll_loops(&mut commands, DEFAULT_UNROLL_LIMIT, true);
+        assert!(!commands
+            .iter()
+            .any(|command| matches!(command, Command::Loop { .. })));
+        assert_eq!(run(commands, &[]), expected);
+
+        // The loops running 10 and 12 times are partially unrolled by the
+        // largest factor of the trip count that fits, 2 and 3
+        let mut commands = parse_ir(ir).unwrap();
+        unroll_loops(&mut commands, 7, true);
+        let bodies: Vec<usize> = commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::Loop { body, .. } => Some(body.len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bodies, [5, 6]);
+        assert_eq!(run(commands, &[]), expected);
+
+        // What is left of the counter is merged away after unrolling
+        let commands = optimized_bf("++++++++[>++++++++<-]", 3, InfiniteLoopMode::Ignore);
+        assert_eq!(write_ir(&commands), "inc @1 64\n");
+    }
+
+    #[test]
+    fn scans_with_strides_and_offsets() {
+        let scans = |ir: &str| {
+            let mut commands = parse_ir(ir).unwrap();
+            replace_scans(&mut commands);
+            write_ir(&commands)
+        };
+        assert_eq!(scans("loop { ptr_inc 2 }"), "scan #1 @0 right 2\n");
+        assert_eq!(scans("loop { ptr_dec 3 ptr_inc 1 }"), "scan #1 @0 left 2\n");
+        assert_eq!(
+            scans("ptr_inc 1 loop { ptr_inc 2 } ptr_dec 1"),
+            "scan #1 @1 right 2\n"
+        );
+        assert_eq!(
+            scans("ptr_inc 3 loop { ptr_dec 1 } ptr_dec 1 out @0"),
+            "ptr_inc 2\nscan #1 @1 left 1\nout @0\n"
+        );
+        // Loops that do anything but move the pointer, or don't move it, stay
+        assert_eq!(
+            scans("loop { ptr_inc 1 ptr_dec 1 }\nloop { ptr_inc 1 out @0 }\n"),
+            "loop #1 {\n  ptr_inc 1\n  ptr_dec 1\n}\nloop #2 {\n  ptr_inc 1\n  out @0\n}\n"
+        );
+    }
+
+    #[test]
+    fn scans_behave_the_same() {
+        let ir = "set @0 1 set @2 1 set @4 1 set @6 1 set @8 1 set @9 1\n\
+                  ptr_inc 1 loop { ptr_inc 2 } ptr_dec 1 out_const 48\n\
+                  ptr_inc 1 loop { ptr_dec 2 } ptr_dec 1 out @0 out @1\n\
+                  ptr_inc 9 loop { ptr_dec 3 } out @0\n";
+        let expected = run_ir(ir, 0);
+        assert_eq!(expected.len(), 4);
+        let commands = optimized(ir, 3, InfiniteLoopMode::Ignore);
+        let scans: Vec<isize> = commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::Scan { offset, .. } => Some(*offset),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(scans, [1, 1, 0]);
+        assert_eq!(run(commands, &[]), expected);
     }
 }
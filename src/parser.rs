@@ -1,10 +1,14 @@
-#[derive(Debug, Clone)]
+use serde::{Deserialize, Deserializer, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Direction {
     Left,
     Right,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
 pub enum Command {
     /// Repr: `>{amount if amount > 1}`
     IncPointer {
@@ -34,12 +38,15 @@ pub enum Command {
         value: u8,
         count: usize,
     },
-    /// Repr: `S>{skip_amount if skip_amount > 1}` if direction is right
-    /// Repr: `S<{skip_amount if skip_amount > 1}` if direction is left
+    /// Moves the pointer by `skip_amount` until the cell at `offset` is zero
+    /// Repr: `[(|offset if offset != 0|>{skip_amount})]` if direction is right
+    /// Repr: `[(|offset if offset != 0|<{skip_amount})]` if direction is left
     Scan {
         id: usize,
         direction: Direction,
         skip_amount: usize,
+        #[serde(default)]
+        offset: isize,
         count: usize,
     },
     /// Repr: `a+|dest_offset||src_offset|{multiplier}` if inverted
@@ -71,6 +78,26 @@ pub enum Command {
         offset: isize,
         count: usize,
     },
+    /// Repr: `[!{divisor if divisor != 0}]`
+    ///
+    /// A loop that never terminates once entered, unless its cell is a
+    /// multiple of `divisor`, in which case the cell is cleared. A `divisor`
+    /// of 0 means the loop never terminates. Otherwise it is a power of two,
+    /// see `is_valid_divisor`.
+    InfiniteLoop {
+        id: usize,
+        #[serde(deserialize_with = "deserialize_divisor")]
+        divisor: u8,
+        count: usize,
+    },
+    /// Repr: `?[ body ]`
+    ///
+    /// A loop whose body always clears its cell, so it runs at most once
+    If {
+        id: usize,
+        body: Vec<Command>,
+        count: usize,
+    },
     /// Repr: `[ body ]`
     Loop {
         id: usize,
@@ -80,12 +107,96 @@ pub enum Command {
     },
 }
 
-#[derive(Debug, Clone)]
+/// Whether an `InfiniteLoop` can have this divisor. Loops stepping their cell
+/// by an even amount only terminate on multiples of a power of two, and the
+/// backends test for those with a mask.
+pub fn is_valid_divisor(divisor: u8) -> bool {
+    divisor == 0 || divisor.is_power_of_two()
+}
+
+/// Mask of the bits of an `InfiniteLoop`'s cell that must be clear for it to
+/// be a multiple of `divisor` and the loop to terminate, or `None` if the loop
+/// never terminates
+pub fn divisor_mask(divisor: u8) -> Option<u8> {
+    (divisor != 0).then(|| divisor - 1)
+}
+
+fn deserialize_divisor<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let divisor = u8::deserialize(deserializer)?;
+    if !is_valid_divisor(divisor) {
+        return Err(serde::de::Error::custom(format!(
+            "divisor must be 0 or a power of two, found {}",
+            divisor
+        )));
+    }
+    Ok(divisor)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum OutputType {
     Const(u8),
     Cell { offset: isize },
 }
 
+/// A piece of Brainfuck source with its 1-based position
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// One of the eight command characters
+    Command(char),
+    /// A maximal run of non-command characters, including whitespace
+    Comment(String),
+}
+
+pub fn is_command_char(c: char) -> bool {
+    matches!(c, '>' | '<' | '+' | '-' | '.' | ',' | '[' | ']')
+}
+
+/// Splits source into commands and comments, keeping the text `parse` discards
+pub fn tokenize(src: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut line = 1;
+    let mut column = 1;
+
+    for c in src.chars() {
+        if is_command_char(c) {
+            tokens.push(Token {
+                kind: TokenKind::Command(c),
+                line,
+                column,
+            });
+        } else if let Some(Token {
+            kind: TokenKind::Comment(text),
+            ..
+        }) = tokens.last_mut()
+        {
+            text.push(c);
+        } else {
+            tokens.push(Token {
+                kind: TokenKind::Comment(c.to_string()),
+                line,
+                column,
+            });
+        }
+
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+
+    tokens
+}
+
 pub fn parse(src: &String) -> Vec<Command> {
     let mut commands: Vec<Command> = vec![];
     let mut stack: Vec<Vec<Command>> = vec![];
@@ -172,146 +283,257 @@ pub fn parse(src: &String) -> Vec<Command> {
 }
 
 pub fn pretty_print(commands: &[Command]) {
-    fn pretty_print_rec(commands: &[Command], indent_level: usize, newline_end: &mut bool) {
+    print!("{}", pretty_string(commands));
+}
+
+/// Repr of a single command. Loops are shown as their opening bracket.
+pub fn pretty_command(command: &Command) -> String {
+    let offset_str = |offset: isize| {
+        if offset == 0 {
+            String::from("")
+        } else {
+            format!("({})", offset)
+        }
+    };
+    match command {
+        Command::IncPointer { amount, .. } => {
+            if *amount == 1 {
+                String::from(">")
+            } else {
+                format!("(>{})", amount)
+            }
+        }
+        Command::DecPointer { amount, .. } => {
+            if *amount == 1 {
+                String::from("<")
+            } else {
+                format!("(<{})", amount)
+            }
+        }
+        Command::IncData { offset, amount, .. } => {
+            if *amount == 1 {
+                format!("{}+", offset_str(*offset))
+            } else {
+                format!("({}+{})", offset_str(*offset), amount)
+            }
+        }
+        Command::DecData { offset, amount, .. } => {
+            if *amount == 1 {
+                format!("{}-", offset_str(*offset))
+            } else {
+                format!("({}-{})", offset_str(*offset), amount)
+            }
+        }
+        Command::SetData { offset, value, .. } => {
+            format!("({}={})", offset_str(*offset), value)
+        }
+        Command::Scan {
+            direction,
+            skip_amount,
+            offset,
+            ..
+        } => match direction {
+            Direction::Left => format!("[({}<{})]", offset_str(*offset), skip_amount),
+            Direction::Right => format!("[({}>{})]", offset_str(*offset), skip_amount),
+        },
+        Command::AddOffsetData {
+            dest_offset,
+            src_offset,
+            multiplier,
+            inverted,
+            ..
+        } => {
+            let inverted_str = if *inverted { "-" } else { "" };
+            format!(
+                "({}+={}({}*{}))",
+                dest_offset, inverted_str, src_offset, multiplier
+            )
+        }
+        Command::SubOffsetData {
+            dest_offset,
+            src_offset,
+            multiplier,
+            inverted,
+            ..
+        } => {
+            let inverted_str = if *inverted { "-" } else { "" };
+            format!(
+                "({}-={}({}*{}))",
+                dest_offset, inverted_str, src_offset, multiplier
+            )
+        }
+        Command::Output { out_type, .. } => match out_type {
+            OutputType::Const(val) => format!("(.{val})"),
+            OutputType::Cell { offset: _ } => String::from("."),
+        },
+        Command::Input { .. } => String::from(","),
+        Command::InfiniteLoop { divisor, .. } => {
+            if *divisor == 0 {
+                String::from("[!]")
+            } else {
+                format!("[!{}]", divisor)
+            }
+        }
+        Command::If { .. } => String::from("?["),
+        Command::Loop { .. } => String::from("["),
+    }
+}
+
+impl Command {
+    /// Id of the loop the command was parsed from, if it was one
+    pub fn loop_id(&self) -> Option<usize> {
+        match self {
+            Command::Scan { id, .. }
+            | Command::InfiniteLoop { id, .. }
+            | Command::If { id, .. }
+            | Command::Loop { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// How often the command ran in a profiling run. For loops this is the
+    /// count of the opening bracket, as shown by the profiler.
+    pub fn count(&self) -> usize {
+        match self {
+            Command::IncPointer { count, .. }
+            | Command::DecPointer { count, .. }
+            | Command::IncData { count, .. }
+            | Command::DecData { count, .. }
+            | Command::SetData { count, .. }
+            | Command::Scan { count, .. }
+            | Command::AddOffsetData { count, .. }
+            | Command::SubOffsetData { count, .. }
+            | Command::Output { count, .. }
+            | Command::Input { count, .. }
+            | Command::InfiniteLoop { count, .. }
+            | Command::If { count, .. } => *count,
+            Command::Loop { start_count, .. } => *start_count,
+        }
+    }
+
+    /// The byte written, if the command writes a constant
+    pub fn const_output(&self) -> Option<u8> {
+        match self {
+            Command::Output {
+                out_type: OutputType::Const(val),
+                ..
+            } => Some(*val),
+            _ => None,
+        }
+    }
+}
+
+/// Splits `commands` into runs of constant output, which the backends write as
+/// a single string, and single other commands
+pub fn output_runs(commands: &[Command]) -> impl Iterator<Item = &[Command]> {
+    commands.chunk_by(|a, b| a.const_output().is_some() && b.const_output().is_some())
+}
+
+/// Bytes written by a run of constant output from `output_runs`
+pub fn run_bytes(run: &[Command]) -> Vec<u8> {
+    run.iter().filter_map(Command::const_output).collect()
+}
+
+pub fn pretty_string(commands: &[Command]) -> String {
+    fn pretty_print_rec(
+        out: &mut String,
+        commands: &[Command],
+        indent_level: usize,
+        newline_end: &mut bool,
+    ) {
         let indent = "  ".repeat(indent_level);
 
         for command in commands {
             if *newline_end {
-                print!("{}", indent);
+                out.push_str(&indent);
             }
             match command {
-                Command::IncPointer { amount, .. } => {
-                    if *amount == 1 {
-                        print!(">");
-                    } else {
-                        print!("(>{})", amount);
-                    }
-                    *newline_end = false;
-                }
-                Command::DecPointer { amount, .. } => {
-                    if *amount == 1 {
-                        print!("<");
-                    } else {
-                        print!("(<{})", amount);
-                    }
-                    *newline_end = false;
-                }
-                Command::IncData { offset, amount, .. } => {
-                    let offset_str = if *offset == 0 {
-                        String::from("")
-                    } else {
-                        format!("({})", offset)
-                    };
-                    if *amount == 1 {
-                        print!("{}+", offset_str);
-                    } else {
-                        print!("({}+{})", offset_str, amount);
-                    }
-                    *newline_end = false;
-                }
-                Command::DecData { offset, amount, .. } => {
-                    let offset_str = if *offset == 0 {
-                        String::from("")
-                    } else {
-                        format!("({})", offset)
-                    };
-                    if *amount == 1 {
-                        print!("{}-", offset_str);
-                    } else {
-                        print!("({}-{})", offset_str, amount);
-                    }
-                    *newline_end = false;
-                }
-                Command::SetData { offset, value, .. } => {
-                    let offset_str = if *offset == 0 {
-                        String::from("")
-                    } else {
-                        format!("({})", offset)
-                    };
-                    print!("({}={})", offset_str, value);
-                    *newline_end = false;
-                }
-                Command::Scan {
-                    direction,
-                    skip_amount,
-                    ..
-                } => {
-                    match direction {
-                        Direction::Left => print!("[(<{})]", skip_amount),
-                        Direction::Right => print!("[(>{})]", skip_amount),
-                    }
-                    *newline_end = false;
-                }
-                Command::AddOffsetData {
-                    dest_offset,
-                    src_offset,
-                    multiplier,
-                    inverted,
-                    ..
-                } => {
-                    let mut dest_string = String::new();
-                    let inverted_str = if *inverted { "-" } else { "" };
-                    dest_string
-                        .push_str(&format!("{}({}*{})", inverted_str, src_offset, multiplier));
-                    print!("({}+={})", dest_offset, dest_string);
-                    *newline_end = false;
-                }
-                Command::SubOffsetData {
-                    dest_offset,
-                    src_offset,
-                    multiplier,
-                    inverted,
-                    ..
-                } => {
-                    let mut dest_string = String::new();
-                    let inverted_str = if *inverted { "-" } else { "" };
-                    dest_string
-                        .push_str(&format!("{}({}*{})", inverted_str, src_offset, multiplier));
-                    print!("({}-={})", dest_offset, dest_string);
-                    *newline_end = false;
-                }
-                Command::Output { out_type, .. } => {
-                    match out_type {
-                        OutputType::Const(val) => {
-                            print!("(.{val})");
-                        }
-                        OutputType::Cell { offset: _ } => {
-                            print!(".");
-                        }
-                    }
-                    *newline_end = false;
-                }
-                Command::Input { .. } => {
-                    print!(",");
-                    *newline_end = false;
-                }
-                Command::Loop { body, .. } => {
+                Command::If { body, .. } | Command::Loop { body, .. } => {
                     if !*newline_end {
-                        println!();
-                        print!("{}", indent);
+                        out.push('\n');
+                        out.push_str(&indent);
                     }
-                    println!("[");
+                    out.push_str(&format!("{}\n", pretty_command(command)));
                     *newline_end = true;
 
                     // Recursively pretty print the commands inside the loop
-                    pretty_print_rec(body, indent_level + 1, newline_end);
+                    pretty_print_rec(out, body, indent_level + 1, newline_end);
 
                     if !*newline_end {
-                        println!()
+                        out.push('\n');
                     }
-                    println!("{}]", indent);
+                    out.push_str(&format!("{}]\n", indent));
                     *newline_end = true;
                 }
+                _ => {
+                    out.push_str(&pretty_command(command));
+                    *newline_end = false;
+                }
             }
         }
 
         if !*newline_end {
-            println!();
+            out.push('\n');
             *newline_end = true;
         }
     }
 
     // Driver for recursive method
+    let mut out = String::new();
     let mut newline_end = true;
-    pretty_print_rec(&commands, 0, &mut newline_end);
+    pretty_print_rec(&mut out, commands, 0, &mut newline_end);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_ir;
+
+    #[test]
+    fn output_runs_group_constant_output() {
+        let commands =
+            parse_ir("out_const 72\nout_const 105\nout @0\nout_const 33\ninc @0 1\n").unwrap();
+        let runs: Vec<&[Command]> = output_runs(&commands).collect();
+        assert_eq!(runs.len(), 4);
+        assert_eq!(run_bytes(runs[0]), b"Hi");
+        assert_eq!(run_bytes(runs[1]), b"");
+        assert_eq!(run_bytes(runs[2]), b"!");
+        assert!(matches!(runs[3], [Command::IncData { .. }]));
+    }
+
+    #[test]
+    fn divisor_masks() {
+        assert_eq!(divisor_mask(0), None);
+        assert_eq!(divisor_mask(1), Some(0));
+        assert_eq!(divisor_mask(64), Some(63));
+        for divisor in [1, 2, 4, 8, 16, 32, 64, 128] {
+            let mask = divisor_mask(divisor).unwrap();
+            for cell in 0..=255u8 {
+                assert_eq!(cell & mask == 0, cell.is_multiple_of(divisor));
+            }
+        }
+    }
+
+    #[test]
+    fn tokenize_keeps_comments_and_positions() {
+        let tokens = tokenize("a +\n\t[b]");
+        let kinds: Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                &TokenKind::Comment(String::from("a ")),
+                &TokenKind::Command('+'),
+                &TokenKind::Comment(String::from("\n\t")),
+                &TokenKind::Command('['),
+                &TokenKind::Comment(String::from("b")),
+                &TokenKind::Command(']'),
+            ]
+        );
+        let positions: Vec<(usize, usize)> = tokens
+            .iter()
+            .map(|token| (token.line, token.column))
+            .collect();
+        assert_eq!(positions, [(1, 1), (1, 3), (1, 4), (2, 2), (2, 3), (2, 4)]);
+    }
 }
@@ -1,4 +1,4 @@
-use crate::parser::{Command, Direction, OutputType};
+use crate::parser::{divisor_mask, Command, Direction, OutputType};
 use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
@@ -38,7 +38,7 @@ fn check_loop_pointer(command: &Command) -> bool {
                 skip_amount,
                 ..
             } => *skip_amount == 0,
-            Command::Loop { id: _, body, .. } => {
+            Command::If { id: _, body, .. } | Command::Loop { id: _, body, .. } => {
                 let mut curr_rel_pointer = 0;
                 for loop_cmd in body {
                     if !check_loop_pointer_rec(loop_cmd, &mut curr_rel_pointer) {
@@ -148,11 +148,15 @@ fn step(
             id: _,
             direction,
             skip_amount,
+            offset,
             ..
         } => {
             let old_ptr = *pointer;
             loop {
-                match tape.get(&pointer).unwrap_or(&AbstractCell::Value(0)) {
+                match tape
+                    .get(&pointer.wrapping_add_signed(*offset))
+                    .unwrap_or(&AbstractCell::Value(0))
+                {
                     AbstractCell::Value(cell_val) => {
                         if *cell_val == 0 {
                             break Ok(None);
@@ -334,6 +338,66 @@ fn step(
             });
             Ok(None)
         }
+        Command::InfiniteLoop { divisor, .. } => {
+            add_prev_value(*pointer, tape, prev_values, inside_loop);
+            match tape.get(pointer).unwrap_or(&AbstractCell::Value(0)) {
+                AbstractCell::Value(cell_val) => {
+                    if *cell_val == 0 {
+                        Ok(None)
+                    } else if divisor_mask(*divisor).is_some_and(|mask| cell_val & mask == 0) {
+                        tape.insert(*pointer, AbstractCell::Value(0));
+                        Ok(None)
+                    } else {
+                        // Traps at runtime, so it has to stay in the program along
+                        // with the value that triggers it
+                        add_prev_value(*pointer, tape, prev_values, true);
+                        Ok(Some(()))
+                    }
+                }
+                // Either traps or clears the cell
+                AbstractCell::Top => {
+                    tape.insert(*pointer, AbstractCell::Value(0));
+                    Ok(Some(()))
+                }
+            }
+        }
+        Command::If { id: _, body, .. } => {
+            match tape.get(pointer).unwrap_or(&AbstractCell::Value(0)) {
+                AbstractCell::Value(cell_val) => {
+                    if *cell_val == 0 {
+                        return Ok(None);
+                    }
+                    add_prev_value(*pointer, tape, prev_values, true);
+                    let old_ptr = *pointer;
+                    let mut ret_val = None;
+                    for if_cmd in body {
+                        match step(if_cmd, tape, pointer, prev_values, cmd_buf, true) {
+                            Ok(Some(_)) => ret_val = Some(()),
+                            Ok(None) => (),
+                            Err(e) => {
+                                *pointer = old_ptr;
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(ret_val)
+                }
+                AbstractCell::Top => {
+                    // The body always leaves the pointer where it started
+                    add_prev_value(*pointer, tape, prev_values, true);
+                    let old_ptr = *pointer;
+                    for if_cmd in body {
+                        if let Err(e) =
+                            step_uncertain(if_cmd, tape, pointer, prev_values, cmd_buf, true)
+                        {
+                            *pointer = old_ptr;
+                            return Err(e);
+                        }
+                    }
+                    Ok(Some(()))
+                }
+            }
+        }
         Command::Loop { id: _, body, .. } => {
             match tape.get(pointer).unwrap_or(&AbstractCell::Value(0)) {
                 AbstractCell::Value(_) => {
@@ -460,11 +524,15 @@ fn step_uncertain(
             id: _,
             direction,
             skip_amount,
+            offset,
             ..
         } => {
             let old_ptr = *pointer;
             loop {
-                match tape.get(&pointer).unwrap_or(&AbstractCell::Value(0)) {
+                match tape
+                    .get(&pointer.wrapping_add_signed(*offset))
+                    .unwrap_or(&AbstractCell::Value(0))
+                {
                     AbstractCell::Value(cell_val) => {
                         if *cell_val == 0 {
                             break Ok(());
@@ -537,7 +605,12 @@ fn step_uncertain(
             tape.insert(pointer.wrapping_add_signed(*offset), AbstractCell::Top);
             Ok(())
         }
-        Command::Loop { id: _, body, .. } => {
+        Command::InfiniteLoop { .. } => {
+            add_prev_value(*pointer, tape, prev_values, true);
+            tape.insert(*pointer, AbstractCell::Top);
+            Ok(())
+        }
+        Command::If { id: _, body, .. } | Command::Loop { id: _, body, .. } => {
             if check_loop_pointer(command) {
                 add_prev_value(*pointer, tape, prev_values, true);
                 let old_ptr = *pointer;
@@ -560,7 +633,11 @@ fn step_uncertain(
     }
 }
 
-pub fn partial_eval(cmds: &Vec<Command>) -> Vec<Command> {
+/// Runs the parts of the program that don't depend on input ahead of time and
+/// returns what is left to do. With `keep_current_cell` the result ends on the
+/// cell the program ends on, holding its final value, as `--exit-code=cell`
+/// needs.
+pub fn partial_eval(cmds: &Vec<Command>, keep_current_cell: bool) -> Vec<Command> {
     let mut new_cmds: Vec<Command> = vec![];
 
     let mut tape: HashMap<usize, AbstractCell> = HashMap::new();
@@ -688,5 +765,69 @@ pub fn partial_eval(cmds: &Vec<Command>) -> Vec<Command> {
         }
     }
 
+    // After an error the rest of the program was copied, so it already ends on
+    // the right cell
+    if keep_current_cell && !error_occurred {
+        let pointer_diff = (pointer as isize) - (abstract_pointer as isize);
+        if pointer_diff > 0 {
+            new_cmds.push(Command::IncPointer {
+                amount: pointer_diff as usize,
+                count: 0,
+            });
+        } else if pointer_diff < 0 {
+            new_cmds.push(Command::DecPointer {
+                amount: -pointer_diff as usize,
+                count: 0,
+            });
+        }
+        let value = match tape.get(&pointer) {
+            Some(AbstractCell::Value(value)) => Some(*value),
+            // Set by commands left in the program
+            Some(AbstractCell::Top) => None,
+            None => Some(0),
+        };
+        if let Some(value) = value {
+            new_cmds.push(Command::SetData {
+                offset: 0,
+                value,
+                count: 0,
+            });
+        }
+    }
+
     return new_cmds;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::interp_io;
+    use crate::optimizer::tests::optimized_bf;
+    use crate::optimizer::InfiniteLoopMode;
+
+    #[test]
+    fn residual_programs_end_on_the_final_cell() {
+        let programs = [
+            "+++.",
+            ">++<+++>",
+            // Moves away from cells only the input decides
+            ",>+++++[<++>-]<[->>+<<]>>",
+            ",[>+<-]>+++<<++",
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..<",
+        ];
+        for src in programs {
+            let commands = optimized_bf(src, 3, InfiniteLoopMode::Ignore);
+            let mut expected = vec![];
+            let cell = interp_io(&mut commands.clone(), &mut &b"\x07"[..], &mut expected);
+            let mut residual = partial_eval(&commands, true);
+            let mut output = vec![];
+            assert_eq!(
+                interp_io(&mut residual, &mut &b"\x07"[..], &mut output),
+                cell,
+                "{}",
+                src
+            );
+            assert_eq!(output, expected, "{}", src);
+        }
+    }
+}
@@ -86,11 +86,17 @@ pub fn print_profile(commands: &[Command]) {
                     id: _,
                     direction,
                     skip_amount,
+                    offset,
                     count,
                 } => {
+                    let offset_str = if *offset == 0 {
+                        String::from("")
+                    } else {
+                        format!("({})", offset)
+                    };
                     let repr = match direction {
-                        Direction::Left => format!("[(<{})]", skip_amount),
-                        Direction::Right => format!("[(>{})]", skip_amount),
+                        Direction::Left => format!("[({}<{})]", offset_str, skip_amount),
+                        Direction::Right => format!("[({}>{})]", offset_str, skip_amount),
                     };
                     println!("{:>6} : {:^6} : {}", curr_idx, repr, count);
                 }
@@ -136,6 +142,27 @@ pub fn print_profile(commands: &[Command]) {
                 Command::Input { offset: _, count } => {
                     println!("{:>6} : {:^6} : {}", curr_idx, ",", count);
                 }
+                Command::InfiniteLoop {
+                    id: _,
+                    divisor,
+                    count,
+                } => {
+                    let repr = if *divisor == 0 {
+                        String::from("[!]")
+                    } else {
+                        format!("[!{}]", divisor)
+                    };
+                    println!("{:>6} : {:^6} : {}", curr_idx, repr, count);
+                }
+                Command::If { id: _, body, count } => {
+                    println!("{:>6} : {:^6} : {}", curr_idx, "?[", count);
+                    *curr_idx += 1;
+
+                    // Recursively print the commands inside the if
+                    print_profile_rec(body, curr_idx, simple_loops, non_simple_loops);
+
+                    println!("{:>6} : {:^6} : {}", curr_idx, "]", count);
+                }
                 Command::Loop {
                     id: _,
                     body,
@@ -0,0 +1,655 @@
+//! RISC-V backend, producing RV64GC assembly for `riscv64-linux-gnu`.
+//!
+//! The tape pointer lives in `s1`. Cells are addressed with a 12-bit offset
+//! from it when possible and through `t5` otherwise; `t6` holds immediates
+//! too large for `addi`. Conditional branches only reach 4 KiB, so loops and
+//! ifs branch over a `j` to their end instead of branching there directly.
+//!
+//! Scans are scalar loops unless `--simd rvv` is given, which tests several
+//! cells per iteration with strided loads from the vector extension.
+
+use crate::compiler::{
+    escape_ascii, CompileOptions, ExitCode, Simd, INIT_POINTER_LOC, INIT_TAPE_SIZE,
+    OUTPUT_BUFFER_SIZE, TAPE_PADDING,
+};
+use crate::debuginfo::LoopPart;
+use crate::parser::{divisor_mask, output_runs, run_bytes, Command, Direction, OutputType};
+
+const PTR_REG: &str = "s1";
+
+struct Riscv64Writer<'a> {
+    out: String,
+    options: &'a CompileOptions,
+    /// Number of constant strings emitted so far, used to name them
+    strings: usize,
+}
+
+impl Riscv64Writer<'_> {
+    /// `dest = src + value`, going through `t6` if `value` doesn't fit in 12 bits
+    fn add_imm(&mut self, dest: &str, src: &str, value: isize) {
+        if (-2048..2048).contains(&value) {
+            self.out
+                .push_str(&format!("    addi {}, {}, {}\n", dest, src, value));
+        } else {
+            self.out.push_str(&format!("    li   t6, {}\n", value));
+            self.out
+                .push_str(&format!("    add  {}, {}, t6\n", dest, src));
+        }
+    }
+
+    /// Address operand of the cell at `offset`, computed into `t5` if the
+    /// offset doesn't fit in the 12 bits loads and stores take
+    fn cell(&mut self, offset: isize) -> String {
+        if (-2048..2048).contains(&offset) {
+            format!("{}({})", offset, PTR_REG)
+        } else {
+            self.add_imm("t5", PTR_REG, offset);
+            String::from("0(t5)")
+        }
+    }
+
+    fn write_header(&mut self) {
+        if self.options.simd == Simd::Rvv {
+            // Lets the assembler accept vector instructions without `-march`
+            self.out.push_str(".attribute arch, \"rv64gcv\"\n");
+        }
+        if self.options.freestanding {
+            // The tape lives in `.bss`, which the kernel zeroes
+            self.out.push_str(&format!(
+                r#"
+.section .bss
+
+.lcomm tape, {}
+
+.section .text
+
+.globl _start
+
+_start:
+    lla  {}, tape
+"#,
+                INIT_TAPE_SIZE + 2 * TAPE_PADDING,
+                PTR_REG
+            ));
+        } else {
+            self.out.push_str(&format!(
+                r#"
+.section .text
+
+.globl main
+.type main, @function
+
+main:
+    addi sp, sp, -32
+    sd   ra, 24(sp)
+    sd   s1, 16(sp)
+    sd   s2, 8(sp)
+    sd   s3, 0(sp)
+
+    li   a0, {}
+    call malloc
+    mv   s2, a0 # Keep the tape address for free
+    mv   {}, a0
+"#,
+                INIT_TAPE_SIZE + 2 * TAPE_PADDING,
+                PTR_REG
+            ));
+        }
+        // Point past the padding to the middle of the tape
+        self.add_imm(PTR_REG, PTR_REG, (TAPE_PADDING + INIT_POINTER_LOC) as isize);
+        self.out.push_str("\n    # Begin program code\n");
+    }
+
+    fn write_footer(&mut self) {
+        let exit_status = match self.options.exit_code {
+            ExitCode::Zero => String::from("li   a0, 0"),
+            ExitCode::Cell => format!("lbu  a0, 0({})", PTR_REG),
+        };
+
+        if self.options.freestanding {
+            self.out.push_str(&format!(
+                r#"    # At bottom of _start
+    call flush_output
+
+    {}
+    li   a7, 94 # exit_group
+    ecall
+"#,
+                exit_status
+            ));
+            return;
+        }
+
+        self.out.push_str(&format!(
+            r#"    # At bottom of main
+    call flush_output
+
+    {}
+    mv   s3, a0 # Keep the exit status across the call to free
+    mv   a0, s2
+    call free
+    mv   a0, s3
+
+    ld   s3, 0(sp)
+    ld   s2, 8(sp)
+    ld   s1, 16(sp)
+    ld   ra, 24(sp)
+    addi sp, sp, 32
+    ret
+"#,
+            exit_status
+        ));
+    }
+
+    /// Routines for buffered output. `output_byte` appends the byte in `a0`,
+    /// `output_bytes` appends `a2` bytes starting at `a1` and `flush_output`
+    /// writes out the buffer. They only clobber caller saved registers.
+    fn write_output_runtime(&mut self) {
+        let write = if self.options.freestanding {
+            "li   a7, 64 # write\n    ecall"
+        } else {
+            "call write"
+        };
+        self.out.push_str(&format!(
+            r#"
+.section .bss
+
+.lcomm output_buffer, {size}
+.lcomm output_len, 8
+
+.section {text}
+
+output_byte:
+    lla  t0, output_len
+    ld   t1, 0(t0)
+    lla  t2, output_buffer
+    add  t2, t2, t1
+    sb   a0, 0(t2)
+    addi t1, t1, 1
+    sd   t1, 0(t0)
+    li   t2, {size}
+    beq  t1, t2, flush_output
+    ret
+
+output_bytes:
+    addi sp, sp, -32
+    sd   ra, 24(sp)
+    sd   s4, 16(sp)
+    sd   s5, 8(sp)
+    mv   s4, a1
+    mv   s5, a2
+output_bytes_loop:
+    beqz s5, output_bytes_end
+    lbu  a0, 0(s4)
+    addi s4, s4, 1
+    call output_byte
+    addi s5, s5, -1
+    j    output_bytes_loop
+output_bytes_end:
+    ld   s5, 8(sp)
+    ld   s4, 16(sp)
+    ld   ra, 24(sp)
+    addi sp, sp, 32
+    ret
+
+flush_output:
+    addi sp, sp, -32
+    sd   ra, 24(sp)
+    sd   s4, 16(sp)
+    sd   s5, 8(sp)
+    lla  s4, output_buffer
+    lla  t0, output_len
+    ld   s5, 0(t0)
+flush_output_loop:
+    blez s5, flush_output_end
+    li   a0, 1
+    mv   a1, s4
+    mv   a2, s5
+    {write}
+    blez a0, flush_output_end # Output is lost on write errors
+    add  s4, s4, a0
+    sub  s5, s5, a0
+    j    flush_output_loop
+flush_output_end:
+    lla  t0, output_len
+    sd   zero, 0(t0)
+    ld   s5, 8(sp)
+    ld   s4, 16(sp)
+    ld   ra, 24(sp)
+    addi sp, sp, 32
+    ret
+"#,
+            text = self.options.runtime_section(),
+            size = OUTPUT_BUFFER_SIZE,
+            write = write
+        ));
+    }
+
+    fn write_data_op(&mut self, offset: isize, amount: isize, comment: &str) {
+        self.out.push_str(&format!("    # {}\n", comment));
+        let cell = self.cell(offset);
+        self.out.push_str(&format!("    lbu  t0, {}\n", cell));
+        self.out.push_str(&format!("    addi t0, t0, {}\n", amount));
+        self.out.push_str(&format!("    sb   t0, {}\n", cell));
+        self.out.push('\n');
+    }
+
+    fn write_offset_data_op(
+        &mut self,
+        dest_offset: isize,
+        src_offset: isize,
+        multiplier: usize,
+        inverted: bool,
+        op: &str,
+    ) {
+        let src = self.cell(src_offset);
+        self.out.push_str(&format!("    lbu  t0, {}\n", src));
+        if inverted {
+            self.out.push_str("    neg  t0, t0\n");
+        }
+        // Only the low byte of the product matters
+        let multiplier = multiplier % 256;
+        if multiplier.is_power_of_two() {
+            if multiplier > 1 {
+                self.out.push_str(&format!(
+                    "    slli t0, t0, {}\n",
+                    multiplier.trailing_zeros()
+                ));
+            }
+        } else {
+            self.out.push_str(&format!("    li   t1, {}\n", multiplier));
+            self.out.push_str("    mul  t0, t0, t1\n");
+        }
+        let dest = self.cell(dest_offset);
+        self.out.push_str(&format!("    lbu  t1, {}\n", dest));
+        self.out.push_str(&format!("    {}  t1, t1, t0\n", op));
+        self.out.push_str(&format!("    sb   t1, {}\n", dest));
+    }
+
+    fn write_scan(&mut self, id: usize, direction: &Direction, skip_amount: usize, offset: isize) {
+        let step = match direction {
+            Direction::Left => "<",
+            Direction::Right => ">",
+        };
+        if offset == 0 {
+            self.out
+                .push_str(&format!("    # [{}]\n", step.repeat(skip_amount)));
+        } else {
+            self.out.push_str(&format!(
+                "    # [{}] testing cell {}\n",
+                step.repeat(skip_amount),
+                offset
+            ));
+        }
+
+        let label = format!("vector{}", id);
+        let stride = match direction {
+            Direction::Left => -(skip_amount as isize),
+            Direction::Right => skip_amount as isize,
+        };
+        if self.options.simd == Simd::Rvv && skip_amount <= TAPE_PADDING {
+            self.write_vector_scan(&label, stride, skip_amount, offset);
+        } else {
+            self.write_scalar_scan(&label, stride, offset);
+        }
+        self.out.push('\n');
+    }
+
+    fn write_scalar_scan(&mut self, label: &str, stride: isize, offset: isize) {
+        self.out.push_str(&format!("{}_loop_start:\n", label));
+        let cell = self.cell(offset);
+        self.out.push_str(&format!("    lbu  t0, {}\n", cell));
+        self.out
+            .push_str(&format!("    beqz t0, {}_found_zero\n", label));
+        self.add_imm(PTR_REG, PTR_REG, stride);
+        self.out
+            .push_str(&format!("    j    {}_loop_start\n", label));
+        self.out.push_str(&format!("{}_found_zero:\n", label));
+    }
+
+    /// Strided loads pick out exactly the cells the scan steps on, so no mask
+    /// is needed. The vector length is capped so that the cells loaded past
+    /// the zero never reach further than the tape padding.
+    fn write_vector_scan(&mut self, label: &str, stride: isize, skip_amount: usize, offset: isize) {
+        self.out.push_str(&format!("    li   t1, {}\n", stride));
+        self.out
+            .push_str(&format!("    li   t2, {}\n", TAPE_PADDING / skip_amount));
+        self.out.push_str("    vsetvli t3, t2, e8, m2, ta, ma\n");
+        // t4 is the distance covered by one iteration
+        self.out.push_str("    mul  t4, t3, t1\n");
+        // a0 is the address of the tested cell
+        self.add_imm("a0", PTR_REG, offset);
+        self.out.push_str(&format!("{}_loop_start:\n", label));
+        self.out.push_str("    vlse8.v v0, (a0), t1\n");
+        self.out.push_str("    vmseq.vi v2, v0, 0\n");
+        self.out.push_str("    vfirst.m t0, v2\n");
+        self.out
+            .push_str(&format!("    bgez t0, {}_found_zero\n", label));
+        self.out.push_str("    add  a0, a0, t4\n");
+        self.out
+            .push_str(&format!("    j    {}_loop_start\n", label));
+        self.out.push_str(&format!("{}_found_zero:\n", label));
+        self.out.push_str("    mul  t0, t0, t1\n");
+        self.out.push_str("    add  a0, a0, t0\n");
+        self.add_imm(PTR_REG, "a0", -offset);
+    }
+
+    /// Ties the following code to a part of loop `id` with `-g`, and shows
+    /// its source with `--annotate`
+    fn loop_marker(&mut self, id: usize, part: LoopPart) {
+        self.out.push_str(&self.options.loop_marker(id, part, "#"));
+    }
+
+    fn write_commands(&mut self, commands: &[Command]) {
+        for run in output_runs(commands) {
+            let command = &run[0];
+            self.out
+                .push_str(&self.options.command_marker(command, "#"));
+            match command {
+                Command::IncPointer { amount, .. } => {
+                    self.out.push_str("    # >\n");
+                    self.add_imm(PTR_REG, PTR_REG, *amount as isize);
+                    self.out.push('\n');
+                }
+                Command::DecPointer { amount, .. } => {
+                    self.out.push_str("    # <\n");
+                    self.add_imm(PTR_REG, PTR_REG, -(*amount as isize));
+                    self.out.push('\n');
+                }
+                Command::IncData { offset, amount, .. } => {
+                    self.write_data_op(*offset, *amount as isize, "+");
+                }
+                Command::DecData { offset, amount, .. } => {
+                    self.write_data_op(*offset, -(*amount as isize), "-");
+                }
+                Command::SetData { offset, value, .. } => {
+                    self.out.push_str(&format!("    # ={}\n", value));
+                    let cell = self.cell(*offset);
+                    if *value == 0 {
+                        self.out.push_str(&format!("    sb   zero, {}\n", cell));
+                    } else {
+                        self.out.push_str(&format!("    li   t0, {}\n", value));
+                        self.out.push_str(&format!("    sb   t0, {}\n", cell));
+                    }
+                }
+                Command::Scan {
+                    id,
+                    direction,
+                    skip_amount,
+                    offset,
+                    ..
+                } => {
+                    self.write_scan(*id, direction, *skip_amount, *offset);
+                    self.loop_marker(*id, LoopPart::After);
+                }
+                Command::AddOffsetData {
+                    src_offset,
+                    dest_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    self.write_offset_data_op(
+                        *dest_offset,
+                        *src_offset,
+                        *multiplier,
+                        *inverted,
+                        "add",
+                    );
+                }
+                Command::SubOffsetData {
+                    src_offset,
+                    dest_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    self.write_offset_data_op(
+                        *dest_offset,
+                        *src_offset,
+                        *multiplier,
+                        *inverted,
+                        "sub",
+                    );
+                }
+                Command::Output {
+                    out_type: OutputType::Const(val),
+                    ..
+                } => {
+                    let bytes = run_bytes(run);
+                    if bytes.len() == 1 {
+                        self.out.push_str("    # .\n");
+                        self.out.push_str(&format!("    li   a0, {}\n", val));
+                        self.out.push_str("    call output_byte\n");
+                    } else {
+                        let label = format!("string{}", self.strings);
+                        self.strings += 1;
+                        self.out.push_str(&format!("    # . x{}\n", bytes.len()));
+                        self.out.push_str("    .pushsection .rodata\n");
+                        self.out.push_str(&format!("{}:\n", label));
+                        self.out
+                            .push_str(&format!("    .ascii \"{}\"\n", escape_ascii(&bytes)));
+                        self.out.push_str("    .popsection\n");
+                        self.out.push_str(&format!("    lla  a1, {}\n", label));
+                        self.out
+                            .push_str(&format!("    li   a2, {}\n", bytes.len()));
+                        self.out.push_str("    call output_bytes\n");
+                    }
+                    self.out.push('\n');
+                }
+                Command::Output {
+                    out_type: OutputType::Cell { offset },
+                    ..
+                } => {
+                    self.out.push_str("    # .\n");
+                    let cell = self.cell(*offset);
+                    self.out.push_str(&format!("    lbu  a0, {}\n", cell));
+                    self.out.push_str("    call output_byte\n");
+                    self.out.push('\n');
+                }
+                Command::Input { offset, .. } if self.options.freestanding => {
+                    self.out.push_str("    # ,\n");
+                    self.out.push_str("    call flush_output\n");
+                    self.out.push_str("    li   a0, 0\n");
+                    self.add_imm("a1", PTR_REG, *offset);
+                    self.out.push_str("    li   a2, 1\n");
+                    self.out.push_str("    li   a7, 63 # read\n");
+                    self.out.push_str("    ecall\n");
+                    // Store -1 on end of input or error, like getchar
+                    self.out.push_str("    bgtz a0, 1f\n");
+                    self.out.push_str("    li   t0, 255\n");
+                    let cell = self.cell(*offset);
+                    self.out.push_str(&format!("    sb   t0, {}\n", cell));
+                    self.out.push_str("1:\n");
+                    self.out.push('\n');
+                }
+                Command::Input { offset, .. } => {
+                    self.out.push_str("    # ,\n");
+                    self.out.push_str("    call flush_output\n");
+                    self.out.push_str("    call getchar\n");
+                    let cell = self.cell(*offset);
+                    self.out.push_str(&format!("    sb   a0, {}\n", cell));
+                    self.out.push('\n');
+                }
+                Command::InfiniteLoop { id, divisor, .. } => {
+                    let msg = format!("infinite loop detected at loop {}", id);
+                    self.out.push_str("    # [!]\n");
+                    self.out.push_str(&format!("    lbu  t0, 0({})\n", PTR_REG));
+                    self.out
+                        .push_str(&format!("    beqz t0, infinite{}_end\n", id));
+                    if let Some(mask) = divisor_mask(*divisor) {
+                        // Every cell is a multiple of one
+                        if mask != 0 {
+                            self.out.push_str(&format!("    andi t1, t0, {}\n", mask));
+                            self.out
+                                .push_str(&format!("    bnez t1, infinite{}_trap\n", id));
+                        }
+                        self.out
+                            .push_str(&format!("    sb   zero, 0({})\n", PTR_REG));
+                        self.out.push_str(&format!("    j    infinite{}_end\n", id));
+                    }
+                    self.out.push_str(&format!("infinite{}_trap:\n", id));
+                    self.out.push_str("    call flush_output\n");
+                    self.out.push_str("    li   a0, 2\n");
+                    self.out
+                        .push_str(&format!("    lla  a1, infinite{}_msg\n", id));
+                    // Message length including the newline
+                    self.out
+                        .push_str(&format!("    li   a2, {}\n", msg.len() + 1));
+                    if self.options.freestanding {
+                        self.out.push_str("    li   a7, 64 # write\n");
+                        self.out.push_str("    ecall\n");
+                        self.out.push_str("    li   a0, 1\n");
+                        self.out.push_str("    li   a7, 94 # exit_group\n");
+                        self.out.push_str("    ecall\n");
+                    } else {
+                        self.out.push_str("    call write\n");
+                        self.out.push_str("    li   a0, 1\n");
+                        self.out.push_str("    call exit\n");
+                    }
+                    self.out.push_str("    .pushsection .rodata\n");
+                    self.out.push_str(&format!("infinite{}_msg:\n", id));
+                    self.out.push_str(&format!("    .ascii \"{}\\n\"\n", msg));
+                    self.out.push_str("    .popsection\n");
+                    self.out.push_str(&format!("infinite{}_end:\n", id));
+                    self.loop_marker(*id, LoopPart::After);
+                    self.out.push('\n');
+                }
+                Command::If { body, id, .. } => {
+                    self.out.push_str("    # ?[\n");
+                    self.out.push_str(&format!("    lbu  t0, 0({})\n", PTR_REG));
+                    self.out.push_str(&format!("    bnez t0, if{}_body\n", id));
+                    self.out.push_str(&format!("    j    if{}_end\n", id));
+                    self.out.push_str(&format!("if{}_body:\n", id));
+                    self.out.push('\n');
+                    self.loop_marker(*id, LoopPart::Body);
+
+                    self.write_commands(body);
+
+                    self.out.push_str("    # ]\n");
+                    self.out.push_str(&format!("if{}_end:\n", id));
+                    self.loop_marker(*id, LoopPart::After);
+                }
+                Command::Loop {
+                    body,
+                    id,
+                    end_count,
+                    ..
+                } => {
+                    self.out.push_str("    # [\n");
+                    self.out.push_str(&format!("loop{}:\n", id));
+                    self.out.push_str(&format!("    lbu  t0, 0({})\n", PTR_REG));
+                    self.out
+                        .push_str(&format!("    bnez t0, loop{}_body\n", id));
+                    self.out.push_str(&format!("    j    loop{}_end\n", id));
+                    self.out.push_str(&format!("loop{}_body:\n", id));
+                    self.out.push('\n');
+                    self.loop_marker(*id, LoopPart::Body);
+
+                    self.write_commands(body);
+
+                    self.out.push_str("    # ]\n");
+                    self.out
+                        .push_str(&self.options.loop_end_marker(*id, *end_count, "#"));
+                    self.out.push_str(&format!("    j    loop{}\n", id));
+                    self.out.push('\n');
+                    self.out.push_str(&format!("loop{}_end:\n", id));
+                    self.loop_marker(*id, LoopPart::After);
+                }
+            }
+        }
+    }
+}
+
+/// Generates the assembly for the whole program
+pub fn generate(commands: &[Command], options: &CompileOptions) -> String {
+    let mut writer = Riscv64Writer {
+        out: String::new(),
+        options,
+        strings: 0,
+    };
+    let source_map = options.debug_map();
+    if let Some(source_map) = source_map {
+        writer.out.push_str(&source_map.file_directive());
+    }
+    writer.write_header();
+    writer.out.push_str(&options.start_marker("#"));
+    writer.write_commands(commands);
+    writer.write_footer();
+    if let Some(source_map) = source_map {
+        writer.out.push_str(&source_map.end_label());
+    }
+    writer.write_output_runtime();
+    if let Some(source_map) = source_map {
+        // `s1` is `x9`, register 9 in the RISC-V DWARF numbering
+        writer
+            .out
+            .push_str(&source_map.debug_sections(options.entry(), 9));
+    }
+    writer.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::{cross_assemble, run_under_qemu, target_options, LOWERINGS, TRAP};
+    use crate::compiler::Target;
+    use crate::interp::interp_io;
+    use crate::ir::parse_ir;
+
+    #[test]
+    fn infinite_loops_by_one_clear_the_cell() {
+        let commands = parse_ir("inf_loop #1 1\ninf_loop #2 4\n").unwrap();
+        let asm = generate(&commands, &target_options(Target::Riscv64, Simd::None));
+        assert!(!asm.contains("andi t1, t0, 0\n"));
+        assert!(asm.contains("andi t1, t0, 3\n    bnez t1, infinite2_trap\n"));
+        assert!(asm.contains("beqz t0, infinite1_end\n    sb   zero, 0(s1)\n"));
+    }
+
+    #[test]
+    fn constant_output_is_written_at_once() {
+        let commands = parse_ir("out_const 72\nout_const 105\nout_const 10\n").unwrap();
+        let asm = generate(&commands, &target_options(Target::Riscv64, Simd::None));
+        assert!(asm.contains("    # . x3\n"));
+        assert!(asm.contains("    .ascii \"Hi\\012\"\n"));
+        assert!(asm.contains("    li   a2, 3\n    call output_bytes\n"));
+    }
+
+    #[test]
+    fn lowerings_assemble() {
+        for simd in [Simd::Rvv, Simd::None] {
+            for freestanding in [false, true] {
+                let options = CompileOptions {
+                    freestanding,
+                    ..target_options(Target::Riscv64, simd)
+                };
+                for ir in [LOWERINGS, TRAP] {
+                    let asm = generate(&parse_ir(ir).unwrap(), &options);
+                    let Some(result) = cross_assemble(&asm, Target::Riscv64) else {
+                        return;
+                    };
+                    result.unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lowerings_run_under_qemu() {
+        let commands = parse_ir(LOWERINGS).unwrap();
+        let mut expected = vec![];
+        interp_io(&mut commands.clone(), &mut &b"xyz"[..], &mut expected);
+        // qemu only runs vector code with a CPU that has the extension
+        let options = target_options(Target::Riscv64, Simd::None);
+        let Some(output) = run_under_qemu(&commands, &options, b"xyz") else {
+            return;
+        };
+        assert!(output.status.success());
+        assert_eq!(output.stdout, expected);
+
+        let output = run_under_qemu(&parse_ir(TRAP).unwrap(), &options, b"").unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stdout, b"Hi");
+        assert_eq!(output.stderr, b"infinite loop detected at loop 3\n");
+    }
+}
@@ -0,0 +1,342 @@
+//! Translates the command tree into Rust source.
+//!
+//! The program becomes `pub fn run(input, output) -> io::Result<u8>`, which
+//! returns the final cell like `interp::interp` does, plus a `main` that runs
+//! it on stdin and stdout. The file builds on its own with `rustc -O`, or `run`
+//! can be copied into another crate. Cells are `u8` and all arithmetic on them
+//! wraps, so the results match the interpreter's.
+
+use crate::compiler::{ExitCode, INIT_POINTER_LOC, INIT_TAPE_SIZE};
+use crate::parser::{divisor_mask, output_runs, run_bytes, Command, Direction, OutputType};
+
+struct RustWriter {
+    out: String,
+    indent: usize,
+    /// What the program does, so that the generated code declares nothing
+    /// that rustc would warn about as unused or needlessly mutable
+    moves_pointer: bool,
+    writes_tape: bool,
+    uses_input: bool,
+}
+
+/// Index expression of the cell at `offset`
+fn cell(offset: isize) -> String {
+    match offset {
+        0 => "p".to_string(),
+        offset if offset < 0 => format!("p - {}", -offset),
+        offset => format!("p + {}", offset),
+    }
+}
+
+/// Contents of a byte string literal for `bytes`
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => escaped.push_str(&format!("\\{}", byte as char)),
+            b'\n' => escaped.push_str("\\n"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+impl RustWriter {
+    fn line(&mut self, line: &str) {
+        self.out.push_str(&"    ".repeat(self.indent));
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn block(&mut self, header: &str, body: &[Command]) {
+        self.line(&format!("{} {{", header));
+        self.indent += 1;
+        self.write_commands(body);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    /// `tape[offset] = tape[offset].<method>(operand);`
+    fn update(&mut self, offset: isize, method: &str, operand: &str) {
+        self.writes_tape = true;
+        self.line(&format!(
+            "tape[{0}] = tape[{0}].{1}({2});",
+            cell(offset),
+            method,
+            operand
+        ));
+    }
+
+    fn write_commands(&mut self, commands: &[Command]) {
+        for run in output_runs(commands) {
+            let command = &run[0];
+            match command {
+                Command::IncPointer { amount, .. } => {
+                    self.moves_pointer = true;
+                    self.line(&format!("p += {};", amount));
+                }
+                Command::DecPointer { amount, .. } => {
+                    self.moves_pointer = true;
+                    self.line(&format!("p -= {};", amount));
+                }
+                Command::IncData { offset, amount, .. } => {
+                    self.update(*offset, "wrapping_add", &amount.to_string())
+                }
+                Command::DecData { offset, amount, .. } => {
+                    self.update(*offset, "wrapping_sub", &amount.to_string())
+                }
+                Command::SetData { offset, value, .. } => {
+                    self.writes_tape = true;
+                    self.line(&format!("tape[{}] = {};", cell(*offset), value));
+                }
+                Command::Scan {
+                    direction,
+                    skip_amount: 1,
+                    offset,
+                    ..
+                } => {
+                    // Searching the tape as a slice lets the standard library
+                    // compare many cells at once. Running off the end of the
+                    // tape panics, as it does in the interpreter.
+                    self.moves_pointer = true;
+                    match direction {
+                        Direction::Right => self.line(&format!(
+                            "p += tape[{}..].iter().position(|&c| c == 0).unwrap();",
+                            cell(*offset)
+                        )),
+                        Direction::Left => self.line(&format!(
+                            "p -= tape[..={}].iter().rev().position(|&c| c == 0).unwrap();",
+                            cell(*offset)
+                        )),
+                    }
+                }
+                Command::Scan {
+                    direction,
+                    skip_amount,
+                    offset,
+                    ..
+                } => {
+                    self.moves_pointer = true;
+                    let step = match direction {
+                        Direction::Left => "-=",
+                        Direction::Right => "+=",
+                    };
+                    self.line(&format!(
+                        "while tape[{}] != 0 {{ p {} {}; }}",
+                        cell(*offset),
+                        step,
+                        skip_amount
+                    ));
+                }
+                Command::AddOffsetData {
+                    dest_offset,
+                    src_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                }
+                | Command::SubOffsetData {
+                    dest_offset,
+                    src_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    // Subtracting the inverted source is the same as adding it
+                    let add = matches!(command, Command::AddOffsetData { .. }) != *inverted;
+                    let method = if add { "wrapping_add" } else { "wrapping_sub" };
+                    // Only the low byte of the product matters
+                    let multiplier = multiplier % 256;
+                    let src = format!("tape[{}]", cell(*src_offset));
+                    if multiplier == 1 {
+                        self.update(*dest_offset, method, &src);
+                    } else {
+                        self.update(
+                            *dest_offset,
+                            method,
+                            &format!("{}.wrapping_mul({})", src, multiplier),
+                        );
+                    }
+                }
+                Command::Output {
+                    out_type: OutputType::Const(_),
+                    ..
+                } => {
+                    let bytes = run_bytes(run);
+                    self.line(&format!(
+                        "output.write_all(b\"{}\")?;",
+                        escape_bytes(&bytes)
+                    ));
+                }
+                Command::Output {
+                    out_type: OutputType::Cell { offset },
+                    ..
+                } => self.line(&format!("output.write_all(&[tape[{}]])?;", cell(*offset))),
+                Command::Input { offset, .. } => {
+                    self.uses_input = true;
+                    self.writes_tape = true;
+                    self.line("output.flush()?;");
+                    self.line(&format!("tape[{}] = read_byte(input);", cell(*offset)));
+                }
+                Command::InfiniteLoop { id, divisor, .. } => {
+                    self.line("if tape[p] != 0 {");
+                    self.indent += 1;
+                    if let Some(mask) = divisor_mask(*divisor) {
+                        self.writes_tape = true;
+                        self.line(&format!("if tape[p] & {} == 0 {{", mask));
+                        self.indent += 1;
+                        self.line("tape[p] = 0;");
+                        self.indent -= 1;
+                        self.line("} else {");
+                        self.indent += 1;
+                    }
+                    self.line("output.flush()?;");
+                    self.line(&format!(
+                        "return Err(io::Error::other(\"infinite loop detected at loop {}\"));",
+                        id
+                    ));
+                    if divisor_mask(*divisor).is_some() {
+                        self.indent -= 1;
+                        self.line("}");
+                    }
+                    self.indent -= 1;
+                    self.line("}");
+                }
+                Command::If { body, .. } => self.block("if tape[p] != 0", body),
+                Command::Loop { body, .. } => self.block("while tape[p] != 0", body),
+            }
+        }
+    }
+}
+
+/// Returns Rust source for the program. `exit_code` decides the exit status
+/// of `main`, as with `--exit-code` when compiling.
+pub fn write_rust(commands: &[Command], exit_code: ExitCode) -> String {
+    let mut writer = RustWriter {
+        out: String::new(),
+        indent: 1,
+        moves_pointer: false,
+        writes_tape: false,
+        uses_input: false,
+    };
+    writer.write_commands(commands);
+
+    let read_byte = if writer.uses_input {
+        r#"
+/// Reads one byte, or returns 255 at the end of input or on errors
+fn read_byte(input: &mut impl Read) -> u8 {
+    let mut byte = [0];
+    match input.read_exact(&mut byte) {
+        Ok(()) => byte[0],
+        Err(_) => 255,
+    }
+}
+"#
+    } else {
+        ""
+    };
+    let status = match exit_code {
+        ExitCode::Zero => "Ok(_) => {}",
+        ExitCode::Cell => "Ok(cell) => std::process::exit(cell as i32),",
+    };
+    format!(
+        r#"// Generated by bfr
+
+use std::io::{{self, Read, Write}};
+
+const TAPE_SIZE: usize = {tape_size};
+{read_byte}
+/// Runs the program and returns the value of the current cell when it ends
+pub fn run({input}: &mut impl Read, output: &mut impl Write) -> io::Result<u8> {{
+    let {tape_mut}tape = vec![0u8; TAPE_SIZE];
+    let {p_mut}p = {pointer};
+
+{body}
+    output.flush()?;
+    Ok(tape[p])
+}}
+
+fn main() {{
+    let mut output = io::BufWriter::new(io::stdout().lock());
+    match run(&mut io::stdin().lock(), &mut output) {{
+        {status}
+        Err(e) => {{
+            eprintln!("{{}}", e);
+            std::process::exit(1);
+        }}
+    }}
+}}
+"#,
+        tape_size = INIT_TAPE_SIZE,
+        read_byte = read_byte,
+        input = if writer.uses_input { "input" } else { "_input" },
+        tape_mut = if writer.writes_tape { "mut " } else { "" },
+        p_mut = if writer.moves_pointer { "mut " } else { "" },
+        pointer = INIT_POINTER_LOC,
+        body = writer.out,
+        status = status
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::{installed, run_with_input};
+    use crate::compiler::TempDir;
+    use crate::interp::interp_io;
+    use crate::optimizer::tests::{optimized_bf, HELLO};
+    use crate::optimizer::InfiniteLoopMode;
+
+    /// Compiles the Rust translation of `src` with `rustc` and runs it on `input`
+    fn run_rust(src: &str, input: &[u8]) -> std::process::Output {
+        let commands = optimized_bf(src, 3, InfiniteLoopMode::Trap);
+        let dir = TempDir::new().unwrap();
+        let (rs_path, path) = (dir.file("a.rs"), dir.file("a.out"));
+        std::fs::write(&rs_path, write_rust(&commands, ExitCode::Cell)).unwrap();
+        let status = std::process::Command::new("rustc")
+            .args(["-O", "-D", "warnings", "-o", &path, &rs_path])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        run_with_input(&mut std::process::Command::new(&path), input)
+    }
+
+    #[test]
+    fn behaves_like_the_interpreter() {
+        if !installed("rustc") {
+            return;
+        }
+        let programs = [
+            HELLO,
+            // Scans with strides of one and two
+            "+>+>+>+>>+>>+>>+[<<]<[<]>[>]>>[>>]<+++++[<++++++++++>-]<.",
+            // Input multiplied into cells on both sides
+            ",[->+++<<++>]>.<<.",
+            // An infinite loop that ends because the input is even
+            ",[--]+++.",
+        ];
+        for src in programs {
+            let mut expected = vec![];
+            let cell = interp_io(
+                &mut crate::parser::parse(&src.to_string()),
+                &mut &b"xyz"[..],
+                &mut expected,
+            );
+            let output = run_rust(src, b"xyz");
+            assert_eq!(output.stdout, expected, "{}", src);
+            assert_eq!(output.status.code(), Some(cell as i32), "{}", src);
+        }
+    }
+
+    #[test]
+    fn infinite_loops_trap() {
+        if !installed("rustc") {
+            return;
+        }
+        let output = run_rust("++.>+++[]", b"");
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stdout, b"\x02");
+        assert_eq!(output.stderr, b"infinite loop detected at loop 1\n");
+    }
+}
@@ -0,0 +1,1034 @@
+//! WebAssembly backend, written either as WAT text or as a binary module.
+//!
+//! The program becomes the exported `_start` function of a WASI command, so
+//! runtimes such as wasmtime can run it directly. I/O goes through the
+//! `fd_read`, `fd_write` and `proc_exit` imports from `wasi_snapshot_preview1`,
+//! with output collected in a buffer like the native backends do.
+//!
+//! Linear memory is laid out as
+//!
+//! ```text
+//! 0       iovec used for fd_read/fd_write (8 bytes), then the byte count (4 bytes)
+//! 16      output buffer
+//! 0x10000 tape, padded on both sides
+//! ...     constant strings
+//! ```
+//!
+//! Both encodings are produced from the same list of `Instr`s, so they always
+//! describe the same module.
+
+use crate::compiler::{
+    ExitCode, INIT_POINTER_LOC, INIT_TAPE_SIZE, OUTPUT_BUFFER_SIZE, TAPE_PADDING,
+};
+use crate::parser::{divisor_mask, output_runs, run_bytes, Command, Direction, OutputType};
+
+const IOVEC: i32 = 0;
+const BYTE_COUNT: i32 = 8;
+const OUTPUT_BUFFER: i32 = 16;
+const TAPE_START: usize = 0x10000;
+const PAGE_SIZE: usize = 0x10000;
+/// Bytes compared per iteration of a SIMD128 scan
+const VECTOR_WIDTH: usize = 16;
+
+/// Function indices. Imports come first, as in the binary format.
+const FD_WRITE: u32 = 0;
+const FD_READ: u32 = 1;
+const PROC_EXIT: u32 = 2;
+const START: u32 = 3;
+const OUTPUT_BYTE: u32 = 4;
+const OUTPUT_BYTES: u32 = 5;
+const FLUSH_OUTPUT: u32 = 6;
+const FUNCTION_NAMES: [&str; 7] = [
+    "fd_write",
+    "fd_read",
+    "proc_exit",
+    "_start",
+    "output_byte",
+    "output_bytes",
+    "flush_output",
+];
+
+/// The instructions the backend uses. Branch targets are relative depths.
+enum Instr {
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Call(u32),
+    Drop,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I32Load(u32),
+    I32Load8U(u32),
+    I32Store(u32),
+    I32Store8(u32),
+    I32Const(i32),
+    I32Eqz,
+    I32Eq,
+    I32Clz,
+    I32Ctz,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32And,
+    I32Or,
+    V128Load(u32),
+    V128Const([u8; 16]),
+    V128Or,
+    I8x16Eq,
+    I8x16Bitmask,
+}
+
+impl Instr {
+    /// Text form. `locals` names the function's parameters and locals.
+    fn wat(&self, locals: &[&str]) -> String {
+        fn memarg(op: &str, offset: u32) -> String {
+            if offset == 0 {
+                op.to_string()
+            } else {
+                format!("{} offset={}", op, offset)
+            }
+        }
+        match self {
+            Instr::Block => "block".to_string(),
+            Instr::Loop => "loop".to_string(),
+            Instr::If => "if".to_string(),
+            Instr::Else => "else".to_string(),
+            Instr::End => "end".to_string(),
+            Instr::Br(depth) => format!("br {}", depth),
+            Instr::BrIf(depth) => format!("br_if {}", depth),
+            Instr::Call(function) => format!("call ${}", FUNCTION_NAMES[*function as usize]),
+            Instr::Drop => "drop".to_string(),
+            Instr::LocalGet(local) => format!("local.get ${}", locals[*local as usize]),
+            Instr::LocalSet(local) => format!("local.set ${}", locals[*local as usize]),
+            Instr::LocalTee(local) => format!("local.tee ${}", locals[*local as usize]),
+            Instr::GlobalGet(_) => "global.get $output_len".to_string(),
+            Instr::GlobalSet(_) => "global.set $output_len".to_string(),
+            Instr::I32Load(offset) => memarg("i32.load", *offset),
+            Instr::I32Load8U(offset) => memarg("i32.load8_u", *offset),
+            Instr::I32Store(offset) => memarg("i32.store", *offset),
+            Instr::I32Store8(offset) => memarg("i32.store8", *offset),
+            Instr::I32Const(value) => format!("i32.const {}", value),
+            Instr::I32Eqz => "i32.eqz".to_string(),
+            Instr::I32Eq => "i32.eq".to_string(),
+            Instr::I32Clz => "i32.clz".to_string(),
+            Instr::I32Ctz => "i32.ctz".to_string(),
+            Instr::I32Add => "i32.add".to_string(),
+            Instr::I32Sub => "i32.sub".to_string(),
+            Instr::I32Mul => "i32.mul".to_string(),
+            Instr::I32And => "i32.and".to_string(),
+            Instr::I32Or => "i32.or".to_string(),
+            Instr::V128Load(offset) => memarg("v128.load", *offset),
+            Instr::V128Const(bytes) => {
+                let lanes: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+                format!("v128.const i8x16 {}", lanes.join(" "))
+            }
+            Instr::V128Or => "v128.or".to_string(),
+            Instr::I8x16Eq => "i8x16.eq".to_string(),
+            Instr::I8x16Bitmask => "i8x16.bitmask".to_string(),
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        // Memory operands are a log2 alignment hint followed by the offset
+        fn memarg(out: &mut Vec<u8>, align: u32, offset: u32) {
+            write_u32(out, align);
+            write_u32(out, offset);
+        }
+        fn simd(out: &mut Vec<u8>, opcode: u32) {
+            out.push(0xFD);
+            write_u32(out, opcode);
+        }
+        const EMPTY_BLOCK_TYPE: u8 = 0x40;
+        match self {
+            Instr::Block => out.extend([0x02, EMPTY_BLOCK_TYPE]),
+            Instr::Loop => out.extend([0x03, EMPTY_BLOCK_TYPE]),
+            Instr::If => out.extend([0x04, EMPTY_BLOCK_TYPE]),
+            Instr::Else => out.push(0x05),
+            Instr::End => out.push(0x0B),
+            Instr::Br(depth) => {
+                out.push(0x0C);
+                write_u32(out, *depth);
+            }
+            Instr::BrIf(depth) => {
+                out.push(0x0D);
+                write_u32(out, *depth);
+            }
+            Instr::Call(function) => {
+                out.push(0x10);
+                write_u32(out, *function);
+            }
+            Instr::Drop => out.push(0x1A),
+            Instr::LocalGet(local) => {
+                out.push(0x20);
+                write_u32(out, *local);
+            }
+            Instr::LocalSet(local) => {
+                out.push(0x21);
+                write_u32(out, *local);
+            }
+            Instr::LocalTee(local) => {
+                out.push(0x22);
+                write_u32(out, *local);
+            }
+            Instr::GlobalGet(global) => {
+                out.push(0x23);
+                write_u32(out, *global);
+            }
+            Instr::GlobalSet(global) => {
+                out.push(0x24);
+                write_u32(out, *global);
+            }
+            Instr::I32Load(offset) => {
+                out.push(0x28);
+                memarg(out, 2, *offset);
+            }
+            Instr::I32Load8U(offset) => {
+                out.push(0x2D);
+                memarg(out, 0, *offset);
+            }
+            Instr::I32Store(offset) => {
+                out.push(0x36);
+                memarg(out, 2, *offset);
+            }
+            Instr::I32Store8(offset) => {
+                out.push(0x3A);
+                memarg(out, 0, *offset);
+            }
+            Instr::I32Const(value) => {
+                out.push(0x41);
+                write_i32(out, *value);
+            }
+            Instr::I32Eqz => out.push(0x45),
+            Instr::I32Eq => out.push(0x46),
+            Instr::I32Clz => out.push(0x67),
+            Instr::I32Ctz => out.push(0x68),
+            Instr::I32Add => out.push(0x6A),
+            Instr::I32Sub => out.push(0x6B),
+            Instr::I32Mul => out.push(0x6C),
+            Instr::I32And => out.push(0x71),
+            Instr::I32Or => out.push(0x72),
+            Instr::V128Load(offset) => {
+                simd(out, 0x00);
+                memarg(out, 0, *offset);
+            }
+            Instr::V128Const(bytes) => {
+                simd(out, 0x0C);
+                out.extend(bytes);
+            }
+            Instr::V128Or => simd(out, 0x50),
+            Instr::I8x16Eq => simd(out, 0x23),
+            Instr::I8x16Bitmask => simd(out, 0x64),
+        }
+    }
+}
+
+/// Unsigned LEB128
+fn write_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Signed LEB128
+fn write_i32(out: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_u32(out, name.len() as u32);
+    out.extend(name.as_bytes());
+}
+
+/// Appends a section with its id and size
+fn write_section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    write_u32(out, contents.len() as u32);
+    out.extend(contents);
+}
+
+/// Function types, referred to by index
+const TYPE_IO: u32 = 0;
+const TYPE_I32: u32 = 1;
+const TYPE_EMPTY: u32 = 2;
+const TYPE_I32_I32: u32 = 3;
+/// Parameters and results of each type
+const TYPES: [(&[&str], &[&str]); 4] = [
+    (&["i32", "i32", "i32", "i32"], &["i32"]),
+    (&["i32"], &[]),
+    (&[], &[]),
+    (&["i32", "i32"], &[]),
+];
+
+struct Function {
+    type_index: u32,
+    /// Names of the parameters, then of the i32 locals
+    params: Vec<&'static str>,
+    locals: Vec<&'static str>,
+    body: Vec<Instr>,
+}
+
+pub struct WasmOptions {
+    /// Use SIMD128 for scans with a stride of at most 16
+    pub simd: bool,
+    pub exit_code: ExitCode,
+}
+
+/// Locals of `_start`
+const P: u32 = 0;
+const SCAN_ADDR: u32 = 1;
+const SCAN_MASK: u32 = 2;
+
+struct WasmWriter<'a> {
+    body: Vec<Instr>,
+    options: &'a WasmOptions,
+    /// Constant strings, placed after the tape
+    data: Vec<u8>,
+}
+
+impl WasmWriter<'_> {
+    fn data_start() -> usize {
+        TAPE_START + INIT_TAPE_SIZE + 2 * TAPE_PADDING
+    }
+
+    /// Adds `bytes` to the data segment and returns their address
+    fn add_data(&mut self, bytes: &[u8]) -> i32 {
+        let addr = Self::data_start() + self.data.len();
+        self.data.extend(bytes);
+        addr as i32
+    }
+
+    fn add_pointer(&mut self, amount: isize) {
+        self.body.push(Instr::LocalGet(P));
+        self.body.push(Instr::I32Const(amount as i32));
+        self.body.push(Instr::I32Add);
+        self.body.push(Instr::LocalSet(P));
+    }
+
+    /// Pushes the address for a cell access and returns the memory offset to
+    /// use with it. Offsets must be unsigned, so negative ones are added to the
+    /// pointer instead.
+    fn cell_address(&mut self, offset: isize) -> u32 {
+        self.body.push(Instr::LocalGet(P));
+        if offset >= 0 {
+            offset as u32
+        } else {
+            self.body.push(Instr::I32Const(offset as i32));
+            self.body.push(Instr::I32Add);
+            0
+        }
+    }
+
+    fn load_cell(&mut self, offset: isize) {
+        let memory_offset = self.cell_address(offset);
+        self.body.push(Instr::I32Load8U(memory_offset));
+    }
+
+    /// Stores the value `value` pushes into the cell at `offset`
+    fn store_cell(&mut self, offset: isize, value: impl FnOnce(&mut Self)) {
+        let memory_offset = self.cell_address(offset);
+        value(self);
+        self.body.push(Instr::I32Store8(memory_offset));
+    }
+
+    /// Points the iovec at `len` bytes starting at the address `addr` pushes
+    fn set_iovec(&mut self, addr: impl FnOnce(&mut Self), len: i32) {
+        self.body.push(Instr::I32Const(IOVEC));
+        addr(self);
+        self.body.push(Instr::I32Store(0));
+        self.body.push(Instr::I32Const(IOVEC));
+        self.body.push(Instr::I32Const(len));
+        self.body.push(Instr::I32Store(4));
+    }
+
+    fn write_scan(&mut self, direction: &Direction, skip_amount: usize, offset: isize) {
+        let stride = match direction {
+            Direction::Left => -(skip_amount as isize),
+            Direction::Right => skip_amount as isize,
+        };
+        if !self.options.simd || skip_amount > VECTOR_WIDTH {
+            self.body.push(Instr::Block);
+            self.body.push(Instr::Loop);
+            self.load_cell(offset);
+            self.body.push(Instr::I32Eqz);
+            self.body.push(Instr::BrIf(1));
+            self.add_pointer(stride);
+            self.body.push(Instr::Br(0));
+            self.body.push(Instr::End);
+            self.body.push(Instr::End);
+            return;
+        }
+
+        // Each iteration tests the cells on the stride within one vector, so
+        // the window advances by the largest multiple of the stride that fits
+        let chunk = (VECTOR_WIDTH - VECTOR_WIDTH % skip_amount) as i32;
+        let mask = crate::compiler::scan_mask(direction, skip_amount, VECTOR_WIDTH);
+        // SCAN_ADDR is the address of the tested cell
+        self.body.push(Instr::LocalGet(P));
+        if offset != 0 {
+            self.body.push(Instr::I32Const(offset as i32));
+            self.body.push(Instr::I32Add);
+        }
+        self.body.push(Instr::LocalSet(SCAN_ADDR));
+        self.body.push(Instr::Block);
+        self.body.push(Instr::Loop);
+        self.body.push(Instr::LocalGet(SCAN_ADDR));
+        if let Direction::Left = direction {
+            // The window ends at the tested cell
+            self.body.push(Instr::I32Const(VECTOR_WIDTH as i32 - 1));
+            self.body.push(Instr::I32Sub);
+        }
+        self.body.push(Instr::V128Load(0));
+        if skip_amount > 1 {
+            self.body.push(Instr::V128Const(mask.try_into().unwrap()));
+            self.body.push(Instr::V128Or);
+        }
+        self.body.push(Instr::V128Const([0; 16]));
+        self.body.push(Instr::I8x16Eq);
+        self.body.push(Instr::I8x16Bitmask);
+        self.body.push(Instr::LocalTee(SCAN_MASK));
+        self.body.push(Instr::BrIf(1));
+        self.body.push(Instr::LocalGet(SCAN_ADDR));
+        self.body
+            .push(Instr::I32Const(if stride < 0 { -chunk } else { chunk }));
+        self.body.push(Instr::I32Add);
+        self.body.push(Instr::LocalSet(SCAN_ADDR));
+        self.body.push(Instr::Br(0));
+        self.body.push(Instr::End);
+        self.body.push(Instr::End);
+        // The zero nearest to the start of the scan
+        self.body.push(Instr::LocalGet(SCAN_ADDR));
+        self.body.push(Instr::LocalGet(SCAN_MASK));
+        match direction {
+            Direction::Right => {
+                self.body.push(Instr::I32Ctz);
+                self.body.push(Instr::I32Add);
+            }
+            Direction::Left => {
+                // The highest set bit is at 31 - clz, which is clz - 16 cells
+                // before the end of the window
+                self.body.push(Instr::I32Clz);
+                self.body.push(Instr::I32Sub);
+                self.body.push(Instr::I32Const(VECTOR_WIDTH as i32));
+                self.body.push(Instr::I32Add);
+            }
+        }
+        if offset != 0 {
+            self.body.push(Instr::I32Const(offset as i32));
+            self.body.push(Instr::I32Sub);
+        }
+        self.body.push(Instr::LocalSet(P));
+    }
+
+    /// Prints the message for an infinite loop to stderr and exits with status 1
+    fn write_infinite_loop_trap(&mut self, id: usize) {
+        let msg = format!("infinite loop detected at loop {}\n", id);
+        let addr = self.add_data(msg.as_bytes());
+        self.body.push(Instr::Call(FLUSH_OUTPUT));
+        self.set_iovec(|w| w.body.push(Instr::I32Const(addr)), msg.len() as i32);
+        self.body.push(Instr::I32Const(2));
+        self.body.push(Instr::I32Const(IOVEC));
+        self.body.push(Instr::I32Const(1));
+        self.body.push(Instr::I32Const(BYTE_COUNT));
+        self.body.push(Instr::Call(FD_WRITE));
+        self.body.push(Instr::Drop);
+        self.body.push(Instr::I32Const(1));
+        self.body.push(Instr::Call(PROC_EXIT));
+    }
+
+    fn write_commands(&mut self, commands: &[Command]) {
+        for run in output_runs(commands) {
+            let command = &run[0];
+            match command {
+                Command::IncPointer { amount, .. } => self.add_pointer(*amount as isize),
+                Command::DecPointer { amount, .. } => self.add_pointer(-(*amount as isize)),
+                Command::IncData { offset, amount, .. } => {
+                    self.store_cell(*offset, |w| {
+                        w.load_cell(*offset);
+                        w.body.push(Instr::I32Const(*amount as i32));
+                        w.body.push(Instr::I32Add);
+                    });
+                }
+                Command::DecData { offset, amount, .. } => {
+                    self.store_cell(*offset, |w| {
+                        w.load_cell(*offset);
+                        w.body.push(Instr::I32Const(*amount as i32));
+                        w.body.push(Instr::I32Sub);
+                    });
+                }
+                Command::SetData { offset, value, .. } => {
+                    self.store_cell(*offset, |w| w.body.push(Instr::I32Const(*value as i32)));
+                }
+                Command::Scan {
+                    direction,
+                    skip_amount,
+                    offset,
+                    ..
+                } => self.write_scan(direction, *skip_amount, *offset),
+                Command::AddOffsetData {
+                    dest_offset,
+                    src_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                }
+                | Command::SubOffsetData {
+                    dest_offset,
+                    src_offset,
+                    multiplier,
+                    inverted,
+                    ..
+                } => {
+                    // Subtracting the inverted source is the same as adding it
+                    let add = matches!(command, Command::AddOffsetData { .. }) != *inverted;
+                    self.store_cell(*dest_offset, |w| {
+                        w.load_cell(*dest_offset);
+                        w.load_cell(*src_offset);
+                        w.body.push(Instr::I32Const((multiplier % 256) as i32));
+                        w.body.push(Instr::I32Mul);
+                        w.body.push(if add { Instr::I32Add } else { Instr::I32Sub });
+                    });
+                }
+                Command::Output {
+                    out_type: OutputType::Const(val),
+                    ..
+                } => {
+                    let bytes = run_bytes(run);
+                    if bytes.len() == 1 {
+                        self.body.push(Instr::I32Const(*val as i32));
+                        self.body.push(Instr::Call(OUTPUT_BYTE));
+                    } else {
+                        let addr = self.add_data(&bytes);
+                        self.body.push(Instr::I32Const(addr));
+                        self.body.push(Instr::I32Const(bytes.len() as i32));
+                        self.body.push(Instr::Call(OUTPUT_BYTES));
+                    }
+                }
+                Command::Output {
+                    out_type: OutputType::Cell { offset },
+                    ..
+                } => {
+                    self.load_cell(*offset);
+                    self.body.push(Instr::Call(OUTPUT_BYTE));
+                }
+                Command::Input { offset, .. } => {
+                    self.body.push(Instr::Call(FLUSH_OUTPUT));
+                    self.set_iovec(
+                        |w| {
+                            w.body.push(Instr::LocalGet(P));
+                            w.body.push(Instr::I32Const(*offset as i32));
+                            w.body.push(Instr::I32Add);
+                        },
+                        1,
+                    );
+                    self.body.push(Instr::I32Const(0));
+                    self.body.push(Instr::I32Const(IOVEC));
+                    self.body.push(Instr::I32Const(1));
+                    self.body.push(Instr::I32Const(BYTE_COUNT));
+                    self.body.push(Instr::Call(FD_READ));
+                    // Store -1 on end of input or error, like getchar
+                    self.body.push(Instr::I32Const(BYTE_COUNT));
+                    self.body.push(Instr::I32Load(0));
+                    self.body.push(Instr::I32Eqz);
+                    self.body.push(Instr::I32Or);
+                    self.body.push(Instr::If);
+                    self.store_cell(*offset, |w| w.body.push(Instr::I32Const(255)));
+                    self.body.push(Instr::End);
+                }
+                Command::InfiniteLoop { id, divisor, .. } => {
+                    self.load_cell(0);
+                    self.body.push(Instr::If);
+                    match divisor_mask(*divisor) {
+                        // Every cell is a multiple of one
+                        Some(0) => self.store_cell(0, |w| w.body.push(Instr::I32Const(0))),
+                        Some(mask) => {
+                            self.load_cell(0);
+                            self.body.push(Instr::I32Const(mask as i32));
+                            self.body.push(Instr::I32And);
+                            self.body.push(Instr::I32Eqz);
+                            self.body.push(Instr::If);
+                            self.store_cell(0, |w| w.body.push(Instr::I32Const(0)));
+                            self.body.push(Instr::Else);
+                            self.write_infinite_loop_trap(*id);
+                            self.body.push(Instr::End);
+                        }
+                        None => self.write_infinite_loop_trap(*id),
+                    }
+                    self.body.push(Instr::End);
+                }
+                Command::If { body, .. } => {
+                    self.load_cell(0);
+                    self.body.push(Instr::If);
+                    self.write_commands(body);
+                    self.body.push(Instr::End);
+                }
+                Command::Loop { body, .. } => {
+                    self.body.push(Instr::Block);
+                    self.body.push(Instr::Loop);
+                    self.load_cell(0);
+                    self.body.push(Instr::I32Eqz);
+                    self.body.push(Instr::BrIf(1));
+                    self.write_commands(body);
+                    self.body.push(Instr::Br(0));
+                    self.body.push(Instr::End);
+                    self.body.push(Instr::End);
+                }
+            }
+        }
+    }
+}
+
+/// `output_byte(byte)`: appends a byte to the output buffer
+fn output_byte() -> Function {
+    use Instr::*;
+    Function {
+        type_index: TYPE_I32,
+        params: vec!["byte"],
+        locals: vec![],
+        body: vec![
+            GlobalGet(0),
+            LocalGet(0),
+            I32Store8(OUTPUT_BUFFER as u32),
+            GlobalGet(0),
+            I32Const(1),
+            I32Add,
+            GlobalSet(0),
+            GlobalGet(0),
+            I32Const(OUTPUT_BUFFER_SIZE as i32),
+            I32Eq,
+            If,
+            Call(FLUSH_OUTPUT),
+            End,
+        ],
+    }
+}
+
+/// `output_bytes(addr, len)`: appends `len` bytes starting at `addr`
+fn output_bytes() -> Function {
+    use Instr::*;
+    Function {
+        type_index: TYPE_I32_I32,
+        params: vec!["addr", "len"],
+        locals: vec![],
+        body: vec![
+            Block,
+            Loop,
+            LocalGet(1),
+            I32Eqz,
+            BrIf(1),
+            LocalGet(0),
+            I32Load8U(0),
+            Call(OUTPUT_BYTE),
+            LocalGet(0),
+            I32Const(1),
+            I32Add,
+            LocalSet(0),
+            LocalGet(1),
+            I32Const(1),
+            I32Sub,
+            LocalSet(1),
+            Br(0),
+            End,
+            End,
+        ],
+    }
+}
+
+/// `flush_output()`: writes out the buffer, retrying after partial writes
+fn flush_output() -> Function {
+    use Instr::*;
+    Function {
+        type_index: TYPE_EMPTY,
+        params: vec![],
+        locals: vec!["addr"],
+        body: vec![
+            I32Const(OUTPUT_BUFFER),
+            LocalSet(0),
+            Block,
+            Loop,
+            GlobalGet(0),
+            I32Eqz,
+            BrIf(1),
+            I32Const(IOVEC),
+            LocalGet(0),
+            I32Store(0),
+            I32Const(IOVEC),
+            GlobalGet(0),
+            I32Store(4),
+            I32Const(1),
+            I32Const(IOVEC),
+            I32Const(1),
+            I32Const(BYTE_COUNT),
+            Call(FD_WRITE),
+            // Output is lost on write errors
+            BrIf(1),
+            I32Const(BYTE_COUNT),
+            I32Load(0),
+            I32Eqz,
+            BrIf(1),
+            LocalGet(0),
+            I32Const(BYTE_COUNT),
+            I32Load(0),
+            I32Add,
+            LocalSet(0),
+            GlobalGet(0),
+            I32Const(BYTE_COUNT),
+            I32Load(0),
+            I32Sub,
+            GlobalSet(0),
+            Br(0),
+            End,
+            End,
+            I32Const(0),
+            GlobalSet(0),
+        ],
+    }
+}
+
+struct Module {
+    functions: Vec<Function>,
+    pages: u32,
+    data_start: usize,
+    data: Vec<u8>,
+}
+
+fn build_module(commands: &[Command], options: &WasmOptions) -> Module {
+    let mut writer = WasmWriter {
+        body: vec![],
+        options,
+        data: vec![],
+    };
+    writer.body.push(Instr::I32Const(
+        (TAPE_START + TAPE_PADDING + INIT_POINTER_LOC) as i32,
+    ));
+    writer.body.push(Instr::LocalSet(P));
+    writer.write_commands(commands);
+    writer.body.push(Instr::Call(FLUSH_OUTPUT));
+    if options.exit_code == ExitCode::Cell {
+        writer.load_cell(0);
+        writer.body.push(Instr::Call(PROC_EXIT));
+    }
+
+    let data_start = WasmWriter::data_start();
+    let pages = (data_start + writer.data.len()).div_ceil(PAGE_SIZE) as u32;
+    Module {
+        functions: vec![
+            Function {
+                type_index: TYPE_EMPTY,
+                params: vec![],
+                locals: vec!["p", "scan_addr", "scan_mask"],
+                body: writer.body,
+            },
+            output_byte(),
+            output_bytes(),
+            flush_output(),
+        ],
+        pages,
+        data_start,
+        data: writer.data,
+    }
+}
+
+fn escape_wat(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => escaped.push_str(&format!("\\{}", byte as char)),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+/// Returns the module in the WebAssembly text format
+pub fn write_wat(commands: &[Command], options: &WasmOptions) -> String {
+    let module = build_module(commands, options);
+    let mut out = String::from("(module\n");
+    for (function, name) in [
+        (FD_WRITE, "fd_write"),
+        (FD_READ, "fd_read"),
+        (PROC_EXIT, "proc_exit"),
+    ] {
+        let type_index = if function == PROC_EXIT {
+            TYPE_I32
+        } else {
+            TYPE_IO
+        };
+        let (params, results) = TYPES[type_index as usize];
+        out.push_str(&format!(
+            "  (import \"wasi_snapshot_preview1\" \"{}\" (func ${}{}{}))\n",
+            name,
+            name,
+            if params.is_empty() {
+                String::new()
+            } else {
+                format!(" (param {})", params.join(" "))
+            },
+            if results.is_empty() {
+                String::new()
+            } else {
+                format!(" (result {})", results.join(" "))
+            }
+        ));
+    }
+    out.push_str(&format!(
+        "  (memory (export \"memory\") {})\n",
+        module.pages
+    ));
+    out.push_str("  (global $output_len (mut i32) (i32.const 0))\n");
+
+    for (i, function) in module.functions.iter().enumerate() {
+        let name = FUNCTION_NAMES[START as usize + i];
+        out.push_str(&format!("  (func ${}", name));
+        if i == 0 {
+            out.push_str(" (export \"_start\")");
+        }
+        for param in &function.params {
+            out.push_str(&format!(" (param ${} i32)", param));
+        }
+        out.push('\n');
+        for local in &function.locals {
+            out.push_str(&format!("    (local ${} i32)\n", local));
+        }
+        let names: Vec<&str> = function
+            .params
+            .iter()
+            .chain(function.locals.iter())
+            .copied()
+            .collect();
+        let mut depth = 2;
+        for instr in &function.body {
+            if let Instr::End | Instr::Else = instr {
+                depth -= 1;
+            }
+            out.push_str(&"  ".repeat(depth));
+            out.push_str(&instr.wat(&names));
+            out.push('\n');
+            if let Instr::Block | Instr::Loop | Instr::If | Instr::Else = instr {
+                depth += 1;
+            }
+        }
+        out.push_str("  )\n");
+    }
+
+    if !module.data.is_empty() {
+        out.push_str(&format!(
+            "  (data (i32.const {}) \"{}\")\n",
+            module.data_start,
+            escape_wat(&module.data)
+        ));
+    }
+    out.push_str(")\n");
+    out
+}
+
+/// Returns the module in the WebAssembly binary format
+pub fn write_wasm(commands: &[Command], options: &WasmOptions) -> Vec<u8> {
+    const I32: u8 = 0x7F;
+    let module = build_module(commands, options);
+    let mut out = vec![0x00, b'a', b's', b'm', 0x01, 0x00, 0x00, 0x00];
+
+    let mut section = vec![];
+    write_u32(&mut section, TYPES.len() as u32);
+    for (params, results) in TYPES {
+        section.push(0x60);
+        write_u32(&mut section, params.len() as u32);
+        section.extend(params.iter().map(|_| I32));
+        write_u32(&mut section, results.len() as u32);
+        section.extend(results.iter().map(|_| I32));
+    }
+    write_section(&mut out, 1, &section);
+
+    let mut section = vec![];
+    write_u32(&mut section, 3);
+    for (name, type_index) in [
+        ("fd_write", TYPE_IO),
+        ("fd_read", TYPE_IO),
+        ("proc_exit", TYPE_I32),
+    ] {
+        write_name(&mut section, "wasi_snapshot_preview1");
+        write_name(&mut section, name);
+        section.push(0x00);
+        write_u32(&mut section, type_index);
+    }
+    write_section(&mut out, 2, &section);
+
+    let mut section = vec![];
+    write_u32(&mut section, module.functions.len() as u32);
+    for function in &module.functions {
+        write_u32(&mut section, function.type_index);
+    }
+    write_section(&mut out, 3, &section);
+
+    // One memory with a minimum size and no maximum
+    let mut section = vec![0x01, 0x00];
+    write_u32(&mut section, module.pages);
+    write_section(&mut out, 5, &section);
+
+    // The mutable output length, starting at 0
+    write_section(&mut out, 6, &[0x01, I32, 0x01, 0x41, 0x00, 0x0B]);
+
+    let mut section = vec![];
+    write_u32(&mut section, 2);
+    write_name(&mut section, "memory");
+    section.extend([0x02, 0x00]);
+    write_name(&mut section, "_start");
+    section.push(0x00);
+    write_u32(&mut section, START);
+    write_section(&mut out, 7, &section);
+
+    let mut section = vec![];
+    write_u32(&mut section, module.functions.len() as u32);
+    for function in &module.functions {
+        let mut code = vec![];
+        if function.locals.is_empty() {
+            write_u32(&mut code, 0);
+        } else {
+            write_u32(&mut code, 1);
+            write_u32(&mut code, function.locals.len() as u32);
+            code.push(I32);
+        }
+        for instr in &function.body {
+            instr.encode(&mut code);
+        }
+        Instr::End.encode(&mut code);
+        write_u32(&mut section, code.len() as u32);
+        section.extend(code);
+    }
+    write_section(&mut out, 10, &section);
+
+    if !module.data.is_empty() {
+        let mut section = vec![];
+        write_u32(&mut section, 1);
+        section.push(0x00);
+        Instr::I32Const(module.data_start as i32).encode(&mut section);
+        Instr::End.encode(&mut section);
+        write_u32(&mut section, module.data.len() as u32);
+        section.extend(&module.data);
+        write_section(&mut out, 11, &section);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::tests::{installed, run_with_input, LOWERINGS, TRAP};
+    use crate::compiler::TempDir;
+    use crate::interp::interp_io;
+    use crate::ir::parse_ir;
+
+    fn options(simd: bool) -> WasmOptions {
+        WasmOptions {
+            simd,
+            exit_code: ExitCode::Zero,
+        }
+    }
+
+    /// Whether the text format of `ir` has the instructions `instrs` in a row
+    fn has_instructions(ir: &str, instrs: &[&str]) -> bool {
+        let wat = write_wat(&parse_ir(ir).unwrap(), &options(false));
+        let lines: Vec<&str> = wat.lines().map(str::trim).collect();
+        lines.windows(instrs.len()).any(|window| window == instrs)
+    }
+
+    #[test]
+    fn infinite_loops_by_one_clear_the_cell() {
+        let ir = "inf_loop #1 1\ninf_loop #2 4\n";
+        assert!(!has_instructions(ir, &["i32.const 0", "i32.and"]));
+        assert!(has_instructions(ir, &["i32.const 3", "i32.and", "i32.eqz"]));
+        assert!(has_instructions(
+            ir,
+            &[
+                "i32.load8_u",
+                "if",
+                "local.get $p",
+                "i32.const 0",
+                "i32.store8",
+                "end",
+            ]
+        ));
+    }
+
+    #[test]
+    fn constant_output_is_written_at_once() {
+        let ir = "out_const 72\nout_const 105\nout_const 10\n";
+        assert!(has_instructions(ir, &["i32.const 3", "call $output_bytes"]));
+        let wat = write_wat(&parse_ir(ir).unwrap(), &options(false));
+        assert!(wat.contains(" \"Hi\\0a\")\n"));
+    }
+
+    #[test]
+    fn modules_validate() {
+        if !installed("node") {
+            return;
+        }
+        for simd in [false, true] {
+            for ir in [LOWERINGS, TRAP] {
+                let module = write_wasm(&parse_ir(ir).unwrap(), &options(simd));
+                let output = run_with_input(
+                    std::process::Command::new("node").args([
+                        "-e",
+                        "process.exit(WebAssembly.validate(require('fs').readFileSync(0)) ? 0 : 1)",
+                    ]),
+                    &module,
+                );
+                assert!(output.status.success(), "{} with SIMD {}", ir, simd);
+            }
+        }
+    }
+
+    #[test]
+    fn lowerings_run_under_wasmtime() {
+        if !installed("wasmtime") {
+            return;
+        }
+        let dir = TempDir::new().unwrap();
+        let path = dir.file("a.wasm");
+        let run = |ir: &str, simd: bool, input: &[u8]| {
+            std::fs::write(&path, write_wasm(&parse_ir(ir).unwrap(), &options(simd))).unwrap();
+            run_with_input(
+                std::process::Command::new("wasmtime").arg("run").arg(&path),
+                input,
+            )
+        };
+        let mut expected = vec![];
+        interp_io(
+            &mut parse_ir(LOWERINGS).unwrap(),
+            &mut &b"xyz"[..],
+            &mut expected,
+        );
+        for simd in [false, true] {
+            let output = run(LOWERINGS, simd, b"xyz");
+            assert!(output.status.success());
+            assert_eq!(output.stdout, expected, "SIMD {}", simd);
+
+            let output = run(TRAP, simd, b"");
+            assert_eq!(output.status.code(), Some(1));
+            assert_eq!(output.stdout, b"Hi");
+            assert_eq!(output.stderr, b"infinite loop detected at loop 3\n");
+        }
+    }
+}